@@ -0,0 +1,46 @@
+//! Compares loading a memtree with `bulk_insert` against inserting one entry at a time
+//!
+//! Run with `cargo bench --bench bulk_insert`.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use catalog::MemTree;
+
+const BUFFER_SIZE: usize = 16 * 1024 * 1024;
+
+fn main() {
+    for count in [1_000, 10_000] {
+        let mut buffer = vec![0u8; BUFFER_SIZE];
+        let insert = time(|| {
+            let mut tree = MemTree::new(&mut buffer[..]);
+            for i in 0..count {
+                tree.insert(i, i);
+            }
+            black_box(tree.get(&(count / 2)).copied());
+        });
+        let bulk_insert = time(|| {
+            let mut tree = MemTree::new(&mut buffer[..]);
+            tree.bulk_insert((0..count).map(|i| (i, i)));
+            black_box(tree.get(&(count / 2)).copied());
+        });
+        println!(
+            "{count:>7} entries: insert {:>10.2?}, bulk_insert {:>10.2?} ({:.1}x)",
+            insert,
+            bulk_insert,
+            insert.as_secs_f64() / bulk_insert.as_secs_f64()
+        );
+    }
+}
+
+/// The fastest of several runs, which is the least disturbed by anything else on the machine
+fn time(mut run: impl FnMut()) -> Duration {
+    (0..5)
+        .map(|_| {
+            let start = Instant::now();
+            run();
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
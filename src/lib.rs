@@ -1,16 +1,94 @@
+//! The in-memory [`BTree`] only needs `alloc`, so it still works with the default `std` feature
+//! turned off. [`MemTree`], the rest of the buffer-backed trees, and [`SyncBTree`] require `std`.
+//!
+//! The `mmap` feature adds `MemTree::create` and `MemTree::open`, which keep a tree in a
+//! memory-mapped file.
+//!
+//! The `rayon` feature adds `BTree::par_from_sorted`, which bulk-loads a tree across threads.
+//!
+//! The `validate-mutations` feature checks the in-memory tree after every insert and remove, for
+//! fuzzing; see [`BTree::debug_invariants_enabled`].
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "std")]
 mod memtree;
+#[cfg(feature = "std")]
+mod sync;
 mod tree;
 
-pub use memtree::BTree as MemTree;
-pub use tree::BTree;
+#[cfg(feature = "std")]
+pub use memtree::{
+    BTree as MemTree, Branch, BranchEntry, BufferAllocator, BytesMemTree, FreeList, Leaf,
+    LeafEntry, LoadError, MemTreeError, NodeId, NodeRef, OutOfSpace, ReadOnlyMemTree, TooSmall,
+    Transaction,
+};
+#[cfg(feature = "std")]
+pub use sync::SyncBTree;
+pub use tree::{
+    merge_join, BTree, Comparator, Entry, Fallible, MergeSide, MultiBTree, NaturalOrder,
+    OccupiedEntry, PartialOrder, SplitPolicy, TreeStats, TryComparator, Unordered, VacantEntry,
+};
+
+/// Builds a [`BTree`] out of `key => value` pairs, e.g. `btree! { 1 => "a", 2 => "b" }`
+#[macro_export]
+macro_rules! btree {
+    () => {
+        $crate::BTree::new()
+    };
+    ($($key:expr => $value:expr),+ $(,)?) => {{
+        let mut tree = $crate::BTree::new();
+        $(
+            tree.insert($key, $value);
+        )+
+        tree
+    }};
+}
+
+/// Only built without `std`, where it checks that the in-memory tree can be used from `no_std` code.
+/// Run `cargo build --no-default-features` to exercise it.
+#[cfg(not(feature = "std"))]
+#[allow(dead_code)]
+mod no_std_check {
+    use alloc::vec::Vec;
+
+    use crate::BTree;
+
+    fn use_tree() -> Vec<(i32, i32)> {
+        let mut tree = BTree::new();
+        for i in 0..100 {
+            tree.insert(i, i);
+        }
+        *tree.entry(0).or_insert(0) += 1;
+        tree.remove(&50);
+        tree.range(10..20).map(|(k, v)| (*k, *v)).collect()
+    }
+}
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod tests {
-    use std::{fs::File, io::Read};
+    use std::{
+        alloc::Layout,
+        cell::Cell,
+        fs::File,
+        hash::{Hash, Hasher},
+        io::Read,
+        ops::Bound,
+        rc::Rc,
+    };
 
     use memmap2::MmapMut;
 
-    use super::tree::BTree;
+    use super::memtree::{
+        BufferAllocator, BytesMemTree, FreeList, LoadError, MemTreeError, NodeId, NodeRef,
+    };
+    use super::sync::SyncBTree;
+    use super::tree::{
+        merge_join, BTree, Entry, Fallible, MergeSide, MultiBTree, PartialOrder, SplitPolicy,
+        TreeStats, Unordered,
+    };
 
     type IntTree = BTree<i32, i32>;
     type IntMemTree<'a> = super::memtree::BTree<'a, i32, i32>;
@@ -31,135 +109,3303 @@ mod tests {
     }
 
     #[test]
-    fn insert_mem_value() {
-        let mut buffer = vec![0u8; 1024];
-        let mut tree = IntMemTree::new(&mut buffer[..]);
+    fn len_empty_tree() {
+        let tree = IntTree::new();
+        assert_eq!(tree.len(), 0);
+        assert!(tree.is_empty());
+    }
 
-        tree.insert(1, 2);
-        assert_eq!(tree.get(&1), Some(&2));
+    #[test]
+    fn len_insert_overwrite() {
+        let mut tree = IntTree::new();
+        for i in 0..20 {
+            tree.insert(i, i);
+        }
+        assert_eq!(tree.len(), 20);
+        for i in 0..20 {
+            tree.insert(i, i * 2);
+        }
+        assert_eq!(tree.len(), 20);
+        assert!(!tree.is_empty());
     }
 
     #[test]
-    fn get_value() {
+    fn len_remove_missing_key() {
         let mut tree = IntTree::new();
-        tree.insert(1, 2);
-        let val = tree.get(&1);
-        assert_eq!(val, Some(&2));
+        for i in 0..10 {
+            tree.insert(i, i);
+        }
+        assert_eq!(tree.remove(&100), None);
+        assert_eq!(tree.len(), 10);
+        for i in 0..10 {
+            tree.remove(&i);
+        }
+        assert_eq!(tree.remove(&0), None);
+        assert_eq!(tree.len(), 0);
+        assert!(tree.is_empty());
     }
 
     #[test]
-    fn insert_many() {
+    fn clear() {
         let mut tree = IntTree::new();
-        for i in (0..32).rev() {
-            tree.insert(i, i.pow(2));
+        for i in 0..20 {
+            tree.insert(i, i);
         }
-        for i in (0..32i32).rev() {
-            assert_eq!(Some(&(i.pow(2))), tree.get(&i));
+        tree.clear();
+        assert!(tree.is_empty());
+        let children: Vec<_> = tree.iter().collect();
+        assert_eq!(&children[..], &[]);
+        for i in 0..20 {
+            assert_eq!(tree.get(&i), None);
         }
+        tree.insert(1, 2);
+        assert_eq!(tree.get(&1), Some(&2));
     }
 
     #[test]
-    fn insert_mem_many() {
-        let mut buffer = vec![0u8; 1024];
-        let mut tree = IntMemTree::new(&mut buffer[..]);
-        for i in (0..32).rev() {
-            tree.insert(i, i.pow(2));
+    fn into_iter() {
+        let mut tree = BTree::new();
+        for i in (0..50).rev() {
+            tree.insert(i, i.to_string());
         }
-        for i in (0..32i32).rev() {
-            assert_eq!(Some(&(i.pow(2))), tree.get(&i));
+        let borrowed: Vec<_> = tree.iter().map(|(k, v)| (*k, v.clone())).collect();
+        let owned: Vec<_> = tree.into_iter().collect();
+        assert_eq!(owned, borrowed);
+        assert_eq!(owned.len(), 50);
+        assert!(owned.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    }
+
+    #[test]
+    fn from_iter_last_duplicate_wins() {
+        let pairs = vec![(5, 0), (1, 1), (9, 2), (5, 3), (3, 4), (1, 5), (7, 6)];
+        let tree: IntTree = pairs.into_iter().collect();
+        let children: Vec<_> = tree.iter().map(|(k, v)| (*k, *v)).collect();
+        assert_eq!(&children[..], &[(1, 5), (3, 4), (5, 3), (7, 6), (9, 2)]);
+        assert_eq!(tree.len(), 5);
+    }
+
+    #[test]
+    fn extend() {
+        let mut tree: IntTree = (0..10).map(|i| (i, i)).collect();
+        tree.extend((5..15).map(|i| (i, -i)));
+        for i in 0..15 {
+            assert_eq!(tree.get(&i), Some(&if i < 5 { i } else { -i }));
         }
+        assert_eq!(tree.len(), 15);
     }
 
     #[test]
-    fn get_mut() {
-        let mut tree = IntTree::new();
-        for i in 0..10 {
-            tree.insert(i, i);
+    fn keys_and_values() {
+        let tree: IntTree = (0..40).rev().map(|i| (i, i * 3)).collect();
+        let keys: Vec<_> = tree.keys().copied().collect();
+        let values: Vec<_> = tree.values().copied().collect();
+        assert_eq!(keys, (0..40).collect::<Vec<_>>());
+        assert_eq!(keys.len(), values.len());
+        for (key, value) in keys.iter().zip(values.iter()) {
+            assert_eq!(key * 3, *value);
         }
-        for i in 0..10 {
-            let val = tree.get_mut(&i).unwrap();
-            if *val > 5 {
-                *val = 10;
+    }
+
+    #[test]
+    fn iter_mut() {
+        let mut tree: IntTree = (0..40).map(|i| (i, i)).collect();
+        for (key, value) in tree.iter_mut() {
+            assert_eq!(key, value);
+            *value *= 2;
+        }
+        for value in tree.values_mut() {
+            *value += 1;
+        }
+        for i in 0..40 {
+            assert_eq!(tree.get(&i), Some(&(i * 2 + 1)));
+        }
+    }
+
+    #[test]
+    fn iter_rev() {
+        let tree: IntTree = (0..50).map(|i| (i, i)).collect();
+        let keys: Vec<_> = tree.iter().rev().map(|(k, _)| *k).collect();
+        assert_eq!(keys, (0..50).rev().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn iter_interleaved_front_back() {
+        let tree: IntTree = (0..51).map(|i| (i, i)).collect();
+        let mut iter = tree.iter();
+        let mut front = Vec::new();
+        let mut back = Vec::new();
+        while let Some((k, _)) = iter.next() {
+            front.push(*k);
+            match iter.next_back() {
+                Some((k, _)) => back.push(*k),
+                None => break,
+            }
+        }
+        assert_eq!(iter.next(), None);
+        assert_eq!(iter.next_back(), None);
+        back.reverse();
+        front.extend(back);
+        assert_eq!(front, (0..51).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn range() {
+        let tree: IntTree = (0..100).map(|i| (i * 2, i)).collect();
+        let keys =
+            |iter: crate::tree::Range<'_, i32, i32>| iter.map(|(k, _)| *k).collect::<Vec<_>>();
+        let expected = |f: &dyn Fn(i32) -> bool| {
+            (0..100)
+                .map(|i| i * 2)
+                .filter(|k| f(*k))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            keys(tree.range(31..77)),
+            expected(&|k| (31..77).contains(&k))
+        );
+        assert_eq!(
+            keys(tree.range(30..76)),
+            expected(&|k| (30..76).contains(&k))
+        );
+        assert_eq!(
+            keys(tree.range(30..=76)),
+            expected(&|k| (30..=76).contains(&k))
+        );
+        assert_eq!(keys(tree.range(..51)), expected(&|k| k < 51));
+        assert_eq!(keys(tree.range(151..)), expected(&|k| k >= 151));
+        assert_eq!(keys(tree.range(..)), expected(&|_| true));
+        assert_eq!(keys(tree.range(-10..0)), Vec::<i32>::new());
+        assert_eq!(keys(tree.range(199..)), Vec::<i32>::new());
+        assert_eq!(keys(tree.range(200..)), Vec::<i32>::new());
+        for start in (-3..205).step_by(5) {
+            for end in (start..205).step_by(3) {
+                assert_eq!(
+                    keys(tree.range(start..end)),
+                    expected(&|k| (start..end).contains(&k))
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn range_rev() {
+        let tree: IntTree = (0..100).map(|i| (i * 2, i)).collect();
+        for start in (-3..205).step_by(5) {
+            for end in (start..205).step_by(3) {
+                let expected: Vec<_> = (0..100)
+                    .map(|i| i * 2)
+                    .filter(|k| (start..end).contains(k))
+                    .rev()
+                    .collect();
+                let rev: Vec<_> = tree.range(start..end).rev().map(|(k, _)| *k).collect();
+                assert_eq!(rev, expected);
+                let range_rev: Vec<_> = tree.range_rev(start..end).map(|(k, _)| *k).collect();
+                assert_eq!(range_rev, expected);
+            }
+        }
+        assert_eq!(tree.range_rev(30..=76).next(), Some((&76, &38)));
+        assert_eq!(
+            tree.range_rev((Bound::Excluded(30), Bound::Excluded(76)))
+                .next_back(),
+            Some((&32, &16))
+        );
+        assert_eq!(tree.range_rev(50..50).count(), 0);
+    }
+
+    #[test]
+    fn range_from_both_ends() {
+        let tree: IntTree = (0..500).map(|i| (i, i)).collect();
+        for (start, end) in [(0, 500), (13, 14), (13, 15), (17, 301), (100, 103)] {
+            let mut range = tree.range(start..end);
+            let (mut front, mut back) = (Vec::new(), Vec::new());
+            for step in 0.. {
+                let next = if step % 3 == 0 {
+                    range.next_back().map(|(k, _)| back.push(*k))
+                } else {
+                    range.next().map(|(k, _)| front.push(*k))
+                };
+                if next.is_none() {
+                    break;
+                }
+            }
+            assert_eq!(range.next(), None);
+            assert_eq!(range.next_back(), None);
+            back.reverse();
+            front.extend(back);
+            assert_eq!(front, (start..end).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn prefix() {
+        let tree: BTree<String, usize> = [
+            "group:1", "user", "user:1", "user:12", "user:2", "user;", "usera", "users:1",
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(i, key)| (String::from(key), i))
+        .collect();
+        let keys = |prefix: &str| -> Vec<&str> {
+            tree.prefix(&String::from(prefix))
+                .map(|(key, _)| key.as_str())
+                .collect()
+        };
+        assert_eq!(keys("user:"), ["user:1", "user:12", "user:2"]);
+        assert_eq!(keys("user:1"), ["user:1", "user:12"]);
+        assert_eq!(
+            keys("user"),
+            ["user", "user:1", "user:12", "user:2", "user;", "usera", "users:1"]
+        );
+        assert_eq!(keys("group:"), ["group:1"]);
+        assert_eq!(keys("admin:"), Vec::<&str>::new());
+        assert_eq!(keys("zzz"), Vec::<&str>::new());
+        assert_eq!(keys("").len(), tree.len());
+
+        // Prefixes ending in the highest byte have no key just past them to stop at
+        let bytes: BTree<Vec<u8>, ()> =
+            [vec![0xFE], vec![0xFF], vec![0xFF, 0x00], vec![0xFF, 0xFF]]
+                .into_iter()
+                .map(|key| (key, ()))
+                .collect();
+        assert_eq!(bytes.prefix(&vec![0xFF]).count(), 3);
+        assert_eq!(bytes.prefix(&vec![0xFF, 0xFF]).count(), 1);
+
+        // The end is found by descending to it, so it can be walked from the back, and an
+        // incremented byte carries past the 0xFF bytes after it
+        let many: BTree<Vec<u8>, usize> = (0..=255u8)
+            .flat_map(|a| [vec![1, a], vec![1, a, 0xFF], vec![2, a]])
+            .enumerate()
+            .map(|(i, key)| (key, i))
+            .collect();
+        let prefixed = |prefix: Vec<u8>| -> Vec<&Vec<u8>> {
+            let keys: Vec<_> = many.prefix(&prefix).map(|(key, _)| key).collect();
+            let mut reversed: Vec<_> = many.prefix(&prefix).rev().map(|(key, _)| key).collect();
+            reversed.reverse();
+            assert_eq!(keys, reversed);
+            assert!(keys.iter().all(|key| key.starts_with(&prefix)));
+            keys
+        };
+        assert_eq!(prefixed(vec![1]).len(), 512);
+        assert_eq!(prefixed(vec![1, 0xFF]).len(), 2);
+        assert_eq!(prefixed(vec![1, 0x10, 0xFF]).len(), 1);
+        assert_eq!(prefixed(vec![2]).len(), 256);
+        assert_eq!(prefixed(vec![3]).len(), 0);
+    }
+
+    #[test]
+    fn fallible_comparator() {
+        const SENTINEL: i32 = -1;
+        let mut tree = BTree::<_, _, 4, _>::with_try_comparator(|a: &i32, b: &i32| {
+            if *a == SENTINEL || *b == SENTINEL {
+                Err("the sentinel can't be compared")
             } else {
-                *val = 0;
+                Ok(a.cmp(b))
             }
+        });
+        for i in 0..100 {
+            assert_eq!(tree.try_insert(i, i), Ok(None));
         }
-        for i in 0..10 {
-            assert_eq!(*tree.get(&i).unwrap(), if i > 5 { 10 } else { 0 });
+        assert_eq!(tree.try_insert(5, 50), Ok(Some(5)));
+        assert_eq!(tree.try_get(&5), Ok(Some(&50)));
+        assert_eq!(tree.try_get(&100), Ok(None));
+
+        let before = tree.clone();
+        assert_eq!(
+            tree.try_insert(SENTINEL, 0),
+            Err("the sentinel can't be compared")
+        );
+        assert_eq!(
+            tree.try_get(&SENTINEL),
+            Err("the sentinel can't be compared")
+        );
+        assert_eq!(tree, before);
+        assert_eq!(tree.len(), 100);
+        tree.validate().unwrap();
+
+        let mut floats = BTree::<f64, i32, 4, Fallible<PartialOrder>>::default();
+        assert_eq!(floats.try_insert(1.5, 1), Ok(None));
+        assert_eq!(floats.try_insert(f64::NAN, 2), Err(Unordered));
+        assert_eq!(floats.try_get(&f64::NAN), Err(Unordered));
+        assert_eq!(floats.get(&1.5), Some(&1));
+        assert_eq!(floats.len(), 1);
+    }
+
+    // The checks `validate-mutations` turns on still panic on a failed comparison
+    #[cfg(not(feature = "validate-mutations"))]
+    #[test]
+    fn fallible_comparator_never_panics() {
+        // Inserting distinct keys never compares a key with itself, but checking that a separator
+        // matches the first key after it does
+        let mut tree = BTree::<_, _, 4, _>::with_try_comparator(|a: &i32, b: &i32| match a == b {
+            true => Err("equal keys"),
+            false => Ok(a.cmp(b)),
+        });
+        for i in 0..100 {
+            assert_eq!(tree.try_insert(i, i), Ok(None));
         }
+        assert!(tree.height() > 2);
+        assert_eq!(tree.try_insert(5, 50), Err("equal keys"));
+        assert!(tree.values().copied().eq(0..100));
     }
 
     #[test]
-    fn remove_value() {
+    fn insert_without_debug() {
+        #[derive(Clone, PartialEq)]
+        struct Opaque(i32);
+
+        let mut tree = BTree::new();
+        for i in 0..100 {
+            assert!(tree.insert(i, Opaque(i)).is_none());
+        }
+        assert!(tree.insert(5, Opaque(50)) == Some(Opaque(5)));
+        tree.entry(100).or_insert(Opaque(100));
+        tree.retain(|key, _| key % 2 == 0);
+        assert_eq!(tree.len(), 51);
+        assert!(tree.get(&4) == Some(&Opaque(4)));
+
+        let mut multi = MultiBTree::new();
+        multi.insert(1, Opaque(1));
+        assert_eq!(multi.len(), 1);
+    }
+
+    #[test]
+    fn write_and_read_ordered() {
+        let tree: BTree<String, u32> = (0..500).map(|i| (format!("key{i:04}"), i)).collect();
+        let mut bytes = Vec::new();
+        tree.write_ordered(
+            &mut bytes,
+            |key, out| out.extend_from_slice(key.as_bytes()),
+            |val, out| out.extend_from_slice(&val.to_le_bytes()),
+        )
+        .unwrap();
+
+        let decode_key = |bytes: &[u8]| {
+            String::from_utf8(bytes.to_vec())
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+        };
+        let decode_value = |bytes: &[u8]| Ok(u32::from_le_bytes(bytes.try_into().unwrap()));
+        let read =
+            BTree::<String, u32>::read_ordered(&mut &bytes[..], decode_key, decode_value).unwrap();
+        assert_eq!(read, tree);
+        read.validate().unwrap();
+
+        let err = BTree::<String, u32>::read_ordered(
+            &mut &bytes[..bytes.len() - 1],
+            decode_key,
+            decode_value,
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+
+        // Written in descending order, so the keys come back out of order
+        let mut reversed = BTree::<_, _, 4, _>::with_comparator(|a: &String, b: &String| b.cmp(a));
+        reversed.extend(tree.iter().map(|(key, val)| (key.clone(), *val)));
+        let mut bytes = Vec::new();
+        reversed
+            .write_ordered(
+                &mut bytes,
+                |key, out| out.extend_from_slice(key.as_bytes()),
+                |val, out| out.extend_from_slice(&val.to_le_bytes()),
+            )
+            .unwrap();
+        let err = BTree::<String, u32>::read_ordered(&mut &bytes[..], decode_key, decode_value)
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn remove_range() {
+        let mut tree: IntTree = (0..100).map(|i| (i, i)).collect();
+        assert_eq!(tree.remove_range(20..70), 50);
+        tree.validate().unwrap();
+        assert_eq!(tree.len(), 50);
+        let keys: Vec<_> = tree.keys().copied().collect();
+        assert_eq!(keys, (0..20).chain(70..100).collect::<Vec<_>>());
+        assert_eq!(tree.remove_range(20..70), 0);
+        assert_eq!(
+            tree.remove_range((Bound::Excluded(90), Bound::Unbounded)),
+            9
+        );
+        assert_eq!(tree.remove_range(..=5), 6);
+        tree.validate().unwrap();
+        let keys: Vec<_> = tree.keys().copied().collect();
+        assert_eq!(keys, (6..20).chain(70..91).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn range_empty() {
+        let tree: IntTree = (0..100).map(|i| (i, i)).collect();
+        assert_eq!(tree.range(50..50).count(), 0);
+        assert_eq!(
+            tree.range((Bound::Included(60), Bound::Included(40)))
+                .count(),
+            0
+        );
+        assert_eq!(
+            tree.range((Bound::Excluded(50), Bound::Excluded(51)))
+                .count(),
+            0
+        );
+        assert_eq!(
+            tree.range((Bound::Excluded(50), Bound::Excluded(50)))
+                .count(),
+            0
+        );
+        assert_eq!(IntTree::new().range(..).count(), 0);
+    }
+
+    #[test]
+    fn entry_or_insert() {
         let mut tree = IntTree::new();
-        tree.insert(1, 2);
-        let val = tree.remove(&1);
-        assert_eq!(val, Some(2));
-        let children: Vec<_> = tree.iter().collect();
-        assert_eq!(&children[..], &[]);
+        for i in 0..30 {
+            *tree.entry(i % 10).or_insert(0) += 1;
+        }
+        assert_eq!(tree.len(), 10);
+        for i in 0..10 {
+            assert_eq!(tree.get(&i), Some(&3));
+        }
     }
 
     #[test]
-    fn remove_many() {
+    fn entry_or_insert_with_is_lazy() {
+        let mut tree: IntTree = (0..20).map(|i| (i, i)).collect();
+        let mut calls = 0;
+        for i in 0..40 {
+            tree.entry(i).or_insert_with(|| {
+                calls += 1;
+                -i
+            });
+        }
+        assert_eq!(calls, 20);
+        assert_eq!(tree.get(&5), Some(&5));
+        assert_eq!(tree.get(&25), Some(&-25));
+    }
+
+    #[test]
+    fn entry_or_default() {
+        let mut tree: IntTree = (0..10).map(|i| (i, i + 1)).collect();
+        assert_eq!(*tree.entry(20).or_default(), 0);
+        assert_eq!(tree.get(&20), Some(&0));
+        assert_eq!(*tree.entry(5).or_default(), 6);
+        *tree.entry(5).or_default() += 10;
+        assert_eq!(tree.get(&5), Some(&16));
+        assert_eq!(tree.len(), 11);
+    }
+
+    #[test]
+    fn entry_and_modify() {
+        let mut tree: IntTree = (0..10).map(|i| (i, i)).collect();
+        tree.entry(3).and_modify(|v| *v = 100).or_insert(0);
+        tree.entry(30).and_modify(|v| *v = 100).or_insert(0);
+        assert_eq!(tree.get(&3), Some(&100));
+        assert_eq!(tree.get(&30), Some(&0));
+        assert_eq!(*tree.entry(7).key(), 7);
+        match tree.entry(4) {
+            Entry::Occupied(entry) => assert_eq!(entry.remove(), 4),
+            Entry::Vacant(_) => panic!("key 4 should be occupied"),
+        }
+        assert!(matches!(tree.entry(4), Entry::Vacant(_)));
+    }
+
+    #[test]
+    fn first_and_last_key_value() {
         let mut tree = IntTree::new();
-        for i in 0..25 {
-            tree.insert(i, i);
+        assert_eq!(tree.first_key_value(), None);
+        assert_eq!(tree.last_key_value(), None);
+
+        tree.insert(5, 50);
+        assert_eq!(tree.first_key_value(), Some((&5, &50)));
+        assert_eq!(tree.last_key_value(), Some((&5, &50)));
+
+        for i in 0..100 {
+            tree.insert(i * 3, i);
         }
-        for i in 0..25 {
-            if i < 15 {
-                assert_eq!(tree.remove(&i), Some(i));
-            }
+        assert_eq!(tree.first_key_value(), Some((&0, &0)));
+        assert_eq!(tree.last_key_value(), Some((&297, &99)));
+    }
+
+    #[test]
+    fn pop_first_drains_ascending() {
+        let mut tree: IntTree = (0..60).rev().map(|i| (i, i * 2)).collect();
+        for i in 0..60 {
+            assert_eq!(tree.pop_first(), Some((i, i * 2)));
+            assert_eq!(tree.len(), (59 - i) as usize);
         }
-        for i in 0..10 {
-            assert_eq!(tree.get(&i), if i < 15 { None } else { Some(&i) });
+        assert_eq!(tree.pop_first(), None);
+        assert!(tree.is_empty());
+        tree.insert(1, 1);
+        assert_eq!(tree.get(&1), Some(&1));
+    }
+
+    #[test]
+    fn pop_last_drains_descending() {
+        let mut tree: IntTree = (0..60).map(|i| (i, i * 2)).collect();
+        for i in (0..60).rev() {
+            assert_eq!(tree.pop_last(), Some((i, i * 2)));
         }
+        assert_eq!(tree.pop_last(), None);
+        assert!(tree.is_empty());
     }
 
     #[test]
-    fn remove_mem_many() {
-        let mut buffer = vec![0u8; 1024];
-        let mut tree = IntMemTree::new(&mut buffer[..]);
-        for i in 0..25 {
+    fn first_and_last_entry() {
+        let mut tree: IntTree = (0..60).map(|i| (i, i * 2)).collect();
+        *tree.first_entry().unwrap().get_mut() += 1;
+        assert_eq!(tree.first_key_value(), Some((&0, &1)));
+        assert_eq!(tree.first_entry().unwrap().remove(), 1);
+        assert_eq!(tree.first_key_value(), Some((&1, &2)));
+
+        let mut last = tree.last_entry().unwrap();
+        assert_eq!(*last.key(), 59);
+        assert_eq!(last.insert(0), 118);
+        assert_eq!(tree.last_key_value(), Some((&59, &0)));
+        assert_eq!(tree.last_entry().unwrap().remove(), 0);
+        assert_eq!(tree.last_key_value(), Some((&58, &116)));
+        assert_eq!(tree.len(), 58);
+
+        let mut empty = IntTree::new();
+        assert!(empty.first_entry().is_none());
+        assert!(empty.last_entry().is_none());
+    }
+
+    #[test]
+    fn end_entries_dont_compare_keys() {
+        let compares = Rc::new(Cell::new(0));
+        let counter = compares.clone();
+        let mut tree = BTree::<_, _, 4, _>::with_comparator(move |a: &i32, b: &i32| {
+            counter.set(counter.get() + 1);
+            a.cmp(b)
+        });
+        for i in 0..200 {
             tree.insert(i, i);
         }
-        for i in 0..25 {
-            if i < 15 {
-                assert_eq!(tree.remove(&i), Some(i));
-            }
+        compares.set(0);
+        for i in 0..100 {
+            let mut first = tree.first_entry().unwrap();
+            assert_eq!(*first.key(), i);
+            *first.get_mut() += 1;
+            assert_eq!(first.remove(), i + 1);
         }
-        for i in 0..10 {
-            assert_eq!(tree.get(&i), if i < 15 { None } else { Some(&i) });
+        *tree.last_entry().unwrap().into_mut() = -1;
+        // Checking the tree after each change compares keys of its own
+        if !IntTree::debug_invariants_enabled() {
+            assert_eq!(compares.get(), 0);
         }
+        assert_eq!(tree.len(), 100);
+        assert_eq!(tree.first_key_value(), Some((&100, &100)));
+        assert_eq!(tree.last_key_value(), Some((&199, &-1)));
     }
 
     #[test]
-    fn restore_from_buffer() {
-        let mut buffer = vec![0u8; 1024];
-        {
-            let mut tree = IntMemTree::new(&mut buffer[..]);
-            for i in 0..25 {
-                tree.insert(i, i);
-            }
-            for i in 0..25 {
-                if i < 15 {
-                    assert_eq!(tree.remove(&i), Some(i));
+    fn split_off() {
+        let mut left: IntTree = (0..100).map(|i| (i, i)).collect();
+        let mut right = left.split_off(&40);
+        assert_eq!(left.len(), 40);
+        assert_eq!(right.len(), 60);
+        assert_eq!(left.last_key_value(), Some((&39, &39)));
+        assert_eq!(right.first_key_value(), Some((&40, &40)));
+        assert!(left.keys().copied().eq(0..40));
+        assert!(right.keys().copied().eq(40..100));
+
+        left.insert(1000, 0);
+        right.insert(-1000, 0);
+        assert_eq!(left.remove(&0), Some(0));
+        assert_eq!(right.remove(&99), Some(99));
+        assert_eq!(left.get(&1000), Some(&0));
+        assert_eq!(right.get(&-1000), Some(&0));
+        assert_eq!(right.get(&0), None);
+    }
+
+    #[test]
+    fn append() {
+        let mut tree: IntTree = (0..60).map(|i| (i, 0)).collect();
+        let mut other: IntTree = (40..100).map(|i| (i, 1)).collect();
+        tree.append(&mut other);
+        assert_eq!(tree.len(), 100);
+        for i in 0..100 {
+            assert_eq!(tree.get(&i), Some(&if i < 40 { 0 } else { 1 }));
+        }
+        assert!(other.is_empty());
+        assert_eq!(other.iter().count(), 0);
+        other.insert(5, 5);
+        assert_eq!(other.get(&5), Some(&5));
+    }
+
+    #[test]
+    fn append_sorted() {
+        let mut tree: IntTree = (0..50).map(|i| (i, i)).collect();
+        tree.append_sorted((50..100).map(|i| (i, i)).collect());
+        tree.validate().unwrap();
+        assert_eq!(tree.len(), 100);
+        assert!(tree.iter().map(|(key, _)| *key).eq(0..100));
+
+        // Trees of every height on either side, including lone leaves too small to stand alone
+        fn check<const MAX: usize>() {
+            for split in [0, 1, 2, 3, 5, 8, 20, 64, 200, 700] {
+                for len in [0, 1, 2, 3, 5, 8, 20, 64, 200, 700] {
+                    let mut left: BTree<i32, i32, MAX> = (0..split).map(|i| (i, i)).collect();
+                    let right: BTree<i32, i32, MAX> =
+                        (split..split + len).map(|i| (i, i)).collect();
+                    let shared = right.snapshot();
+                    left.append_sorted(right);
+                    left.validate()
+                        .unwrap_or_else(|err| panic!("{split} + {len}: {err}"));
+                    assert_eq!(left.len(), (split + len) as usize);
+                    assert!(left.iter().map(|(key, _)| *key).eq(0..split + len));
+                    assert!(shared.iter().map(|(key, _)| *key).eq(split..split + len));
                 }
             }
         }
+        check::<4>();
+        check::<5>();
+        check::<16>();
+    }
 
-        {
-            let tree = IntMemTree::load(&mut buffer[..]);
-            for i in 0..10 {
-                assert_eq!(tree.get(&i), if i < 15 { None } else { Some(&i) });
+    #[test]
+    fn merge_join_sides() {
+        let a: IntTree = [(1, 10), (2, 20), (4, 40), (7, 70)].into_iter().collect();
+        let b: BTree<i32, &str> = [(2, "b"), (3, "c"), (7, "g"), (9, "i")]
+            .into_iter()
+            .collect();
+        let joined: Vec<_> = merge_join(&a, &b).collect();
+        assert_eq!(
+            joined,
+            vec![
+                (&1, MergeSide::Left(&10)),
+                (&2, MergeSide::Both(&20, &"b")),
+                (&3, MergeSide::Right(&"c")),
+                (&4, MergeSide::Left(&40)),
+                (&7, MergeSide::Both(&70, &"g")),
+                (&9, MergeSide::Right(&"i")),
+            ]
+        );
+
+        let keys = |keep: fn(&MergeSide<i32, &str>) -> bool| -> Vec<i32> {
+            merge_join(&a, &b)
+                .filter(|(_, side)| keep(side))
+                .map(|(key, _)| *key)
+                .collect()
+        };
+        assert_eq!(keys(|_| true), [1, 2, 3, 4, 7, 9]);
+        assert_eq!(keys(|side| matches!(side, MergeSide::Both(..))), [2, 7]);
+        assert_eq!(keys(|side| matches!(side, MergeSide::Left(_))), [1, 4]);
+        assert_eq!(keys(|side| matches!(side, MergeSide::Right(_))), [3, 9]);
+
+        // Either side empty, and trees with different fanouts
+        let empty: IntTree = BTree::new();
+        assert_eq!(merge_join(&empty, &empty).count(), 0);
+        assert!(merge_join(&a, &empty).all(|(_, side)| matches!(side, MergeSide::Left(_))));
+        let wide: BTree<i32, i32, 16> = (0..100).map(|i| (i * 3, i)).collect();
+        let narrow: IntTree = (0..100).map(|i| (i * 2, i)).collect();
+        let both: Vec<i32> = merge_join(&narrow, &wide)
+            .filter(|(_, side)| matches!(side, MergeSide::Both(..)))
+            .map(|(key, _)| *key)
+            .collect();
+        assert_eq!(both, (0..34).map(|i| i * 6).collect::<Vec<_>>());
+        assert_eq!(merge_join(&narrow, &wide).count(), 200 - 34);
+    }
+
+    #[test]
+    fn retain() {
+        let mut tree: IntTree = (0..100).map(|i| (i, i)).collect();
+        tree.retain(|key, val| {
+            *val *= 10;
+            key % 2 == 1
+        });
+        assert_eq!(tree.len(), 50);
+        assert!(tree.keys().copied().eq((0..100).filter(|i| i % 2 == 1)));
+        for i in 0..100 {
+            assert_eq!(tree.get(&i), (i % 2 == 1).then_some(&(i * 10)));
+        }
+        for i in 0..100 {
+            tree.insert(i, i);
+        }
+        assert!(tree.keys().copied().eq(0..100));
+
+        // A predicate that panics partway through doesn't cost the tree any entries
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            tree.retain(|key, val| {
+                assert!(*key < 60, "stop partway");
+                *val += 1;
+                key % 3 == 0
+            })
+        }));
+        assert!(result.is_err());
+        assert_eq!(tree.len(), 100);
+        for i in 0..100 {
+            assert_eq!(tree.get(&i), Some(&(i + i32::from(i < 60))));
+        }
+    }
+
+    #[test]
+    fn borrowed_lookup() {
+        let mut tree = BTree::new();
+        for i in 0..30 {
+            tree.insert(format!("key{i:02}"), i);
+        }
+        assert_eq!(tree.get("key07"), Some(&7));
+        assert_eq!(tree.get("key99"), None);
+        assert!(tree.contains_key("key29"));
+        *tree.get_mut("key10").unwrap() = 100;
+        assert_eq!(tree.get("key10"), Some(&100));
+        assert_eq!(tree.remove("key15"), Some(15));
+        assert!(!tree.contains_key("key15"));
+        assert_eq!(tree.len(), 29);
+    }
+
+    #[test]
+    fn remove_entry() {
+        let mut tree = BTree::new();
+        for i in 0..30 {
+            let mut key = String::with_capacity(64);
+            key.push_str(&format!("key{i:02}"));
+            tree.insert(key, i);
+        }
+        let (key, val) = tree.remove_entry("key12").unwrap();
+        assert_eq!((key.as_str(), val), ("key12", 12));
+        // The key handed back is the one that was stored, not a copy of the query
+        assert!(key.capacity() >= 64);
+        assert_eq!(tree.remove_entry("key12"), None);
+        assert_eq!(tree.len(), 29);
+        for i in (0..30).filter(|i| *i != 12) {
+            let (key, val) = tree.remove_entry(&format!("key{i:02}")).unwrap();
+            assert_eq!((key.capacity() >= 64, val), (true, i));
+        }
+        assert!(tree.is_empty());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let tree: BTree<i32, String> = (0..40).rev().map(|i| (i * 7, i.to_string())).collect();
+        let json = serde_json::to_string(&tree).unwrap();
+        let restored: BTree<i32, String> = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.len(), tree.len());
+        assert!(restored.iter().eq(tree.iter()));
+
+        let shuffled: BTree<i32, i32> =
+            serde_json::from_str(r#"{"3": 3, "1": 1, "2": 2}"#).unwrap();
+        assert!(shuffled.keys().copied().eq(1..=3));
+    }
+
+    #[test]
+    fn debug_structure() {
+        let tree: IntTree = (0..5).map(|i| (i, i * 10)).collect();
+        assert_eq!(
+            tree.debug_structure(),
+            "Branch [2]\n  Leaf [(0, 0), (1, 10)]\n  Leaf [(2, 20), (3, 30), (4, 40)]\n"
+        );
+        assert_eq!(IntTree::new().debug_structure(), "Branch []\n");
+    }
+
+    #[test]
+    fn height_and_stats() {
+        let mut tree = IntTree::new();
+        assert_eq!(tree.height(), 0);
+        assert_eq!(tree.node_count(), 1);
+
+        tree.insert(0, 0);
+        assert_eq!(tree.height(), 1);
+        assert_eq!(
+            tree.stats(),
+            TreeStats {
+                height: 1,
+                leaf_count: 1,
+                branch_count: 1,
+                entries: 1,
+                capacity: 1,
+            }
+        );
+
+        let mut previous_height = tree.height();
+        for i in 1..200 {
+            tree.insert(i, i);
+            assert!(tree.height() >= previous_height);
+            previous_height = tree.height();
+        }
+        assert!(tree.height() >= 2);
+        let stats = tree.stats();
+        assert_eq!(stats.entries, 200);
+        assert_eq!(stats.height, tree.height());
+        assert_eq!(tree.node_count(), stats.branch_count + stats.leaf_count);
+        assert!(stats.leaf_count > stats.branch_count);
+    }
+
+    #[test]
+    fn split_policy() {
+        fn fill_factor<const MAX: usize>(policy: SplitPolicy) -> f64 {
+            let mut tree = BTree::<i32, i32, MAX>::default();
+            tree.set_split_policy(policy);
+            for i in 0..1000 {
+                tree.insert(i, i);
             }
+            tree.validate().unwrap();
+            let stats = tree.stats();
+            stats.entries as f64 / (stats.leaf_count * MAX) as f64
+        }
+
+        assert!(fill_factor::<4>(SplitPolicy::AppendOptimized) > 0.7);
+        assert!(fill_factor::<4>(SplitPolicy::Balanced) < 0.55);
+        assert!(
+            fill_factor::<9>(SplitPolicy::AppendOptimized)
+                > fill_factor::<9>(SplitPolicy::Balanced)
+        );
+
+        // Any other order still keeps every node within bounds
+        let mut tree = IntTree::new();
+        tree.set_split_policy(SplitPolicy::AppendOptimized);
+        assert_eq!(tree.split_policy(), SplitPolicy::AppendOptimized);
+        for i in 0..500 {
+            tree.insert(i * 7919 % 500, i);
+            tree.get_or_insert_with(i * 31 % 700, || 0);
+        }
+        tree.validate().unwrap();
+        let right = tree.split_off(&250);
+        assert_eq!(right.split_policy(), SplitPolicy::AppendOptimized);
+        right.validate().unwrap();
+    }
+
+    #[test]
+    fn shrink_to_fit() {
+        let mut tree: IntTree = (0..1000).map(|i| (i, i)).collect();
+        for i in (0..1000).filter(|i| i % 10 != 0) {
+            tree.remove(&i);
         }
+        let before = tree.stats();
+        tree.shrink_to_fit();
+        let after = tree.stats();
+        assert_eq!(after.entries, 100);
+        assert_eq!(after.capacity, after.entries);
+        assert!(after.capacity < before.capacity);
+        assert_eq!(tree, (0..100).map(|i| (i * 10, i * 10)).collect());
+        tree.validate().unwrap();
+
+        // Nodes shared with a clone stay as they are
+        let mut tree: IntTree = (0..1000).map(|i| (i, i)).collect();
+        let snapshot = tree.clone();
+        tree.shrink_to_fit();
+        assert_eq!(tree.stats(), snapshot.stats());
+    }
+
+    #[test]
+    fn get_or_insert_with() {
+        let mut tree = IntTree::new();
+        let mut expected = std::collections::BTreeMap::new();
+        for i in 0..500 {
+            let key = i * 37 % 250;
+            let calls = Cell::new(0);
+            let val = tree.get_or_insert_with(key, || {
+                calls.set(calls.get() + 1);
+                key
+            });
+            *val += 1;
+            let was_present = expected.contains_key(&key);
+            *expected.entry(key).or_insert(key) += 1;
+            assert_eq!(calls.get(), if was_present { 0 } else { 1 });
+            tree.validate().unwrap();
+        }
+        assert_eq!(tree.len(), expected.len());
+        assert!(tree.iter().eq(expected.iter()));
+
+        // Snapshots keep the values they were taken with
+        let snapshot = tree.clone();
+        *tree.get_or_insert_with(0, || unreachable!()) = -1;
+        assert_eq!(snapshot.get(&0), Some(&2));
+        assert_eq!(tree.get(&0), Some(&-1));
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn par_from_sorted() {
+        for len in [0, 1, 4, 5, 17, 1000, 12_345] {
+            let entries: Vec<_> = (0..len).map(|i| (i, i * 2)).collect();
+            let serial = IntTree::from_sorted(entries.clone());
+            let parallel = IntTree::par_from_sorted(entries);
+            assert_eq!(parallel, serial);
+            assert_eq!(parallel.len(), serial.len());
+            assert_eq!(parallel.stats(), serial.stats());
+            assert_eq!(parallel.debug_structure(), serial.debug_structure());
+        }
+
+        let entries: Vec<_> = (0..5000).map(|i| (i, i)).collect();
+        let serial = BTree::<i32, i32, 7>::from_sorted(entries.clone());
+        let parallel = BTree::<i32, i32, 7>::par_from_sorted(entries);
+        assert_eq!(parallel.debug_structure(), serial.debug_structure());
+    }
+
+    #[test]
+    fn reserve() {
+        let mut tree: IntTree = (0..100).map(|i| (i * 10, i)).collect();
+        tree.shrink_to_fit();
+        let before = tree.stats().capacity;
+        tree.reserve(100);
+        assert!(tree.stats().capacity >= before + 100);
+        for i in 0..1000 {
+            tree.insert(i, i);
+        }
+        assert_eq!(tree, (0..1000).map(|i| (i, i)).collect());
+        tree.validate().unwrap();
+
+        let mut empty = IntTree::new();
+        empty.reserve(10);
+        empty.insert(1, 1);
+        assert_eq!(empty.len(), 1);
+
+        let mut buffer = vec![0u8; 16 * 1024];
+        let mut tree = IntMemTree::new(&mut buffer[..]);
+        assert!(tree.reserve(1_000_000).is_err());
+        tree.reserve(100).unwrap();
+        for i in 0..100 {
+            tree.insert(i, i);
+        }
+        assert!(tree.iter().map(|(key, _)| *key).eq(0..100));
+    }
+
+    #[test]
+    fn from_sorted() {
+        let empty = IntTree::from_sorted(Vec::new());
+        assert!(empty.is_empty());
+        assert_eq!(empty.height(), 0);
+
+        let tree = IntTree::from_sorted((0..1000).map(|i| (i, i * 2)));
+        let inserted: IntTree = (0..1000).map(|i| (i, i * 2)).collect();
+        assert_eq!(tree, inserted);
+        assert_eq!(tree.len(), 1000);
+
+        // Leaves are packed full, and all at the same depth
+        let stats = tree.stats();
+        assert_eq!(stats.leaf_count, 250);
+        assert!(tree.height() <= inserted.height());
+        let structure = tree.debug_structure();
+        let leaf_depths: Vec<_> = structure
+            .lines()
+            .filter(|line| line.trim_start().starts_with("Leaf"))
+            .map(|line| line.len() - line.trim_start().len())
+            .collect();
+        assert!(leaf_depths.iter().all(|depth| *depth == leaf_depths[0]));
+
+        // The tree keeps working as a normal tree afterwards
+        let mut tree = tree;
+        for i in (0..1000).step_by(2) {
+            assert_eq!(tree.remove(&i), Some(i * 2));
+        }
+        tree.insert(-1, -1);
+        for i in -1..1000 {
+            let expected = if i == -1 {
+                Some(-1)
+            } else {
+                (i % 2 == 1).then_some(i * 2)
+            };
+            assert_eq!(tree.get(&i).copied(), expected);
+        }
+    }
+
+    #[test]
+    fn sync_tree_across_threads() {
+        let tree = SyncBTree::new();
+        std::thread::scope(|scope| {
+            for writer in 0..4 {
+                let tree = &tree;
+                scope.spawn(move || {
+                    for i in 0..250 {
+                        let key = writer * 250 + i;
+                        assert_eq!(tree.insert(key, key * 2), None);
+                        assert_eq!(tree.get(&key), Some(key * 2));
+                    }
+                    // Each writer removes every other key it inserted
+                    for i in (0..250).step_by(2) {
+                        let key = writer * 250 + i;
+                        assert_eq!(tree.remove(&key), Some(key * 2));
+                    }
+                });
+            }
+            for _ in 0..4 {
+                let tree = &tree;
+                scope.spawn(move || {
+                    for key in 0..1000 {
+                        if let Some(val) = tree.get(&key) {
+                            assert_eq!(val, key * 2);
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(tree.len(), 500);
+        let tree = tree.into_inner();
+        let expected: Vec<_> = (0..1000)
+            .filter(|i| i % 2 == 1)
+            .map(|i| (i, i * 2))
+            .collect();
+        let actual: Vec<_> = tree.iter().map(|(k, v)| (*k, *v)).collect();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn iter_size_hint() {
+        let mut tree: IntTree = (0..100).map(|i| (i, i)).collect();
+
+        let mut iter = tree.iter();
+        for remaining in (0..=100).rev() {
+            assert_eq!(iter.size_hint(), (remaining, Some(remaining)));
+            assert_eq!(iter.len(), remaining);
+            // Take from both ends, which share the count
+            if remaining % 2 == 0 {
+                iter.next();
+            } else {
+                iter.next_back();
+            }
+        }
+        assert_eq!(iter.next(), None);
+        assert_eq!(iter.next(), None);
+
+        let mut iter = tree.iter_mut();
+        for remaining in (0..=100).rev() {
+            assert_eq!(iter.size_hint(), (remaining, Some(remaining)));
+            iter.next();
+        }
+        assert_eq!(iter.next(), None);
+
+        let mut iter = tree.clone().into_iter();
+        for remaining in (0..=100).rev() {
+            assert_eq!(iter.len(), remaining);
+            iter.next();
+        }
+        assert_eq!(iter.next(), None);
+
+        assert_eq!(IntTree::new().iter().size_hint(), (0, Some(0)));
+        tree.clear();
+        assert_eq!(tree.iter().len(), 0);
+    }
+
+    #[test]
+    fn into_keys_and_values() {
+        let tree: BTree<String, Vec<i32>> = (0..50)
+            .rev()
+            .map(|i| (format!("{i:02}"), vec![i]))
+            .collect();
+
+        let keys: Vec<String> = tree.clone().into_keys().collect();
+        let expected: Vec<String> = (0..50).map(|i| format!("{i:02}")).collect();
+        assert_eq!(keys, expected);
+
+        let values: Vec<Vec<i32>> = tree.into_values().collect();
+        let expected: Vec<Vec<i32>> = (0..50).map(|i| vec![i]).collect();
+        assert_eq!(values, expected);
+    }
+
+    /// The number of entries in each node below the root, and the depth of each leaf, read back out
+    /// of the debug structure
+    ///
+    /// The root is always a branch, so a tree small enough to fit in a leaf has a root with a
+    /// single leaf beneath it, which is as exempt from the minimum size as the root is.
+    fn node_shape(tree: &IntTree) -> (Vec<usize>, Vec<usize>) {
+        let mut lens = Vec::new();
+        let mut leaf_depths = Vec::new();
+        let mut root_depth = 0;
+        for line in tree.debug_structure().lines() {
+            let depth = (line.len() - line.trim_start().len()) / 2;
+            let line = line.trim_start();
+            if let Some(entries) = line.strip_prefix("Leaf ") {
+                leaf_depths.push(depth);
+                if depth > root_depth {
+                    lens.push(entries.matches('(').count());
+                }
+            } else if let Some(intervals) = line.strip_prefix("Branch ") {
+                if depth == root_depth && intervals == "[]" {
+                    root_depth += 1;
+                } else if depth > root_depth {
+                    lens.push(intervals.split(',').count() + 1);
+                }
+            }
+        }
+        (lens, leaf_depths)
+    }
+
+    #[test]
+    fn remove_keeps_nodes_in_bounds() {
+        let removal_orders: [Vec<i32>; 3] = [
+            (0..200).collect(),
+            (0..200).rev().collect(),
+            (0..200).step_by(2).chain((1..200).step_by(2)).collect(),
+        ];
+        for order in removal_orders {
+            let mut tree: IntTree = (0..200).map(|i| (i, i)).collect();
+            for (removed, key) in order.iter().enumerate() {
+                assert_eq!(tree.remove(key), Some(*key));
+                tree.validate().unwrap();
+                let (lens, leaf_depths) = node_shape(&tree);
+                assert!(lens.iter().all(|len| (2..=4).contains(len)), "{lens:?}");
+                assert!(leaf_depths.iter().all(|depth| *depth == leaf_depths[0]));
+                assert_eq!(tree.len(), 199 - removed);
+            }
+            assert!(tree.is_empty());
+        }
+    }
+
+    #[test]
+    fn interleaved_edits_keep_leaves_level() {
+        let mut state = 0x5eed_u32;
+        let mut next = move || {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            (state >> 16) as i32
+        };
+        let mut tree = IntTree::new();
+        let mut model = std::collections::BTreeMap::new();
+        for _ in 0..5000 {
+            let key = next() % 300;
+            if next() % 3 == 0 {
+                assert_eq!(tree.remove(&key), model.remove(&key));
+            } else {
+                assert_eq!(tree.insert(key, key), model.insert(key, key));
+            }
+            tree.validate().unwrap();
+        }
+        assert!(tree.iter().eq(model.iter()));
+    }
+
+    #[test]
+    fn validate_reports_broken_trees() {
+        let reversed = Rc::new(Cell::new(false));
+        let order = reversed.clone();
+        let mut tree =
+            BTree::<_, _, 4, _>::with_comparator(
+                move |a: &i32, b: &i32| {
+                    if order.get() {
+                        b.cmp(a)
+                    } else {
+                        a.cmp(b)
+                    }
+                },
+            );
+        assert_eq!(tree.validate(), Ok(()));
+        for i in 0..50 {
+            tree.insert(i, i);
+            tree.validate().unwrap();
+        }
+        // Flipping the order out from under the tree leaves every key in the wrong place
+        reversed.set(true);
+        let err = tree.validate().unwrap_err();
+        assert!(err.contains("outside of the intervals"), "{err}");
+    }
+
+    #[cfg(feature = "validate-mutations")]
+    #[test]
+    fn validate_every_mutation() {
+        assert!(IntTree::debug_invariants_enabled());
+        // Every change below is checked as it's made, so getting through them is the test
+        let mut tree = IntTree::new();
+        for i in 0..300 {
+            tree.insert(i * 37 % 300, i);
+        }
+        for i in (0..300).step_by(2) {
+            tree.remove(&i);
+        }
+        while tree.pop_last().is_some() {}
+        let mut fallible = BTree::<_, _, 4, _>::with_try_comparator(|a: &f64, b: &f64| {
+            a.partial_cmp(b).ok_or("NaN")
+        });
+        for i in 0..100 {
+            fallible.try_insert(f64::from(i), i).unwrap();
+        }
+        assert!(fallible.try_insert(f64::NAN, 0).is_err());
+    }
+
+    #[cfg(feature = "validate-mutations")]
+    #[test]
+    #[should_panic(expected = "insert broke the tree")]
+    fn validate_every_mutation_catches_breakage() {
+        let reversed = Rc::new(Cell::new(false));
+        let order = reversed.clone();
+        let mut tree =
+            BTree::<_, _, 4, _>::with_comparator(move |a: &i32, b: &i32| match order.get() {
+                true => b.cmp(a),
+                false => a.cmp(b),
+            });
+        for i in 0..50 {
+            tree.insert(i, i);
+        }
+        reversed.set(true);
+        tree.insert(50, 50);
+    }
+
+    #[test]
+    fn remove_shrinks_height() {
+        let mut tree: IntTree = (0..1000).map(|i| (i, i)).collect();
+        let full_height = tree.height();
+        assert!(full_height >= 3);
+
+        let mut previous_height = full_height;
+        for i in 0..997 {
+            tree.remove(&i);
+            assert!(tree.height() <= previous_height);
+            previous_height = tree.height();
+        }
+        assert_eq!(tree.height(), 1);
+        let remaining: Vec<_> = tree.keys().copied().collect();
+        assert_eq!(remaining, [997, 998, 999]);
+
+        // The shortened tree can still grow back
+        tree.extend((0..997).map(|i| (i, i)));
+        assert_eq!(tree.height(), full_height);
+        assert_eq!(tree.len(), 1000);
+    }
+
+    #[test]
+    fn drain() {
+        let mut tree: BTree<i32, String> = (0..100).map(|i| (i, i.to_string())).collect();
+        let drained: Vec<_> = tree.drain().collect();
+        let expected: Vec<_> = (0..100).map(|i| (i, i.to_string())).collect();
+        assert_eq!(drained, expected);
+        assert!(tree.is_empty());
+
+        tree.extend((0..100).map(|i| (i, i.to_string())));
+        let mut drain = tree.drain();
+        assert_eq!(drain.len(), 100);
+        let first: Vec<_> = drain.by_ref().take(10).map(|(key, _)| key).collect();
+        assert_eq!(first, (0..10).collect::<Vec<_>>());
+        drop(drain);
+        assert!(tree.is_empty());
+        assert_eq!(tree.iter().next(), None);
+
+        tree.insert(5, "five".to_string());
+        assert_eq!(tree.get(&5).map(String::as_str), Some("five"));
+    }
+
+    #[test]
+    fn extract_if() {
+        let mut tree: IntTree = (0..90).map(|i| (i, i)).collect();
+        let extracted: Vec<_> = tree.extract_if(|key, _| key % 3 == 0).collect();
+        let expected: Vec<_> = (0..90).filter(|i| i % 3 == 0).map(|i| (i, i)).collect();
+        assert_eq!(extracted, expected);
+        let remaining: Vec<_> = tree.iter().map(|(k, v)| (*k, *v)).collect();
+        let expected: Vec<_> = (0..90).filter(|i| i % 3 != 0).map(|i| (i, i)).collect();
+        assert_eq!(remaining, expected);
+        assert_eq!(tree.len(), 60);
+
+        // Stopping early keeps everything not yet reached
+        {
+            let mut extract = tree.extract_if(|_, val| val % 2 == 0);
+            assert_eq!(extract.next(), Some((2, 2)));
+            assert_eq!(extract.next(), Some((4, 4)));
+        }
+        assert_eq!(tree.len(), 58);
+        assert_eq!(tree.get(&1), Some(&1));
+        assert_eq!(tree.get(&8), Some(&8));
+        assert_eq!(tree.get(&4), None);
+    }
+
+    #[test]
+    fn extract_if_only_copies_removed_paths() {
+        let clones = Rc::new(Cell::new(0));
+        let mut tree = BTree::new();
+        for i in 0..200 {
+            tree.insert(CloneCounted(i, clones.clone()), i);
+        }
+        let snapshot = tree.snapshot();
+        clones.set(0);
+        let extracted: Vec<_> = tree.extract_if(|_, val| *val == 150).collect();
+        assert_eq!(extracted.len(), 1);
+        // Only the nodes on the way to the removed entry were copied, not every node looked at
+        assert!(clones.get() < 50, "{}", clones.get());
+        assert_eq!(tree.len(), 199);
+        assert_eq!(snapshot.len(), 200);
+        assert!(snapshot.values().copied().eq(0..200));
+    }
+
+    #[test]
+    fn get_key_value() {
+        /// Ordered by `id` alone, so a lookup key can differ from the stored one
+        #[derive(Clone, Debug)]
+        struct Tagged {
+            id: i32,
+            tag: &'static str,
+        }
+
+        impl PartialEq for Tagged {
+            fn eq(&self, other: &Self) -> bool {
+                self.id == other.id
+            }
+        }
+
+        impl Eq for Tagged {}
+
+        impl PartialOrd for Tagged {
+            fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+                Some(self.cmp(other))
+            }
+        }
+
+        impl Ord for Tagged {
+            fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+                self.id.cmp(&other.id)
+            }
+        }
+
+        let mut tree = BTree::new();
+        for id in 0..20 {
+            tree.insert(Tagged { id, tag: "stored" }, id);
+        }
+        let query = Tagged {
+            id: 7,
+            tag: "query",
+        };
+        let (key, val) = tree.get_key_value(&query).unwrap();
+        assert_eq!(key.tag, "stored");
+        assert_eq!(key.id, 7);
+        assert_eq!(*val, 7);
+        assert_eq!(
+            tree.get_key_value(&Tagged {
+                id: 20,
+                tag: "query"
+            }),
+            None
+        );
+
+        let strings: BTree<String, i32> = [("a".to_string(), 1)].into_iter().collect();
+        assert_eq!(strings.get_key_value("a"), Some((&"a".to_string(), &1)));
+    }
+
+    #[test]
+    fn floor_and_ceiling() {
+        let tree: IntTree = (0..100).map(|i| (i * 10, i)).collect();
+
+        assert_eq!(tree.floor(&500), Some((&500, &50)));
+        assert_eq!(tree.ceiling(&500), Some((&500, &50)));
+        assert_eq!(tree.floor(&505), Some((&500, &50)));
+        assert_eq!(tree.ceiling(&505), Some((&510, &51)));
+        for target in -5..1000i32 {
+            let floor = (target >= 0).then(|| target / 10 * 10);
+            let ceiling = (target <= 990).then(|| (target + 9).div_euclid(10) * 10);
+            assert_eq!(tree.floor(&target).map(|(k, _)| *k), floor, "{target}");
+            assert_eq!(tree.ceiling(&target).map(|(k, _)| *k), ceiling, "{target}");
+        }
+
+        assert_eq!(tree.floor(&-1), None);
+        assert_eq!(tree.ceiling(&-1), Some((&0, &0)));
+        assert_eq!(tree.floor(&1000), Some((&990, &99)));
+        assert_eq!(tree.ceiling(&991), None);
+        assert_eq!(IntTree::new().floor(&0), None);
+        assert_eq!(IntTree::new().ceiling(&0), None);
+    }
+
+    #[test]
+    fn predecessor_and_successor() {
+        let tree: IntTree = (0..100).map(|i| (i * 10, i)).collect();
+
+        assert_eq!(tree.predecessor(&500), Some((&490, &49)));
+        assert_eq!(tree.successor(&500), Some((&510, &51)));
+        assert_eq!(tree.predecessor(&505), Some((&500, &50)));
+        assert_eq!(tree.successor(&505), Some((&510, &51)));
+        for target in -5..1000i32 {
+            let predecessor = (target > 0).then(|| (target - 1).div_euclid(10) * 10);
+            let successor = (target < 990).then(|| (target + 10).div_euclid(10) * 10);
+            let found = tree.predecessor(&target).map(|(k, _)| *k);
+            assert_eq!(found, predecessor, "{target}");
+            let found = tree.successor(&target).map(|(k, _)| *k);
+            assert_eq!(found, successor, "{target}");
+        }
+
+        assert_eq!(tree.predecessor(&0), None);
+        assert_eq!(tree.successor(&990), None);
+        assert_eq!(tree.successor(&-1), Some((&0, &0)));
+        assert_eq!(tree.predecessor(&1000), Some((&990, &99)));
+        assert_eq!(IntTree::new().predecessor(&0), None);
+        assert_eq!(IntTree::new().successor(&0), None);
+    }
+
+    #[test]
+    fn cursor() {
+        let tree: IntTree = (0..100).map(|i| (i * 2, i)).collect();
+
+        let mut cursor = tree.cursor();
+        assert_eq!(cursor.key(), Some(&0));
+        cursor.seek(&50);
+        assert_eq!((cursor.key(), cursor.value()), (Some(&50), Some(&25)));
+        for i in 26..36 {
+            cursor.move_next();
+            assert_eq!(cursor.value(), Some(&i));
+        }
+        for i in (10..35).rev() {
+            cursor.move_prev();
+            assert_eq!(cursor.value(), Some(&i));
+        }
+
+        // Seeking a missing key lands on the next one up
+        cursor.seek(&51);
+        assert_eq!(cursor.key(), Some(&52));
+        cursor.seek(&-10);
+        assert_eq!(cursor.key(), Some(&0));
+
+        // Either end steps onto the ghost position, and then around to the other end
+        cursor.move_prev();
+        assert_eq!(cursor.key(), None);
+        cursor.move_prev();
+        assert_eq!(cursor.key(), Some(&198));
+        cursor.move_next();
+        assert_eq!(cursor.key(), None);
+        cursor.move_next();
+        assert_eq!(cursor.key(), Some(&0));
+        cursor.seek(&199);
+        assert_eq!(cursor.key(), None);
+
+        let walked: Vec<_> = std::iter::from_fn(|| {
+            cursor.move_next();
+            cursor.key().copied()
+        })
+        .collect();
+        assert_eq!(walked, tree.keys().copied().collect::<Vec<_>>());
+
+        let empty = IntTree::new();
+        let mut cursor = empty.cursor();
+        assert_eq!(cursor.key(), None);
+        cursor.move_next();
+        cursor.move_prev();
+        cursor.seek(&0);
+        assert_eq!(cursor.key(), None);
+    }
+
+    #[test]
+    fn rank_and_select() {
+        let mut state = 0x1234567u32;
+        let mut next = move || {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            (state >> 16) as i32 % 1000
+        };
+        let keys: Vec<i32> = (0..300).map(|_| next()).collect();
+        let tree: IntTree = keys.iter().map(|key| (*key, -key)).collect();
+        let mut sorted = keys.clone();
+        sorted.sort();
+        sorted.dedup();
+
+        for target in -1..=1000 {
+            let expected = keys
+                .iter()
+                .filter(|key| **key < target)
+                .collect::<std::collections::BTreeSet<_>>()
+                .len();
+            assert_eq!(tree.rank(&target), expected, "{target}");
+        }
+        for (n, key) in sorted.iter().enumerate() {
+            assert_eq!(tree.select(n), Some((key, &-key)));
+            assert_eq!(tree.rank(key), n);
+        }
+        assert_eq!(tree.select(sorted.len()), None);
+        assert_eq!(IntTree::new().rank(&0), 0);
+    }
+
+    #[test]
+    fn count_range() {
+        use std::ops::RangeBounds;
+
+        let mut state = 0x2545f491u32;
+        let mut next = move || {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            (state >> 16) as i32 % 1000
+        };
+        let keys: Vec<i32> = (0..400).map(|_| next()).collect();
+        let tree: IntTree = keys.iter().map(|key| (*key, 0)).collect();
+        let keys: std::collections::BTreeSet<i32> = keys.into_iter().collect();
+
+        for _ in 0..200 {
+            let (a, b) = (next(), next());
+            let (start, end) = (a.min(b), a.max(b));
+            let brute = |range: (Bound<i32>, Bound<i32>)| {
+                keys.iter().filter(|key| range.contains(key)).count()
+            };
+            for range in [
+                (Bound::Included(start), Bound::Excluded(end)),
+                (Bound::Excluded(start), Bound::Included(end)),
+                (Bound::Unbounded, Bound::Included(end)),
+                (Bound::Excluded(start), Bound::Unbounded),
+            ] {
+                assert_eq!(tree.count_range(range), brute(range), "{range:?}");
+            }
+        }
+        assert_eq!(tree.count_range(..), keys.len());
+        assert_eq!(tree.count_range(500..500), 0);
+        assert_eq!(IntTree::new().count_range(..), 0);
+    }
+
+    #[test]
+    fn nth() {
+        let mut tree: IntTree = (0..100).map(|i| (i * 3, i)).collect();
+        assert_eq!(tree.nth(0), tree.first_key_value());
+        assert_eq!(tree.nth(tree.len() - 1), tree.last_key_value());
+        assert_eq!(tree.nth(10), Some((&30, &10)));
+        assert_eq!(tree.nth(100), None);
+
+        *tree.nth_mut(10).unwrap().1 = -1;
+        assert_eq!(tree.get(&30), Some(&-1));
+        assert_eq!(tree.nth_mut(100), None);
+        assert_eq!(IntTree::new().nth(0), None);
+    }
+
+    #[test]
+    fn multi_tree() {
+        let mut tree = MultiBTree::new();
+        tree.insert("b", 1);
+        tree.insert("a", 2);
+        tree.insert("b", 3);
+        tree.insert("b", 4);
+        assert_eq!(tree.get_all("b"), &[1, 3, 4]);
+        assert_eq!(tree.get_all("a"), &[2]);
+        assert_eq!(tree.get_all("c"), &[] as &[i32]);
+        assert_eq!((tree.len(), tree.key_count()), (4, 2));
+
+        let all: Vec<_> = tree.iter().map(|(k, v)| (*k, *v)).collect();
+        assert_eq!(all, [("a", 2), ("b", 1), ("b", 3), ("b", 4)]);
+
+        assert_eq!(tree.remove_all("b"), [1, 3, 4]);
+        assert_eq!(tree.remove_all("b"), Vec::<i32>::new());
+        assert!(!tree.contains_key("b"));
+        assert_eq!(tree.len(), 1);
+    }
+
+    #[test]
+    fn get_many() {
+        let tree: IntTree = (0..200).map(|i| (i * 2, i)).collect();
+        let mut state = 0x9e3779b9u32;
+        let keys: Vec<i32> = (0..300)
+            .map(|_| {
+                state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+                (state >> 16) as i32 % 450 - 25
+            })
+            .collect();
+        let expected: Vec<_> = keys.iter().map(|key| tree.get(key)).collect();
+        assert_eq!(tree.get_many(&keys), expected);
+        assert!(expected.iter().any(Option::is_some) && expected.iter().any(Option::is_none));
+
+        assert_eq!(tree.get_many(&[]), Vec::<Option<&i32>>::new());
+        assert_eq!(IntTree::new().get_many(&[1, 2]), [None, None]);
+    }
+
+    #[test]
+    fn index() {
+        let tree: BTree<String, i32> = (0..20).map(|i| (i.to_string(), i)).collect();
+        assert_eq!(tree["7"], 7);
+        assert_eq!(tree[&"19".to_string()], 19);
+    }
+
+    #[test]
+    #[should_panic(expected = "no entry found for key")]
+    fn index_missing_key() {
+        let tree: IntTree = (0..20).map(|i| (i, i)).collect();
+        let _ = tree[&20];
+    }
+
+    #[test]
+    fn btree_macro() {
+        let mut expected = BTree::new();
+        expected.insert(1, "a");
+        expected.insert(2, "b");
+        assert_eq!(crate::btree! { 1 => "a", 2 => "b" }, expected);
+        assert_eq!(crate::btree! { 2 => "b", 1 => "a", }, expected);
+
+        let empty: BTree<i32, &str> = crate::btree! {};
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn std_map_conversions() {
+        let map: std::collections::BTreeMap<i32, String> = (0..300)
+            .rev()
+            .map(|i| (i * 7 % 1000, i.to_string()))
+            .collect();
+        let tree = BTree::from(map.clone());
+        assert!(tree.iter().eq(map.iter()));
+        assert_eq!(tree.len(), map.len());
+
+        let round_trip: std::collections::BTreeMap<_, _> = tree.into();
+        assert_eq!(round_trip, map);
+
+        let empty = IntTree::from(std::collections::BTreeMap::new());
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn find_idx_from_interval() {
+        use super::tree::{find_idx_from_interval as find_idx, NaturalOrder};
+
+        let cmp = NaturalOrder;
+        assert_eq!(find_idx::<i32, i32, _>(&[], &5, &cmp), 0);
+        assert_eq!(find_idx(&[10], &5, &cmp), 0);
+        assert_eq!(find_idx(&[10], &10, &cmp), 1);
+        assert_eq!(find_idx(&[10], &15, &cmp), 1);
+
+        let intervals = [10, 20, 30, 40, 50];
+        let expected = [
+            (i32::MIN, 0),
+            (9, 0),
+            (10, 1),
+            (19, 1),
+            (20, 2),
+            (35, 3),
+            (40, 4),
+            (49, 4),
+            (50, 5),
+            (i32::MAX, 5),
+        ];
+        for (key, idx) in expected {
+            assert_eq!(find_idx(&intervals, &key, &cmp), idx, "{key}");
+        }
+        // Every key equal to an interval is routed to the child after that interval
+        for (i, interval) in intervals.iter().enumerate() {
+            assert_eq!(find_idx(&intervals, interval, &cmp), i + 1);
+        }
+    }
+
+    /// Counts how many times it's cloned, to catch clones of keys that could have been moves
+    #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+    struct CloneCounted(i32, Rc<Cell<usize>>);
+
+    impl Clone for CloneCounted {
+        fn clone(&self) -> Self {
+            self.1.set(self.1.get() + 1);
+            CloneCounted(self.0, self.1.clone())
+        }
+    }
+
+    #[test]
+    fn split_clones_only_leaf_separators() {
+        let clones = Rc::new(Cell::new(0));
+        let mut tree = BTree::new();
+        for i in 0..1000 {
+            tree.insert(CloneCounted(i, clones.clone()), i);
+        }
+        let stats = tree.stats();
+        assert!(stats.branch_count > 1);
+        // Each leaf split clones one key into its parent, and branch splits move theirs
+        assert_eq!(clones.get(), stats.leaf_count - 1);
+    }
+
+    #[test]
+    fn snapshot_is_unaffected_by_changes() {
+        let mut tree: IntTree = (0..500).map(|i| (i, i)).collect();
+        let snapshot = tree.snapshot();
+        for i in 0..100 {
+            tree.remove(&i);
+            tree.insert(i + 1000, i);
+        }
+        *tree.get_mut(&200).unwrap() = -1;
+        for (_, value) in tree.iter_mut() {
+            *value += 1;
+        }
+        tree.validate().unwrap();
+        snapshot.validate().unwrap();
+        assert_eq!(snapshot.len(), 500);
+        assert!(snapshot
+            .iter()
+            .map(|(key, value)| (*key, *value))
+            .eq((0..500).map(|i| (i, i))));
+        assert_eq!(tree.get(&200), Some(&0));
+
+        // Changing the snapshot doesn't reach back into the tree it was taken from either
+        let mut snapshot = snapshot;
+        snapshot.clear();
+        assert_eq!(tree.len(), 500);
+        assert_eq!(tree.get(&1099), Some(&100));
+    }
+
+    #[test]
+    fn snapshot_copies_only_the_changed_path() {
+        let clones = Rc::new(Cell::new(0));
+        let mut tree = BTree::new();
+        for i in 0..1000 {
+            tree.insert(CloneCounted(i, clones.clone()), i);
+        }
+        let snapshot = tree.snapshot();
+        clones.set(0);
+        tree.insert(CloneCounted(1000, clones.clone()), 1000);
+        // Each node on the way down holds at most 4 keys, and the new leaf entry needs a
+        // separator if the leaf splits
+        let height = tree.height();
+        assert!(clones.get() <= (height + 1) * 4 + 1, "{}", clones.get());
+        assert_eq!(snapshot.len(), 1000);
+        assert_eq!(tree.len(), 1001);
+    }
+
+    #[test]
+    fn only_shared_nodes_are_cloned() {
+        let clones = Rc::new(Cell::new(0));
+        let mut tree = BTree::new();
+        for i in 0..200 {
+            tree.insert(CloneCounted(i, clones.clone()), i);
+        }
+        clones.set(0);
+        for (_, value) in tree.iter_mut() {
+            *value += 1;
+        }
+        let snapshot = tree.snapshot();
+        assert_eq!(clones.get(), 0);
+
+        // Every node is shared with the snapshot now, so all of them are cloned on the way out
+        assert!(tree.into_iter().map(|(_, value)| value).eq(1..201));
+        assert!(clones.get() >= 200);
+        clones.set(0);
+        assert!(snapshot.into_values().eq(1..201));
+        assert_eq!(clones.get(), 0);
+    }
+
+    #[test]
+    fn merge_moves_nodes_instead_of_cloning() {
+        let clones = Rc::new(Cell::new(0));
+        let mut tree = BTree::new();
+        for i in 0..1000 {
+            tree.insert(CloneCounted(i, clones.clone()), i);
+        }
+        clones.set(0);
+        for i in (0..1000).rev() {
+            assert_eq!(tree.remove(&CloneCounted(i, clones.clone())), Some(i));
+        }
+        assert!(tree.is_empty());
+        // Ascending inserts leave every node as small as it can be, so removing from the back only
+        // ever merges, and merging moves both nodes and the separator between them
+        assert_eq!(clones.get(), 0);
+    }
+
+    #[test]
+    fn custom_comparator() {
+        let mut tree = BTree::<_, _, 4, _>::with_comparator(|a: &i32, b: &i32| b.cmp(a));
+        for i in 0..100 {
+            tree.insert(i, i * 2);
+        }
+        let keys: Vec<_> = tree.keys().copied().collect();
+        let expected: Vec<_> = (0..100).rev().collect();
+        assert_eq!(keys, expected);
+        assert_eq!(tree.get(&10), Some(&20));
+        assert_eq!(tree.first_key_value(), Some((&99, &198)));
+
+        // Ranges are in comparator order too, so they run from high to low
+        let range: Vec<_> = tree
+            .range((Bound::Included(20), Bound::Included(10)))
+            .map(|(k, _)| *k)
+            .collect();
+        assert_eq!(range, (10..=20).rev().collect::<Vec<_>>());
+
+        for i in (0..100).step_by(2) {
+            assert_eq!(tree.remove(&i), Some(i * 2));
+        }
+        assert_eq!(tree.len(), 50);
+        assert!(tree
+            .keys()
+            .copied()
+            .eq((0..100).rev().filter(|i| i % 2 == 1)));
+    }
+
+    #[test]
+    fn configurable_fanout() {
+        let mut small = IntTree::new();
+        let mut large = BTree::<i32, i32, 64>::default();
+        for i in 0..60 {
+            small.insert(i, i);
+            large.insert(i, i);
+        }
+        // 60 entries overflow a leaf of 4 many times over, but fit in a single leaf of 64
+        assert!(small.stats().leaf_count > 1);
+        assert_eq!(large.stats().leaf_count, 1);
+        assert_eq!(large.height(), 1);
+        assert!(small.iter().eq(large.iter()));
+
+        for i in 60..1000 {
+            large.insert(i, i);
+        }
+        for i in (0..1000).step_by(3) {
+            assert_eq!(large.remove(&i), Some(i));
+        }
+        for i in 0..1000 {
+            assert_eq!(large.get(&i), (i % 3 != 0).then_some(&i));
+        }
+
+        // A comparator that isn't `Default` can go with any fanout too
+        let mut descending = BTree::<_, _, 16, _>::with_comparator(|a: &i32, b: &i32| b.cmp(a));
+        let mut fallible = BTree::<_, _, 16, _>::with_try_comparator(|a: &f64, b: &f64| {
+            a.partial_cmp(b).ok_or("NaN")
+        });
+        for i in 0..100 {
+            descending.insert(i, i);
+            fallible.try_insert(f64::from(i), i).unwrap();
+        }
+        // 100 entries take at most 13 leaves of 16, which all fit under the root
+        assert_eq!(descending.height(), 1);
+        assert!(descending.keys().copied().eq((0..100).rev()));
+        assert!(fallible.values().copied().eq(0..100));
+        assert!(fallible.try_insert(f64::NAN, 0).is_err());
+    }
+
+    #[test]
+    fn clone_and_eq() {
+        let tree: IntTree = (0..30).map(|i| (i, i)).collect();
+        let mut cloned = tree.clone();
+        assert_eq!(tree, cloned);
+        cloned.insert(5, 50);
+        assert_ne!(tree, cloned);
+        assert_eq!(tree.get(&5), Some(&5));
+
+        let mut reshaped: IntTree = (0..60).rev().map(|i| (i, i)).collect();
+        for i in 30..60 {
+            reshaped.remove(&i);
+        }
+        assert_ne!(tree.debug_structure(), reshaped.debug_structure());
+        assert_eq!(tree, reshaped);
+    }
+
+    #[test]
+    fn hash_follows_contents() {
+        fn hash_of(tree: &IntTree) -> u64 {
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            tree.hash(&mut hasher);
+            hasher.finish()
+        }
+
+        let tree: IntTree = (0..30).map(|i| (i, i)).collect();
+        let mut reshaped: IntTree = (0..60).rev().map(|i| (i, i)).collect();
+        for i in 30..60 {
+            reshaped.remove(&i);
+        }
+        assert_ne!(tree.debug_structure(), reshaped.debug_structure());
+        assert_eq!(hash_of(&tree), hash_of(&reshaped));
+        assert_ne!(
+            hash_of(&tree),
+            hash_of(&(0..30).map(|i| (i, i + 1)).collect())
+        );
+
+        let mut seen = std::collections::HashSet::new();
+        assert!(seen.insert(tree));
+        assert!(!seen.insert(reshaped));
+        assert!(seen.insert(IntTree::new()));
+    }
+
+    #[test]
+    fn ordering() {
+        let tree = |entries: &[(i32, i32)]| entries.iter().copied().collect::<IntTree>();
+        let expected = [
+            tree(&[]),
+            tree(&[(1, 1)]),
+            tree(&[(1, 1), (2, 0)]),
+            tree(&[(1, 1), (2, 0), (3, 0)]),
+            tree(&[(1, 2)]),
+            tree(&[(2, 0)]),
+            tree(&[(2, 0), (3, 0)]),
+        ];
+        let mut sorted: Vec<IntTree> = expected.iter().rev().cloned().collect();
+        sorted.sort();
+        assert_eq!(sorted, expected);
+
+        // Differently shaped trees with the same entries compare equal
+        let large: IntTree = (0..100).map(|i| (i, i)).collect();
+        let mut reshaped: IntTree = (0..200).rev().map(|i| (i, i)).collect();
+        reshaped.remove_range(100..);
+        assert_ne!(large.debug_structure(), reshaped.debug_structure());
+        assert_eq!(large.cmp(&reshaped), std::cmp::Ordering::Equal);
+        reshaped.insert(99, 100);
+        assert!(large < reshaped);
+
+        let floats: BTree<i32, f64> = [(1, f64::NAN)].into_iter().collect();
+        assert_eq!(floats.partial_cmp(&floats), None);
+    }
+
+    #[test]
+    fn insert_mem_value() {
+        let mut buffer = vec![0u8; 2 * 1024];
+        let mut tree = IntMemTree::new(&mut buffer[..]);
+
+        tree.insert(1, 2);
+        assert_eq!(tree.get(&1), Some(&2));
+    }
+
+    #[test]
+    fn insert_mem_hundreds() {
+        let mut buffer = vec![0u8; 64 * 1024];
+        let mut tree = IntMemTree::new(&mut buffer[..]);
+        for i in 0..500 {
+            // Spread the keys out so that splits happen all over the tree
+            let key = (i * 7919) % 500;
+            assert_eq!(tree.insert(key, key * 2), None);
+        }
+        for i in 0..500 {
+            assert_eq!(tree.get(&i), Some(&(i * 2)));
+        }
+        assert_eq!(tree.insert(250, 0), Some(500));
+        assert_eq!(tree.get(&250), Some(&0));
+        assert_eq!(tree.get(&500), None);
+    }
+
+    #[test]
+    fn bytes_mem_tree_orders_keys_by_bytes() {
+        let words = [
+            "pear",
+            "fig",
+            "banana",
+            "",
+            "apple",
+            "kiwi",
+            "cherry",
+            "a",
+            "dragonfruit",
+        ];
+        let mut buffer = vec![0u8; 8 * 1024];
+        {
+            let mut tree = BytesMemTree::new(&mut buffer[..]);
+            for (i, word) in words.iter().enumerate() {
+                assert_eq!(tree.insert(word.as_bytes(), i), None);
+            }
+            assert_eq!(tree.insert(b"fig", 100), Some(1));
+            assert_eq!(tree.get(b"banana"), Some(&2));
+            assert_eq!(tree.get(b"fig"), Some(&100));
+            assert!(!tree.contains_key(b"grape"));
+            assert!(!tree.contains_key(b"ap"));
+        }
+
+        let tree = BytesMemTree::<usize>::load(&mut buffer[..]).unwrap();
+        let mut sorted = words;
+        sorted.sort();
+        let keys: Vec<_> = tree.iter().map(|(key, _)| key).collect();
+        let expected: Vec<_> = sorted.iter().map(|word| word.as_bytes()).collect();
+        assert_eq!(keys, expected);
+        assert_eq!(tree.get(b""), Some(&3));
+    }
+
+    #[test]
+    fn bytes_mem_tree_many_keys() {
+        let mut buffer = vec![0u8; 64 * 1024];
+        let mut tree = BytesMemTree::new(&mut buffer[..]);
+        for i in 0..500 {
+            let key = "x".repeat(i % 17) + &((i * 7919) % 500).to_string();
+            tree.insert(key.as_bytes(), i);
+        }
+        let keys: Vec<_> = tree.iter().map(|(key, _)| key.to_vec()).collect();
+        let mut expected = keys.clone();
+        expected.sort();
+        assert_eq!(keys.len(), 500);
+        assert_eq!(keys, expected);
+        let key = "x".repeat(3) + &((3 * 7919) % 500).to_string();
+        assert_eq!(tree.get(key.as_bytes()), Some(&3));
+    }
+
+    #[test]
+    fn insert_mem_grows_nodes_in_place() {
+        let mut buffer = vec![0u8; 64 * 1024];
+        let mut tree = IntMemTree::new(&mut buffer[..]);
+        for i in 0..3 {
+            tree.insert(i, i);
+        }
+        // A leaf holding three entries has room for a fourth
+        let allocations = tree.allocations();
+        let free_bytes = tree.free_bytes();
+        tree.insert(3, 3);
+        assert_eq!(tree.allocations(), allocations);
+        assert_eq!(tree.free_bytes(), free_bytes);
+
+        for i in 4..1000 {
+            tree.insert(i, i);
+        }
+        // Reallocating on every insert took nearly three allocations per key, counting splits
+        assert!(tree.allocations() < 2 * 1000, "{}", tree.allocations());
+        assert!((0..1000).all(|i| tree.get(&i) == Some(&i)));
+    }
+
+    #[test]
+    fn try_new_reports_small_buffers() {
+        let mut buffer = [0u8; 4];
+        let Err(err) = IntMemTree::try_new(&mut buffer[..]) else {
+            panic!("a 4 byte buffer can't hold a tree");
+        };
+        assert!(err.required > 4);
+
+        let mut buffer = vec![0u8; err.required + 64];
+        for len in err.required..buffer.len() {
+            let tree = IntMemTree::try_new(&mut buffer[..len]).unwrap();
+            assert_eq!(tree.get(&1), None);
+        }
+        let short = &mut buffer[..err.required - 1];
+        assert_eq!(IntMemTree::try_new(short).err(), Some(err));
+
+        // Over-aligned keys can need padding before the root
+        let mut buffer = vec![0u8; 1024];
+        let err = super::memtree::BTree::<u128, u8>::try_new(&mut buffer[..4]).err();
+        let required = err.unwrap().required;
+        for len in required..required + 64 {
+            super::memtree::BTree::<u128, u8>::try_new(&mut buffer[..len]).unwrap();
+        }
+    }
+
+    #[test]
+    fn bulk_insert_mem() {
+        let mut buffer = vec![0u8; 256 * 1024];
+        let mut tree = IntMemTree::new(&mut buffer[..]);
+        tree.bulk_insert((0..1000).map(|i| (i * 2, i)));
+        // Packed full, the leaves and branches take far fewer allocations than one per entry
+        assert!(tree.allocations() < 1000 / 2, "{}", tree.allocations());
+        assert!((0..1000).all(|i| tree.get(&(i * 2)) == Some(&i)));
+        assert_eq!(tree.get(&1), None);
+
+        // Later batches merge with what's there, replacing the values of keys already present
+        tree.bulk_insert((0..1000).map(|i| (i * 3, -i)));
+        let mut expected = std::collections::BTreeMap::new();
+        expected.extend((0..1000).map(|i| (i * 2, i)));
+        expected.extend((0..1000).map(|i| (i * 3, -i)));
+        let entries: Vec<_> = tree.iter().map(|(k, v)| (*k, *v)).collect();
+        assert_eq!(entries, expected.into_iter().collect::<Vec<_>>());
+        tree.insert(1, 1);
+        assert_eq!(tree.get(&1), Some(&1));
+    }
+
+    #[test]
+    fn resize_into_larger_buffer() {
+        let mut buffer = vec![0u8; 16 * 1024];
+        let mut tree = IntMemTree::new(&mut buffer[..]);
+        let mut count = 0;
+        while tree.try_insert(count, count * 2).is_ok() {
+            count += 1;
+        }
+
+        let mut larger = vec![0u8; 32 * 1024];
+        let mut resized = tree.resize_into(&mut larger[..]).unwrap();
+        assert!((0..count).all(|i| resized.get(&i) == Some(&(i * 2))));
+        assert!((0..count).all(|i| tree.get(&i) == Some(&(i * 2))));
+        resized.insert(count, count * 2);
+        assert_eq!(resized.iter().count(), count as usize + 1);
+
+        let mut tiny = [0u8; 4];
+        assert!(tree.resize_into(&mut tiny[..]).is_err());
+        let mut small = vec![0u8; 1024];
+        assert!(tree.resize_into(&mut small[..]).is_err());
+    }
+
+    #[test]
+    fn dropped_transaction_changes_nothing() {
+        let mut buffer = vec![0u8; 64 * 1024];
+        let mut tree = IntMemTree::new(&mut buffer[..]);
+        for i in 0..100 {
+            tree.insert(i, i);
+        }
+        {
+            let mut transaction = tree.transaction();
+            assert_eq!(transaction.insert(5, 500), Some(5));
+            assert_eq!(transaction.insert(200, 200), None);
+            assert_eq!(transaction.remove(&10), Some(10));
+            assert_eq!(transaction.get(&5), Some(&500));
+            assert_eq!(transaction.get(&10), None);
+            assert_eq!(transaction.get(&200), Some(&200));
+        }
+        assert!((0..100).all(|i| tree.get(&i) == Some(&i)));
+        assert_eq!(tree.get(&200), None);
+    }
+
+    #[test]
+    fn committed_transaction_applies_every_change() {
+        let mut buffer = vec![0u8; 64 * 1024];
+        let mut tree = IntMemTree::new(&mut buffer[..]);
+        for i in 0..100 {
+            tree.insert(i, i);
+        }
+        let free_bytes = tree.free_bytes();
+        let mut transaction = tree.transaction();
+        for i in (0..200).step_by(2) {
+            transaction.insert(i, i * 10);
+        }
+        for i in (1..100).step_by(4) {
+            assert_eq!(transaction.remove(&i), Some(i));
+        }
+        assert_eq!(transaction.remove(&2), Some(20));
+        assert_eq!(transaction.insert(2, 2), None);
+        transaction.commit().unwrap();
+
+        let expected: Vec<_> = (0..200)
+            .filter_map(|i| match i {
+                2 => Some((2, 2)),
+                _ if i % 2 == 0 => Some((i, i * 10)),
+                _ if i < 100 && i % 4 != 1 => Some((i, i)),
+                _ => None,
+            })
+            .collect();
+        let entries: Vec<_> = tree.iter().map(|(k, v)| (*k, *v)).collect();
+        assert_eq!(entries, expected);
+        // The old tree's nodes were freed after the swap
+        assert!(tree.free_bytes() > free_bytes / 2);
+    }
+
+    #[test]
+    fn get_value() {
+        let mut tree = IntTree::new();
+        tree.insert(1, 2);
+        let val = tree.get(&1);
+        assert_eq!(val, Some(&2));
+    }
+
+    #[test]
+    fn insert_many() {
+        let mut tree = IntTree::new();
+        for i in (0..32).rev() {
+            tree.insert(i, i.pow(2));
+        }
+        for i in (0..32i32).rev() {
+            assert_eq!(Some(&(i.pow(2))), tree.get(&i));
+        }
+    }
+
+    #[test]
+    fn insert_mem_many() {
+        let mut buffer = vec![0u8; 2 * 1024];
+        let mut tree = IntMemTree::new(&mut buffer[..]);
+        for i in (0..32).rev() {
+            tree.insert(i, i.pow(2));
+        }
+        for i in (0..32i32).rev() {
+            assert_eq!(Some(&(i.pow(2))), tree.get(&i));
+        }
+    }
+
+    #[test]
+    fn get_mut_mem() {
+        let mut buffer = vec![0u8; 4 * 1024];
+        let mut tree = IntMemTree::new(&mut buffer[..]);
+        assert_eq!(tree.get_mut(&0), None);
+        for i in 0..32 {
+            tree.insert(i, i);
+        }
+        for i in 0..32 {
+            *tree.get_mut(&i).unwrap() += 1;
+        }
+        for i in 0..32 {
+            assert_eq!(tree.get(&i), Some(&(i + 1)));
+        }
+        assert_eq!(tree.get_mut(&32), None);
+    }
+
+    #[test]
+    fn iter_mem_many() {
+        let mut buffer = vec![0u8; 2 * 1024];
+        let mut tree = IntMemTree::new(&mut buffer[..]);
+        assert_eq!(tree.iter().next(), None);
+        for i in (0..32).rev() {
+            tree.insert(i, i.pow(2));
+        }
+        let entries: Vec<_> = tree.iter().map(|(k, v)| (*k, *v)).collect();
+        let expected: Vec<_> = (0..32i32).map(|i| (i, i.pow(2))).collect();
+        assert_eq!(entries, expected);
+    }
+
+    #[test]
+    fn contains_key() {
+        let mut tree = IntTree::new();
+        assert!(!tree.contains_key(&0));
+        for i in 0..32 {
+            tree.insert(i * 2, i);
+        }
+        for i in 0..32 {
+            assert!(tree.contains_key(&(i * 2)));
+            assert!(!tree.contains_key(&(i * 2 + 1)));
+        }
+        assert!(!tree.contains_key(&-1));
+    }
+
+    #[test]
+    fn get_mut() {
+        let mut tree = IntTree::new();
+        for i in 0..10 {
+            tree.insert(i, i);
+        }
+        for i in 0..10 {
+            let val = tree.get_mut(&i).unwrap();
+            if *val > 5 {
+                *val = 10;
+            } else {
+                *val = 0;
+            }
+        }
+        for i in 0..10 {
+            assert_eq!(*tree.get(&i).unwrap(), if i > 5 { 10 } else { 0 });
+        }
+    }
+
+    #[test]
+    fn remove_value() {
+        let mut tree = IntTree::new();
+        tree.insert(1, 2);
+        let val = tree.remove(&1);
+        assert_eq!(val, Some(2));
+        let children: Vec<_> = tree.iter().collect();
+        assert_eq!(&children[..], &[]);
+    }
+
+    #[test]
+    fn remove_many() {
+        let mut tree = IntTree::new();
+        for i in 0..25 {
+            tree.insert(i, i);
+        }
+        for i in 0..25 {
+            if i < 15 {
+                assert_eq!(tree.remove(&i), Some(i));
+            }
+        }
+        for i in 0..10 {
+            assert_eq!(tree.get(&i), if i < 15 { None } else { Some(&i) });
+        }
+    }
+
+    #[test]
+    fn remove_mem_many() {
+        let mut buffer = vec![0u8; 2 * 1024];
+        let mut tree = IntMemTree::new(&mut buffer[..]);
+        for i in 0..25 {
+            tree.insert(i, i);
+        }
+        for i in 0..25 {
+            if i < 15 {
+                assert_eq!(tree.remove(&i), Some(i));
+            }
+        }
+        for i in 0..10 {
+            assert_eq!(tree.get(&i), if i < 15 { None } else { Some(&i) });
+        }
+    }
+
+    #[test]
+    fn insert_remove_mem_stress() {
+        let mut buffer = vec![0u8; 256 * 1024];
+        let mut tree = IntMemTree::new(&mut buffer[..]);
+        let mut expected = std::collections::BTreeMap::new();
+        // A small LCG keeps the workload random but reproducible
+        let mut state = 0x2545f491u32;
+        let mut next = move || {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            (state >> 16) as i32
+        };
+        for _ in 0..5000 {
+            let key = next() % 400;
+            if next() % 3 == 0 {
+                assert_eq!(tree.remove(&key), expected.remove(&key));
+            } else {
+                let value = next();
+                assert_eq!(tree.insert(key, value), expected.insert(key, value));
+            }
+        }
+        for key in 0..400 {
+            assert_eq!(tree.get(&key), expected.get(&key));
+        }
+        assert!(tree.iter().eq(expected.iter()));
+        for key in 0..400 {
+            assert_eq!(tree.remove(&key), expected.remove(&key));
+        }
+        for key in 0..400 {
+            assert_eq!(tree.get(&key), None);
+        }
+    }
+
+    #[test]
+    fn mem_tree_len() {
+        let mut buffer = vec![0u8; 256 * 1024];
+        let mut tree = IntMemTree::new(&mut buffer[..]);
+        assert_eq!(tree.len(), 0);
+        assert!(tree.is_empty());
+        let mut state = 0x1b873593u32;
+        let mut next = move || {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            (state >> 16) as i32
+        };
+        for _ in 0..3000 {
+            let key = next() % 300;
+            if next() % 3 == 0 {
+                tree.remove(&key);
+            } else {
+                tree.insert(key, key);
+            }
+            assert_eq!(tree.len(), tree.iter().count());
+        }
+
+        tree.compact().unwrap();
+        assert_eq!(tree.len(), tree.iter().count());
+        tree.bulk_insert((300..400).map(|i| (i, i)));
+        assert_eq!(tree.len(), tree.iter().count());
+        let mut transaction = tree.transaction();
+        transaction.remove(&350);
+        transaction.insert(1000, 0);
+        transaction.commit().unwrap();
+        assert_eq!(tree.len(), tree.iter().count());
+        let len = tree.len();
+        drop(tree);
+
+        let mut tree = IntMemTree::load(&mut buffer[..]).unwrap();
+        assert_eq!(tree.len(), len);
+        // An insert that runs out of room leaves the count as it was
+        while tree.try_insert(tree.len() as i32 + 2000, 0).is_ok() {}
+        assert_eq!(tree.len(), tree.iter().count());
+    }
+
+    #[test]
+    fn load_moved_buffer() {
+        let mut buffer = vec![0u8; 32 * 1024];
+        let mut tree = IntMemTree::new(&mut buffer[..]);
+        for i in 0..300 {
+            tree.insert(i, i);
+        }
+        for i in (0..300).step_by(3) {
+            tree.remove(&i);
+        }
+        drop(tree);
+
+        // The allocator only keeps offsets, so a copy of the buffer can be changed on its own
+        let mut moved = buffer.clone();
+        drop(buffer);
+        let mut tree = IntMemTree::load(&mut moved[..]).unwrap();
+        for i in 300..600 {
+            tree.insert(i, i);
+        }
+        for i in (0..600).step_by(3) {
+            tree.remove(&i);
+        }
+        assert_eq!(tree.len(), 400);
+        assert!(tree.iter().all(|(key, val)| key % 3 != 0 && key == val));
+    }
+
+    #[test]
+    fn custom_allocator() {
+        /// Hands out space front to back and never takes any of it back
+        struct Bump {
+            next: Cell<usize>,
+            end: usize,
+        }
+
+        unsafe impl BufferAllocator for Bump {
+            unsafe fn init(_: *mut u8, start: usize, size: usize) -> Self {
+                Bump {
+                    next: Cell::new(start),
+                    end: start + size,
+                }
+            }
+
+            unsafe fn alloc(&self, base: *mut u8, layout: Layout) -> Option<usize> {
+                let base = base as usize;
+                let start = (base + self.next.get()).next_multiple_of(layout.align()) - base;
+                if start + layout.size() > self.end {
+                    return None;
+                }
+                self.next.set(start + layout.size());
+                Some(start)
+            }
+
+            unsafe fn dealloc(&self, _: *mut u8, _: usize, _: Layout) {}
+
+            fn free(&self) -> usize {
+                self.end - self.next.get()
+            }
+        }
+
+        type BumpMemTree<'a> = super::memtree::BTree<'a, i32, i32, Bump>;
+
+        let mut buffer = vec![0u8; 64 * 1024];
+        let mut tree = BumpMemTree::with_allocator(&mut buffer[..]);
+        for i in (0..200).rev() {
+            tree.insert(i, i * 2);
+        }
+        for i in (0..200).step_by(4) {
+            assert_eq!(tree.remove(&i), Some(i * 2));
+        }
+        assert_eq!(tree.len(), 150);
+        assert!(tree
+            .iter()
+            .all(|(key, val)| key % 4 != 0 && *val == key * 2));
+
+        // Compacting builds the new nodes in fresh space, since the old ones are never reused
+        let free_bytes = tree.free_bytes();
+        assert_eq!(tree.compact().unwrap(), 0);
+        assert!(tree.free_bytes() < free_bytes);
+        assert_eq!(tree.len(), 150);
+        assert!(tree
+            .iter()
+            .all(|(key, val)| key % 4 != 0 && *val == key * 2));
+
+        // Nothing is ever freed, so the space new keys take up isn't given back by removing them,
+        // and the nodes that run short can't always be merged, but removing still works
+        let mut added = 200;
+        while tree.try_insert(added, 0).is_ok() {
+            added += 1;
+        }
+        let free_bytes = tree.free_bytes();
+        for i in 200..added {
+            assert_eq!(tree.remove(&i), Some(0));
+        }
+        assert!(tree.free_bytes() <= free_bytes);
+        // With no room left for a second copy, compacting leaves the tree as it was
+        assert!(matches!(tree.compact(), Err(MemTreeError::OutOfSpace(_))));
+        assert_eq!(tree.len(), 150);
+        assert_eq!(tree.remove(&1), Some(2));
+        assert_eq!(tree.len(), 149);
+        assert!(tree
+            .iter()
+            .all(|(key, val)| key % 4 != 0 && *key != 1 && *val == key * 2));
+        drop(tree);
+
+        let tree = BumpMemTree::load_with_allocator(&mut buffer[..]).unwrap();
+        assert_eq!(tree.len(), 149);
+        assert_eq!(tree.get(&3), Some(&6));
+    }
+
+    #[test]
+    fn allocator_faults() {
+        #[derive(Clone, Copy)]
+        enum Fault {
+            None,
+            Refuse,
+            OutOfBounds,
+        }
+        thread_local! {
+            static FAULT: Cell<Fault> = const { Cell::new(Fault::None) };
+        }
+
+        /// A free list that can be made to run out of space, or to hand out space past the end of
+        /// the buffer as if its bookkeeping had been damaged
+        struct Faulty {
+            list: FreeList,
+            end: usize,
+        }
+
+        unsafe impl BufferAllocator for Faulty {
+            unsafe fn init(base: *mut u8, start: usize, size: usize) -> Self {
+                Faulty {
+                    list: FreeList::init(base, start, size),
+                    end: start + size,
+                }
+            }
+
+            unsafe fn alloc(&self, base: *mut u8, layout: Layout) -> Option<usize> {
+                match FAULT.get() {
+                    Fault::None => self.list.alloc(base, layout),
+                    Fault::Refuse => None,
+                    Fault::OutOfBounds => Some(self.end),
+                }
+            }
+
+            unsafe fn dealloc(&self, base: *mut u8, offset: usize, layout: Layout) {
+                assert!(offset < self.end, "freed a block outside the buffer");
+                self.list.dealloc(base, offset, layout);
+            }
+
+            fn free(&self) -> usize {
+                self.list.free()
+            }
+        }
+
+        type FaultyMemTree<'a> = super::memtree::BTree<'a, i32, i32, Faulty>;
+
+        let mut buffer = vec![0u8; 32 * 1024];
+        let mut tree = FaultyMemTree::with_allocator(&mut buffer[..]);
+        for i in 0..300 {
+            tree.insert(i, i);
+        }
+
+        // A block past the end is reported, and never given back to the allocator
+        FAULT.set(Fault::OutOfBounds);
+        assert!(matches!(
+            tree.try_insert(1000, 0),
+            Err(MemTreeError::OffsetOverflow { .. })
+        ));
+        assert_eq!(tree.len(), 300);
+        assert_eq!(tree.get(&1000), None);
+
+        // With no space to merge nodes into, removing leaves them short but keeps everything
+        // reachable
+        FAULT.set(Fault::Refuse);
+        let mut state = 3u32;
+        let mut keys: Vec<i32> = (0..300).collect();
+        for i in (1..keys.len()).rev() {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            keys.swap(i, (state >> 8) as usize % (i + 1));
+        }
+        for (removed, key) in keys.iter().enumerate() {
+            assert_eq!(tree.remove(key), Some(*key));
+            assert_eq!(tree.len(), 299 - removed);
+            if removed % 20 == 0 {
+                assert!(tree.verify_nodes().is_ok());
+                assert!(keys[removed + 1..]
+                    .iter()
+                    .all(|key| tree.get(key) == Some(key)));
+            }
+        }
+        assert_eq!(tree.iter().count(), 0);
+
+        FAULT.set(Fault::None);
+        for i in 0..300 {
+            tree.insert(i, i);
+        }
+        assert!(tree.iter().map(|(key, _)| *key).eq(0..300));
+    }
+
+    #[test]
+    fn mem_tree_without_debug() {
+        #[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+        struct Key(u32);
+        #[derive(Clone, PartialEq)]
+        struct Value(u64);
+
+        let mut buffer = vec![0u8; 16 * 1024];
+        let mut tree = super::memtree::BTree::new(&mut buffer[..]);
+        for i in 0..50 {
+            assert!(tree.insert(Key(i), Value(i as u64)).is_none());
+        }
+        assert!(tree.remove(&Key(10)) == Some(Value(10)));
+        assert!(tree.get(&Key(20)) == Some(&Value(20)));
+        let mut transaction = tree.transaction();
+        transaction.insert(Key(10), Value(100));
+        transaction.commit().unwrap();
+        assert!(tree.get(&Key(10)) == Some(&Value(100)));
+        assert_eq!(tree.len(), 50);
+    }
+
+    #[test]
+    fn commit_needs_room_for_a_copy() {
+        let mut buffer = vec![0u8; 8 * 1024];
+        let mut tree = IntMemTree::new(&mut buffer[..]);
+        let mut len = 0;
+        while tree.try_insert(len, len).is_ok() {
+            len += 1;
+        }
+
+        // Even a single change rebuilds every node, and a full buffer has no room for that
+        let free_bytes = tree.free_bytes();
+        let mut transaction = tree.transaction();
+        transaction.insert(0, 100);
+        assert!(matches!(
+            transaction.commit(),
+            Err(MemTreeError::OutOfSpace(_))
+        ));
+        assert!(tree
+            .iter()
+            .map(|(key, val)| (*key, *val))
+            .eq((0..len).map(|i| (i, i))));
+        assert_eq!(tree.free_bytes(), free_bytes);
+
+        // With the tree down to a third, there's room for the copy alongside it
+        for i in len / 3..len {
+            tree.remove(&i);
+        }
+        let mut transaction = tree.transaction();
+        transaction.insert(0, 100);
+        transaction.commit().unwrap();
+        assert_eq!(tree.get(&0), Some(&100));
+        assert_eq!(tree.len(), (len / 3) as usize);
+    }
+
+    #[test]
+    fn insert_mem_ten_thousand() {
+        let mut buffer = vec![0u8; 1024 * 1024];
+        let mut tree = IntMemTree::new(&mut buffer[..]);
+        for i in 0..10_000 {
+            tree.insert(i, i);
+        }
+        for i in 0..10_000 {
+            assert_eq!(tree.get(&i), Some(&i));
+        }
+    }
+
+    #[test]
+    fn try_insert_full_buffer() {
+        let mut buffer = vec![0u8; 512];
+        let mut tree = IntMemTree::new(&mut buffer[..]);
+        let mut inserted = 0;
+        let err = loop {
+            match tree.try_insert(inserted, inserted) {
+                Ok(previous) => assert_eq!(previous, None),
+                Err(err) => break err,
+            }
+            inserted += 1;
+            assert!(inserted < 1000, "a 512 byte buffer should fill up");
+        };
+        let MemTreeError::OutOfSpace(err) = err else {
+            panic!("expected to run out of space, not {err}");
+        };
+        assert!(err.bytes > 0);
+
+        // The failed insert leaves the tree untouched
+        for i in 0..inserted {
+            assert_eq!(tree.get(&i), Some(&i));
+        }
+        assert_eq!(tree.get(&inserted), None);
+        // Overwriting doesn't need a new node, so it still works
+        assert_eq!(tree.try_insert(0, 100), Ok(Some(0)));
+        assert_eq!(tree.get(&0), Some(&100));
+    }
+
+    #[test]
+    fn remove_from_full_buffer() {
+        let mut buffer = vec![0u8; 4096];
+        let mut tree = IntMemTree::new(&mut buffer[..]);
+        let mut state = 7u32;
+        let mut next = move || {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            state >> 8
+        };
+        let mut keys = Vec::new();
+        loop {
+            let key = (next() % 10_000) as i32;
+            match tree.try_insert(key, key) {
+                Ok(None) => keys.push(key),
+                Ok(Some(_)) => {}
+                Err(_) => break,
+            }
+        }
+
+        // Taking entries out never needs more room than the tree already has, even when it's
+        // completely out of space
+        for i in (1..keys.len()).rev() {
+            keys.swap(i, next() as usize % (i + 1));
+        }
+        while let Some(key) = keys.pop() {
+            assert_eq!(tree.remove(&key), Some(key));
+            assert_eq!(tree.len(), keys.len());
+            if keys.len() % 16 == 0 {
+                assert!(tree.verify_nodes().is_ok());
+                assert!(keys.iter().all(|key| tree.get(key) == Some(key)));
+                assert!(tree.iter().zip(tree.iter().skip(1)).all(|(a, b)| a.0 < b.0));
+            }
+        }
+        assert_eq!(tree.iter().count(), 0);
+        assert_eq!(tree.try_insert(1, 1), Ok(None));
+    }
+
+    #[test]
+    fn compact_reclaims_space() {
+        let mut buffer = vec![0u8; 64 * 1024];
+        let mut tree = IntMemTree::new(&mut buffer[..]);
+        for i in 0..500 {
+            tree.insert(i, i);
+        }
+        for i in (0..500).filter(|i| i % 4 != 0) {
+            tree.remove(&i);
+        }
+
+        let free_before = tree.free_bytes();
+        let reclaimed = tree.compact().unwrap();
+        assert!(reclaimed > 0);
+        assert_eq!(tree.free_bytes(), free_before + reclaimed);
+        for i in 0..500 {
+            assert_eq!(tree.get(&i), (i % 4 == 0).then_some(&i));
+        }
+
+        // The compacted tree is still a normal tree
+        for i in 500..600 {
+            tree.insert(i, i);
+        }
+        for i in (0..500).step_by(4) {
+            assert_eq!(tree.remove(&i), Some(i));
+        }
+        for i in 0..600 {
+            assert_eq!(tree.get(&i), (i >= 500).then_some(&i));
+        }
+    }
+
+    #[test]
+    fn compact_empty_tree() {
+        let mut buffer = vec![0u8; 1024];
+        let mut tree = IntMemTree::new(&mut buffer[..]);
+        tree.compact().unwrap();
+        assert_eq!(tree.get(&0), None);
+        tree.insert(0, 0);
+        assert_eq!(tree.get(&0), Some(&0));
+    }
+
+    #[test]
+    fn remove_mem_subtree_minimum() {
+        let mut buffer = vec![0u8; 8 * 1024];
+        let mut tree = IntMemTree::new(&mut buffer[..]);
+        for i in (0..64).step_by(2) {
+            tree.insert(i, i);
+        }
+        // Every even key is the smallest in some subtree at some point, and the odd key after it
+        // has to be routed by the interval left behind
+        for i in (0..64).step_by(2) {
+            assert_eq!(tree.remove(&i), Some(i));
+            tree.insert(i + 1, i + 1);
+            assert_eq!(tree.get(&(i + 1)), Some(&(i + 1)));
+        }
+        let entries: Vec<_> = tree.iter().map(|(k, _)| *k).collect();
+        let expected: Vec<_> = (0..64).filter(|i| i % 2 == 1).collect();
+        assert_eq!(entries, expected);
+    }
+
+    #[test]
+    fn overaligned_mem_keys() {
+        #[repr(align(64))]
+        #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+        struct Aligned(i32);
+
+        let mut buffer = vec![0u8; 64 * 1024];
+        let mut tree = super::memtree::BTree::new(&mut buffer[..]);
+        for i in 0..50 {
+            tree.insert(Aligned(i), i);
+        }
+        for i in 0..50 {
+            let key = tree.iter().nth(i as usize).unwrap().0;
+            assert_eq!(key as *const Aligned as usize % 64, 0);
+            assert_eq!(tree.get(&Aligned(i)), Some(&i));
+        }
+        for i in (0..50).step_by(2) {
+            assert_eq!(tree.remove(&Aligned(i)), Some(i));
+        }
+        let remaining: Vec<_> = tree.iter().map(|(k, v)| (k.0, *v)).collect();
+        let expected: Vec<_> = (0..50).filter(|i| i % 2 == 1).map(|i| (i, i)).collect();
+        assert_eq!(remaining, expected);
+    }
+
+    /// Counts how many copies of it are alive, to catch leaks and double drops
+    #[derive(Debug)]
+    struct Counted(i32, Rc<Cell<i32>>);
+
+    impl Counted {
+        fn new(val: i32, live: &Rc<Cell<i32>>) -> Self {
+            live.set(live.get() + 1);
+            Counted(val, live.clone())
+        }
+    }
+
+    impl Clone for Counted {
+        fn clone(&self) -> Self {
+            Counted::new(self.0, &self.1)
+        }
+    }
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.1.set(self.1.get() - 1);
+        }
+    }
+
+    impl PartialEq for Counted {
+        fn eq(&self, other: &Self) -> bool {
+            self.0 == other.0
+        }
+    }
+
+    impl Eq for Counted {}
+
+    impl PartialOrd for Counted {
+        fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    impl Ord for Counted {
+        fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+            self.0.cmp(&other.0)
+        }
+    }
+
+    #[test]
+    fn drop_mem_entries() {
+        let live = Rc::new(Cell::new(0));
+        let mut buffer = vec![0u8; 16 * 1024];
+        {
+            let mut tree = super::memtree::BTree::new(&mut buffer[..]);
+            for i in 0..100 {
+                tree.insert(Counted::new(i, &live), Counted::new(i, &live));
+            }
+            for i in (0..100).step_by(3) {
+                let previous = tree.insert(Counted::new(i, &live), Counted::new(-i, &live));
+                assert_eq!(previous.map(|val| val.0), Some(i));
+            }
+            for i in (0..100).step_by(2) {
+                let removed = tree.remove(&Counted::new(i, &live));
+                assert!(removed.is_some());
+            }
+            assert!(live.get() > 0);
+        }
+        assert_eq!(live.get(), 0);
+
+        // Inserts that run out of space and are rolled back don't leak either
+        let mut buffer = vec![0u8; 2 * 1024];
+        {
+            let mut tree = super::memtree::BTree::new(&mut buffer[..]);
+            let mut i = 0;
+            while tree
+                .try_insert(Counted::new(i, &live), Counted::new(i, &live))
+                .is_ok()
+            {
+                i += 1;
+            }
+            for j in 0..i {
+                assert_eq!(tree.get(&Counted::new(j, &live)).map(|val| val.0), Some(j));
+            }
+        }
+        assert_eq!(live.get(), 0);
+    }
+
+    #[test]
+    fn convert_between_trees() {
+        let tree: IntTree = (0..500).map(|i| (i, i * 2)).collect();
+        let mut buffer = vec![0u8; 64 * 1024];
+        {
+            let mem_tree = tree.write_to(&mut buffer[..]).unwrap();
+            assert!(mem_tree.iter().eq(tree.iter()));
+            assert_eq!(mem_tree.to_btree(), tree);
+        }
+        let mut mem_tree = IntMemTree::load(&mut buffer[..]).unwrap();
+        mem_tree.insert(500, 1000);
+        assert_eq!(mem_tree.to_btree().len(), 501);
+
+        let mut small_buffer = vec![0u8; 1024];
+        assert!(tree.write_to(&mut small_buffer[..]).is_err());
+        // A buffer that can't even hold the header is reported rather than panicking
+        let mut tiny_buffer = [0u8; 16];
+        let Err(MemTreeError::OutOfSpace(err)) = tree.write_to(&mut tiny_buffer[..]) else {
+            panic!("a 16 byte buffer can't hold a tree");
+        };
+        assert!(err.bytes > 16);
+
+        let live = Rc::new(Cell::new(0));
+        let counted: BTree<Counted, Counted> = (0..200)
+            .map(|i| (Counted::new(i, &live), Counted::new(i, &live)))
+            .collect();
+        // Running out of space at any point while writing doesn't leak what was already copied
+        let mut written = 0;
+        for size in (8..64).map(|i| i * 256) {
+            let mut buffer = vec![0u8; size];
+            written += counted.write_to(&mut buffer[..]).is_ok() as usize;
+        }
+        assert!(written > 0 && written < 56);
+        drop(counted);
+        assert_eq!(live.get(), 0);
+    }
+
+    #[test]
+    fn restore_from_buffer() {
+        let mut buffer = vec![0u8; 2 * 1024];
+        {
+            let mut tree = IntMemTree::new(&mut buffer[..]);
+            for i in 0..25 {
+                tree.insert(i, i);
+            }
+            for i in 0..25 {
+                if i < 15 {
+                    assert_eq!(tree.remove(&i), Some(i));
+                }
+            }
+        }
+
+        {
+            let tree = IntMemTree::load(&mut buffer[..]).unwrap();
+            for i in 0..10 {
+                assert_eq!(tree.get(&i), if i < 15 { None } else { Some(&i) });
+            }
+        }
+    }
+
+    #[test]
+    fn load_and_keep_mutating() {
+        let mut buffer = vec![0u8; 16 * 1024];
+        {
+            let mut tree = IntMemTree::new(&mut buffer[..]);
+            for i in 0..50 {
+                tree.insert(i, i);
+            }
+        }
+
+        {
+            let mut tree = IntMemTree::load(&mut buffer[..]).unwrap();
+            for i in 50..100 {
+                tree.insert(i, i);
+            }
+            for i in 0..25 {
+                assert_eq!(tree.remove(&i), Some(i));
+            }
+        }
+
+        let tree = IntMemTree::load(&mut buffer[..]).unwrap();
+        for i in 0..100 {
+            assert_eq!(tree.get(&i), (i >= 25).then_some(&i));
+        }
+    }
+
+    #[test]
+    fn load_read_only() {
+        let mut buffer = vec![0u8; 16 * 1024];
+        {
+            let mut tree = IntMemTree::new(&mut buffer[..]);
+            for i in 0..100 {
+                tree.insert(i, i * 3);
+            }
+            tree.checkpoint();
+        }
+
+        let buffer = buffer;
+        let before = buffer.clone();
+        let tree = IntMemTree::load_ro(&buffer).unwrap();
+        let also = IntMemTree::load_ro(&buffer).unwrap();
+        for i in -5..105 {
+            assert_eq!(tree.get(&i), (0..100).contains(&i).then_some(&(i * 3)));
+            assert_eq!(also.contains_key(&i), (0..100).contains(&i));
+        }
+        assert!(tree
+            .iter()
+            .map(|(key, val)| (*key, *val))
+            .eq((0..100).map(|i| (i, i * 3))));
+        drop((tree, also));
+        // Reading didn't so much as mark the checksum stale
+        assert_eq!(buffer, before);
+        assert!(IntMemTree::verify(&buffer));
+
+        assert_eq!(
+            super::memtree::BTree::<i64, i32>::load_ro(&buffer).err(),
+            Some(LoadError::LayoutMismatch {
+                key_size: 4,
+                value_size: 4
+            })
+        );
+    }
+
+    #[test]
+    fn load_rejects_foreign_buffers() {
+        let mut buffer = vec![0u8; 1024];
+        IntMemTree::new(&mut buffer[..]).insert(1, 1);
+        assert!(IntMemTree::load(&mut buffer[..]).is_ok());
+        assert_eq!(
+            super::memtree::BTree::<i64, i32>::load(&mut buffer[..]).err(),
+            Some(LoadError::LayoutMismatch {
+                key_size: 4,
+                value_size: 4
+            })
+        );
+
+        buffer[0] ^= 0xFF;
+        assert_eq!(
+            IntMemTree::load(&mut buffer[..]).err(),
+            Some(LoadError::BadMagic)
+        );
+        assert_eq!(
+            IntMemTree::load(&mut [0u8; 4][..]).err(),
+            Some(LoadError::TooSmall)
+        );
+    }
+
+    #[test]
+    fn mem_tree_node_access() {
+        // Returns how many entries are beneath the node
+        fn walk(tree: &IntMemTree, id: &NodeId, depth: usize, leaves: &mut Vec<usize>) -> usize {
+            match tree.node(id) {
+                NodeRef::Branch(branch) => {
+                    let entries = branch
+                        .children
+                        .iter()
+                        .map(|child| walk(tree, &child.node_id, depth + 1, leaves))
+                        .sum();
+                    assert_eq!(branch.subtree_len(), entries);
+                    entries
+                }
+                NodeRef::Leaf(leaf) => {
+                    leaves.push(depth);
+                    for entry in leaf.children.iter() {
+                        assert_eq!(tree.get(&entry.key), Some(&entry.value));
+                    }
+                    assert!(!leaf.children.is_empty());
+                    leaf.children.len()
+                }
+            }
+        }
+
+        let mut buffer = vec![0; 64 * 1024];
+        let mut tree = IntMemTree::new(&mut buffer);
+        for i in 0..200 {
+            tree.insert(i * 7 % 200, i);
+        }
+        assert!(matches!(tree.node(tree.root()), NodeRef::Branch(_)));
+        let mut leaves = Vec::new();
+        assert_eq!(walk(&tree, tree.root(), 0, &mut leaves), 200);
+        assert!(leaves.iter().all(|depth| *depth == leaves[0]));
+
+        let mut keys = Vec::new();
+        let mut stack = vec![tree.root()];
+        while let Some(id) = stack.pop() {
+            match tree.node(id) {
+                NodeRef::Branch(branch) => {
+                    stack.extend(branch.children.iter().map(|child| &child.node_id))
+                }
+                NodeRef::Leaf(leaf) => keys.extend(leaf.children.iter().map(|entry| entry.key)),
+            }
+        }
+        keys.sort();
+        assert_eq!(keys, (0..200).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn mem_tree_separator_clones() {
+        #[derive(PartialEq, Eq, PartialOrd, Ord)]
+        struct Key(i32, Rc<Cell<usize>>);
+
+        impl Clone for Key {
+            fn clone(&self) -> Self {
+                self.1.set(self.1.get() + 1);
+                Key(self.0, self.1.clone())
+            }
+        }
+
+        fn count_nodes(tree: &super::memtree::BTree<Key, i32>, id: &NodeId) -> usize {
+            match tree.node(id) {
+                NodeRef::Branch(branch) => {
+                    let children = branch.children.iter();
+                    1 + children
+                        .map(|child| count_nodes(tree, &child.node_id))
+                        .sum::<usize>()
+                }
+                NodeRef::Leaf(_) => 1,
+            }
+        }
+
+        let clones = Rc::new(Cell::new(0));
+        let mut buffer = vec![0u8; 256 * 1024];
+        let mut tree = super::memtree::BTree::new(&mut buffer[..]);
+        for i in 0..2000 {
+            tree.insert(Key(i * 7 % 2000, clones.clone()), i);
+        }
+        assert_eq!(tree.len(), 2000);
+        // The first leaf and the root above it need one interval between them, and every split
+        // after that adds one node and copies one separator
+        assert_eq!(clones.get(), count_nodes(&tree, tree.root()) - 1);
+    }
+
+    #[test]
+    fn mem_tree_reports_damaged_nodes() {
+        // Find the leaf holding a single distinctive key; leaf entries start 32 bytes in, after
+        // the tag, checksum, length, capacity and subtree length
+        let key = 0x1234_5678;
+        let mut buffer = vec![0u8; 1024];
+        IntMemTree::new(&mut buffer[..]).insert(key, 1);
+        let leaf = (32..buffer.len() - 4)
+            .find(|&i| buffer[i..i + 4] == key.to_le_bytes() && buffer[i - 32] == 0x0C)
+            .unwrap()
+            - 32;
+
+        buffer[leaf] = 0x7F;
+        let tree = IntMemTree::load(&mut buffer[..]).unwrap();
+        assert_eq!(
+            tree.try_get(&key),
+            Err(MemTreeError::BadTag {
+                offset: leaf,
+                tag: 0x7F
+            })
+        );
+        drop(tree);
+        buffer[leaf] = 0x0C;
+
+        let len = leaf + 8..leaf + 16;
+        buffer[len.clone()].copy_from_slice(&(u64::MAX / 2).to_le_bytes());
+        let tree = IntMemTree::load(&mut buffer[..]).unwrap();
+        assert_eq!(
+            tree.try_get(&key),
+            Err(MemTreeError::OffsetOverflow { offset: leaf })
+        );
+        drop(tree);
+        buffer[len].copy_from_slice(&1u64.to_le_bytes());
+        let tree = IntMemTree::load(&mut buffer[..]).unwrap();
+        assert_eq!(tree.try_get(&key), Ok(Some(&1)));
+    }
+
+    #[test]
+    fn node_id_has_a_niche() {
+        assert_eq!(
+            std::mem::size_of::<Option<NodeId>>(),
+            std::mem::size_of::<NodeId>()
+        );
+    }
+
+    #[test]
+    fn header_is_little_endian() {
+        let mut buffer = vec![0u8; 1024];
+        let free = {
+            let mut tree = IntMemTree::new(&mut buffer[..]);
+            tree.insert(1, 1);
+            tree.free_bytes()
+        };
+        let read_u64 = |buffer: &[u8], at: usize| {
+            u64::from_le_bytes(buffer[at..at + 8].try_into().unwrap()) as usize
+        };
+        // The version follows the 8 magic bytes, and the key and value sizes follow the checksum
+        let version = u32::from_le_bytes(buffer[8..12].try_into().unwrap());
+        assert_eq!(read_u64(&buffer, 24), 4);
+        assert_eq!(read_u64(&buffer, 32), 4);
+        // Then the free list's first hole and free byte count, and each hole starts with its size
+        // and the next hole's offset
+        assert_eq!(read_u64(&buffer, 48), free);
+        let (mut hole, mut holes_size) = (read_u64(&buffer, 40), 0);
+        while hole != 0 {
+            holes_size += read_u64(&buffer, hole);
+            hole = read_u64(&buffer, hole + 8);
+        }
+        assert_eq!(holes_size, free);
+
+        // A header written by a host with the other byte order is caught by its version rather
+        // than misread: the version, checkpoint flag and checksum, the key and value sizes, the
+        // free list's two fields and the root all come out byte-swapped
+        let fields = [
+            (8, 4),
+            (12, 4),
+            (16, 4),
+            (24, 8),
+            (32, 8),
+            (40, 8),
+            (48, 8),
+            (56, 8),
+        ];
+        let swap_header = |buffer: &mut [u8]| {
+            for (at, size) in fields {
+                buffer[at..at + size].reverse();
+            }
+        };
+        swap_header(&mut buffer);
+        assert_eq!(
+            IntMemTree::load(&mut buffer[..]).err(),
+            Some(LoadError::UnsupportedVersion(version.swap_bytes()))
+        );
+        swap_header(&mut buffer);
+        assert_eq!(IntMemTree::load(&mut buffer[..]).unwrap().get(&1), Some(&1));
+    }
+
+    #[test]
+    fn checkpoint_detects_corruption() {
+        let mut buffer = vec![0u8; 4 * 1024];
+        {
+            let mut tree = IntMemTree::new(&mut buffer[..]);
+            for i in 0..50 {
+                tree.insert(i, i);
+            }
+            tree.checkpoint();
+        }
+        assert!(IntMemTree::verify(&buffer));
+        assert!(IntMemTree::load(&mut buffer[..]).is_ok());
+
+        let middle = buffer.len() / 2;
+        buffer[middle] ^= 0xFF;
+        assert!(!IntMemTree::verify(&buffer));
+        assert_eq!(
+            IntMemTree::load(&mut buffer[..]).err(),
+            Some(LoadError::ChecksumMismatch)
+        );
+
+        // Changing the tree after a checkpoint clears the stale checksum
+        buffer[middle] ^= 0xFF;
+        IntMemTree::load(&mut buffer[..]).unwrap().insert(50, 50);
+        assert!(!IntMemTree::verify(&buffer));
+        let tree = IntMemTree::load(&mut buffer[..]).unwrap();
+        assert_eq!(tree.get(&50), Some(&50));
+    }
+
+    #[test]
+    fn node_checksums_detect_corruption() {
+        let mut buffer = vec![0u8; 16 * 1024];
+        let marker = 0x5EED_0000;
+        {
+            let mut tree = IntMemTree::new(&mut buffer[..]);
+            for i in 0..200 {
+                tree.insert(i, marker + i);
+            }
+            tree.remove(&10);
+            *tree.get_mut(&20).unwrap() = marker + 1000;
+        }
+        let tree = IntMemTree::load(&mut buffer[..]).unwrap();
+        assert_eq!(tree.verify_nodes(), Ok(()));
+        assert_eq!(tree.get(&20), Some(&(marker + 1000)));
+        drop(tree);
+
+        // Damage the value stored under one key, which is only found by checking its leaf
+        let value = (marker + 150).to_le_bytes();
+        let position = buffer.windows(4).position(|bytes| bytes == value).unwrap();
+        buffer[position] ^= 0xFF;
+        let tree = IntMemTree::load(&mut buffer[..]).unwrap();
+        assert!(tree.verify_nodes().is_err());
+        #[cfg(feature = "verify-nodes")]
+        {
+            let read =
+                std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| tree.get(&150).copied()));
+            assert!(read.is_err());
+        }
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn create_and_open_file() {
+        let path = std::env::temp_dir().join(format!("catalog-create-{}", std::process::id()));
+        {
+            let mut tree = IntMemTree::create(&path, 16 * 1024).unwrap();
+            for i in 0..200 {
+                tree.insert(i, i * 2);
+            }
+            for i in 0..100 {
+                assert_eq!(tree.remove(&i), Some(i * 2));
+            }
+        }
+        {
+            let mut tree = IntMemTree::open(&path).unwrap();
+            for i in 0..200 {
+                assert_eq!(tree.get(&i), (i >= 100).then_some(&(i * 2)));
+            }
+            tree.insert(0, -1);
+        }
+        assert_eq!(IntMemTree::open(&path).unwrap().get(&0), Some(&-1));
+
+        std::fs::write(&path, b"not a tree").unwrap();
+        let err = IntMemTree::open(&path).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn reopen_file_elsewhere() {
+        let path = std::env::temp_dir().join(format!("catalog-reopen-{}", std::process::id()));
+        {
+            let mut tree = IntMemTree::create(&path, 64 * 1024).unwrap();
+            for i in 0..100 {
+                tree.insert(i, i * 2);
+            }
+        }
+        {
+            // Holding the first mapping open forces the second one somewhere else, and dropping it
+            // leaves nothing mapped where the tree used to be
+            let first = IntMemTree::open(&path).unwrap();
+            let mut tree = IntMemTree::open(&path).unwrap();
+            assert_ne!(first.root() as *const NodeId, tree.root() as *const NodeId);
+            drop(first);
+            for i in 100..1000 {
+                tree.insert(i, i * 2);
+            }
+            for i in (0..1000).step_by(2) {
+                assert_eq!(tree.remove(&i), Some(i * 2));
+            }
+        }
+        let tree = IntMemTree::open(&path).unwrap();
+        assert_eq!(tree.len(), 500);
+        assert!(tree
+            .iter()
+            .all(|(key, val)| key % 2 == 1 && *val == key * 2));
+        drop(tree);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn flush_file() {
+        let path = std::env::temp_dir().join(format!("catalog-flush-{}", std::process::id()));
+        let mut tree = IntMemTree::create(&path, 16 * 1024).unwrap();
+        for i in 0..100 {
+            tree.insert(i, i);
+        }
+        tree.flush().unwrap();
+        tree.flush_range(0, 4096).unwrap();
+        drop(tree);
+        std::fs::remove_file(&path).unwrap();
+
+        let mut buffer = vec![0u8; 2 * 1024];
+        let tree = IntMemTree::new(&mut buffer[..]);
+        tree.flush().unwrap();
+        tree.flush_range(0, 1024).unwrap();
     }
 
     #[test]
     fn mmap() {
         {
             let file = File::create_new("memmap-test-file").unwrap();
-            file.set_len(1024).unwrap();
+            file.set_len(2 * 1024).unwrap();
             let mut mmap = unsafe { MmapMut::map_mut(&file).unwrap() };
             let mut tree = IntMemTree::new(&mut mmap[..]);
             for i in 0..25 {
@@ -178,7 +3424,7 @@ mod tests {
             let mut file = File::open("memmap-test-file").unwrap();
             let mut buffer = Vec::new();
             file.read_to_end(&mut buffer).unwrap();
-            let tree = IntMemTree::load(&mut buffer[..]);
+            let tree = IntMemTree::load(&mut buffer[..]).unwrap();
             for i in 0..10 {
                 assert_eq!(tree.get(&i), if i < 15 { None } else { Some(&i) });
             }
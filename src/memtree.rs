@@ -1,229 +1,1397 @@
+mod bytes;
 mod context;
+mod free_list;
+#[cfg(feature = "mmap")]
+mod mmap;
+mod read_only;
+mod transaction;
 
-use std::fmt::Debug;
+use std::cmp::Ordering;
+use std::io;
 use std::mem::MaybeUninit;
+use std::ptr;
 
-pub use context::{BNodeContext, NodeId};
+pub use bytes::BytesMemTree;
+pub use context::{
+    BNodeContext, Branch, BranchEntry, BufferAllocator, CorruptNode, Leaf, LeafEntry, LoadError,
+    MemTreeError, NodeId, NodeRef, OutOfSpace, TooSmall,
+};
+pub use free_list::FreeList;
+pub use read_only::ReadOnlyMemTree;
+pub use transaction::Transaction;
 
-use crate::memtree::context::LeafEntry;
+use crate::tree::{Comparator, NaturalOrder};
 
-use self::context::{BranchEntry, NodeMut, NodeRef};
+use self::context::{BranchMaybeUninit, LeafMaybeUninit, NodeMut};
 
-pub struct BTree<'a, K, V> {
-    ctx: BNodeContext<'a, K, V>,
+const MIN_ITEMS_IN_NODE: usize = 2;
+const MAX_ITEMS_IN_NODE: usize = 4;
+
+/// A B+tree stored entirely within a caller-provided buffer
+///
+/// Dropping the tree leaves it in the buffer to be picked up again by [`BTree::load`], unless `K`
+/// or `V` need dropping. Those own resources outside the buffer that can't outlive the tree, so
+/// then every entry is dropped and the buffer is left holding an empty tree.
+pub struct BTree<'a, K, V, A: BufferAllocator = FreeList> {
+    ctx: BNodeContext<'a, K, V, A>,
+    /// The file mapping the buffer lives in, if the tree was opened from a file. Fields are dropped
+    /// in order, so this outlives the context pointing into it.
+    #[cfg(feature = "mmap")]
+    mmap: Option<memmap2::MmapMut>,
+}
+
+impl<'a, K, V, A: BufferAllocator> BTree<'a, K, V, A> {
+    fn with_context(ctx: BNodeContext<'a, K, V, A>) -> Self {
+        BTree {
+            ctx,
+            #[cfg(feature = "mmap")]
+            mmap: None,
+        }
+    }
+
+    /// Iterates over every entry in ascending key order
+    pub fn iter(&self) -> BTreeIter<'_, K, V, A> {
+        BTreeIter::new(&self.ctx)
+    }
+
+    /// How many entries are in the tree
+    ///
+    /// Each branch keeps count of the entries beneath it, so this is read straight off the root.
+    pub fn len(&self) -> usize {
+        subtree_len(&self.ctx, self.ctx.root())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The node at the top of the tree, for tools that walk its structure with [`BTree::node`]
+    pub fn root(&self) -> &NodeId {
+        self.ctx.root()
+    }
+
+    /// Reads a node, given an id borrowed from the tree
+    ///
+    /// The root is always a branch, and every leaf is the same number of branches below it. A
+    /// branch's entries each pair a child's id with an interval: every key beneath the child is at
+    /// least its interval and less than the next entry's. The first interval isn't kept up to date
+    /// as smaller keys are inserted, so it may be greater than keys beneath its child. A leaf's
+    /// entries are in ascending key order. No node has more than 4 entries, and only the root and a
+    /// lone leaf beneath it may have fewer than 2, unless a remove found the buffer too full to
+    /// merge a node with its sibling. Only the root and a lone leaf beneath it may be empty.
+    ///
+    /// # Panics
+    /// If the id wasn't borrowed from this tree, through [`BTree::root`] or a branch, or if the
+    /// node has been damaged; see [`BTree::try_node`] for a version that reports damage instead
+    pub fn node(&self, id: &NodeId) -> NodeRef<'_, K, V> {
+        self.try_node(id).unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// Like [`BTree::node`], but reports a damaged node instead of panicking
+    ///
+    /// # Panics
+    /// If the id wasn't borrowed from this tree
+    pub fn try_node(&self, id: &NodeId) -> Result<NodeRef<'_, K, V>, MemTreeError> {
+        // Ids can't be copied out of the buffer without `NodeId::alias`, and holding the tree keeps
+        // it from changing, so an id stored in this buffer refers to one of its live nodes
+        assert!(self.ctx.holds(id), "the node id must come from this tree");
+        unsafe { self.ctx.try_node(id) }
+    }
 }
 
-impl<K: Ord + Clone + Debug, V: Clone + Debug> BTree<'_, K, V> {
+impl<K: Ord + Clone, V: Clone> BTree<'_, K, V> {
+    /// Creates an empty tree at the start of the buffer, overwriting anything already there
+    ///
+    /// # Panics
+    /// If the buffer is too small to hold even an empty tree; see [`BTree::try_new`]
     pub fn new(buffer: &mut [u8]) -> Self {
-        let ctx = BNodeContext::new(buffer);
-        BTree { ctx }
+        BTree::with_allocator(buffer)
+    }
+
+    /// Creates an empty tree at the start of the buffer, or reports how large the buffer would
+    /// need to be if it can't hold one
+    pub fn try_new(buffer: &mut [u8]) -> Result<Self, TooSmall> {
+        BTree::try_with_allocator(buffer)
     }
 
-    pub fn load(buffer: &mut [u8]) -> Self {
-        let ctx = BNodeContext::load(buffer);
-        BTree { ctx }
+    /// Picks up a tree previously written to the buffer by [`BTree::new`] and later mutations
+    ///
+    /// The root node is tracked in a header at the start of the buffer, so nothing is allocated.
+    /// The header also records the format version and the sizes of `K` and `V`, and loading fails
+    /// if they don't match.
+    pub fn load(buffer: &mut [u8]) -> Result<Self, LoadError> {
+        BTree::load_with_allocator(buffer)
+    }
+}
+
+impl<K: Ord + Clone, V: Clone, A: BufferAllocator> BTree<'_, K, V, A> {
+    /// Like [`BTree::new`], but handing out the buffer's space with `A` rather than the default
+    /// heap
+    pub fn with_allocator(buffer: &mut [u8]) -> Self {
+        BTree::with_context(BNodeContext::with_allocator(buffer))
     }
 
+    /// Like [`BTree::try_new`], but handing out the buffer's space with `A`
+    pub fn try_with_allocator(buffer: &mut [u8]) -> Result<Self, TooSmall> {
+        Ok(BTree::with_context(BNodeContext::try_with_allocator(
+            buffer,
+        )?))
+    }
+
+    /// Like [`BTree::load`], for a buffer created with [`BTree::with_allocator`] and the same `A`
+    pub fn load_with_allocator(buffer: &mut [u8]) -> Result<Self, LoadError> {
+        Ok(BTree::with_context(BNodeContext::load_with_allocator(
+            buffer,
+        )?))
+    }
+
+    /// Looks up the value stored under a key
+    ///
+    /// # Panics
+    /// If the buffer has been damaged; see [`BTree::try_get`]
     pub fn get(&self, key: &K) -> Option<&V> {
-        get(&self.ctx, self.ctx.root(), key)
+        self.try_get(key).unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// Like [`BTree::get`], but reports a damaged buffer instead of panicking
+    pub fn try_get(&self, key: &K) -> Result<Option<&V>, MemTreeError> {
+        get(&self.ctx, self.ctx.root(), key, &NaturalOrder)
+    }
+
+    /// Borrows the value stored under a key mutably, so it can be changed in place in the buffer
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        self.ctx.invalidate_checksum();
+        get_mut(&self.ctx, self.ctx.root(), key, &NaturalOrder)
     }
 
+    /// Inserts a key-value pair, returning the value previously stored under the key
+    ///
+    /// # Panics
+    /// If the buffer runs out of space; see [`BTree::try_insert`] for a fallible version
     pub fn insert(&mut self, key: K, value: V) -> Option<V> {
-        let (new_root, old_value) = insert(&self.ctx, self.ctx.root(), key, value);
-        if let Some(new_root) = new_root {
-            self.ctx.replace_root(new_root);
-        }
-        old_value
+        self.try_insert(key, value)
+            .unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// Inserts a key-value pair, returning the value previously stored under the key
+    ///
+    /// If the buffer doesn't have room for the nodes the insert needs, the tree is left as it was
+    /// and the key and value are dropped.
+    pub fn try_insert(&mut self, key: K, value: V) -> Result<Option<V>, MemTreeError> {
+        self.try_insert_by(key, value, &NaturalOrder)
     }
 
+    /// Removes a key, returning the value that was stored under it
+    ///
+    /// Entries are shifted within the nodes they're in or moved into a sibling, and the only
+    /// allocation is for merging two small nodes, which is skipped if there's no room for it, so
+    /// keys can still be removed once the buffer has filled up.
     pub fn remove(&mut self, key: &K) -> Option<V> {
-        let (new_root, old_value) = remove(&self.ctx, self.ctx.root(), key);
-        if let Some(new_root) = new_root {
-            self.ctx.replace_root(new_root);
+        self.ctx.invalidate_checksum();
+        let previous = remove(&self.ctx, self.ctx.root(), key, &NaturalOrder);
+        // A root with a single branch beneath it is a wasted level, so promote the branch
+        let collapsed = match unsafe { self.ctx.node(self.ctx.root()) } {
+            NodeRef::Branch(root) if root.children.len() == 1 => {
+                match unsafe { self.ctx.node(&root.children[0].node_id) } {
+                    NodeRef::Branch(_) => {
+                        let entry = unsafe { take_entries(&root.children) }.pop().unwrap();
+                        Some(entry.node_id)
+                    }
+                    NodeRef::Leaf(_) => None,
+                }
+            }
+            _ => None,
+        };
+        if let Some(child_node_id) = collapsed {
+            self.ctx.replace_root(child_node_id);
+        }
+        previous
+    }
+
+    /// Stores a checksum of the whole buffer in its header, so that [`BTree::load`] and
+    /// [`BTree::verify`] can tell if it's been damaged, e.g. by a partial write to a memmapped file
+    ///
+    /// Any change to the tree clears the checksum until the next checkpoint.
+    pub fn checkpoint(&mut self) {
+        self.ctx.checkpoint();
+    }
+
+    /// Writes changes to a memory-mapped tree back to its file, returning once they're on disk
+    ///
+    /// Until then the OS writes changes back in its own time, and a crash or power loss can lose
+    /// the most recent ones. A tree in a plain buffer has nowhere to write to, so this does
+    /// nothing.
+    pub fn flush(&self) -> io::Result<()> {
+        self.ctx.seal();
+        #[cfg(feature = "mmap")]
+        if let Some(mmap) = &self.mmap {
+            return mmap.flush();
+        }
+        Ok(())
+    }
+
+    /// Like [`BTree::flush`], but only for the `len` bytes of the buffer starting at `offset`
+    pub fn flush_range(&self, offset: usize, len: usize) -> io::Result<()> {
+        self.ctx.seal();
+        #[cfg(feature = "mmap")]
+        if let Some(mmap) = &self.mmap {
+            return mmap.flush_range(offset, len);
+        }
+        let _ = (offset, len);
+        Ok(())
+    }
+
+    /// Checks that a buffer holds a tree that was checkpointed and hasn't changed since
+    pub fn verify(buffer: &[u8]) -> bool {
+        context::verify(buffer)
+    }
+
+    /// Checks every node against the checksum stored in its header, reporting the first that
+    /// doesn't match
+    ///
+    /// Unlike [`BTree::verify`], this doesn't need a checkpoint, and finds which node was damaged.
+    /// Each node's checksum is brought up to date once whatever wrote to it is done, such as when
+    /// the next change starts, the tree is flushed or checkpointed, or it's dropped. With the
+    /// `verify-nodes` feature, nodes are also checked as they're read, which panics on a mismatch.
+    pub fn verify_nodes(&self) -> Result<(), CorruptNode> {
+        self.ctx.seal();
+        unsafe { verify_node(&self.ctx, self.ctx.root()) }
+    }
+
+    /// How many bytes of the buffer are free for new nodes
+    ///
+    /// Free space can be fragmented, so an allocation smaller than this may still fail; see
+    /// [`BTree::compact`].
+    pub fn free_bytes(&self) -> usize {
+        self.ctx.free_bytes()
+    }
+
+    /// Checks up front whether the buffer could have room for `additional` more entries, so that
+    /// a batch of inserts can be turned away before any of them are made
+    ///
+    /// Nothing is set aside, so this is only an estimate: it assumes every node the entries end up
+    /// in is as empty as a node can be, but free space can be fragmented too, and the entries
+    /// share the buffer with whatever else is inserted in the meantime.
+    pub fn reserve(&self, additional: usize) -> Result<(), OutOfSpace> {
+        // There are never more branches than leaves
+        let nodes = additional.div_ceil(MIN_ITEMS_IN_NODE);
+        let node_size =
+            self.ctx.leaf_size(MAX_ITEMS_IN_NODE) + self.ctx.branch_size(MAX_ITEMS_IN_NODE);
+        let bytes = nodes.saturating_mul(node_size);
+        match self.ctx.free_bytes() >= bytes {
+            true => Ok(()),
+            false => Err(OutOfSpace { bytes }),
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn allocations(&self) -> usize {
+        self.ctx.allocations()
+    }
+
+    /// Rebuilds the tree with every node packed full, reclaiming space lost to half-empty nodes.
+    /// Returns how many bytes were freed up.
+    ///
+    /// The new nodes are built before the old ones are freed, since an allocator doesn't have to
+    /// reuse freed space, so the buffer needs room for both. If it doesn't have it, the tree is
+    /// left as it was; [`BTree::resize_into`] can move it to a bigger buffer instead.
+    pub fn compact(&mut self) -> Result<usize, MemTreeError> {
+        self.ctx.invalidate_checksum();
+        let free_before = self.ctx.free_bytes();
+        let entries = self
+            .iter()
+            .map(|(key, value)| LeafEntry {
+                key: key.clone(),
+                value: value.clone(),
+            })
+            .collect();
+        unsafe {
+            let new_root = build(&self.ctx, entries)?;
+            let old_root = self.ctx.set_root(new_root);
+            drop_node(&self.ctx, old_root);
+        }
+        Ok(self.ctx.free_bytes().saturating_sub(free_before))
+    }
+
+    /// Inserts entries that are already in ascending key order, replacing the values of any keys
+    /// already in the tree
+    ///
+    /// The tree is rebuilt bottom-up with nodes packed full, the same as [`BTree::compact`] leaves
+    /// them, rather than inserting one entry at a time, so this is the fast way to load a tree.
+    ///
+    /// # Panics
+    /// If the buffer runs out of space; see [`BTree::try_bulk_insert`] for a fallible version
+    pub fn bulk_insert(&mut self, sorted: impl IntoIterator<Item = (K, V)>) {
+        self.try_bulk_insert(sorted)
+            .unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// Like [`BTree::bulk_insert`], but reports a full buffer instead of panicking
+    ///
+    /// The new nodes are built before the old ones are freed, so the buffer needs room for both.
+    /// If it doesn't have it, the tree is left as it was and the entries are dropped.
+    pub fn try_bulk_insert(
+        &mut self,
+        sorted: impl IntoIterator<Item = (K, V)>,
+    ) -> Result<(), MemTreeError> {
+        let sorted: Vec<_> = sorted
+            .into_iter()
+            .map(|(key, value)| (key, Some(value)))
+            .collect();
+        debug_assert!(
+            sorted.windows(2).all(|pair| pair[0].0 < pair[1].0),
+            "bulk_insert needs strictly ascending keys"
+        );
+        self.ctx.invalidate_checksum();
+        let entries = merged_entries(self.iter(), sorted);
+        unsafe {
+            let new_root = build(&self.ctx, entries)?;
+            let old_root = self.ctx.set_root(new_root);
+            drop_node(&self.ctx, old_root);
+        }
+        Ok(())
+    }
+
+    /// Copies the tree into a new buffer, such as a larger one once this one has filled up,
+    /// returning a tree over it
+    ///
+    /// The new tree is built packed full like [`BTree::compact`] does, and this one is left as it
+    /// was. A buffer too small to even hold the header reports the space it needs in the error.
+    pub fn resize_into<'b>(
+        &self,
+        new_buffer: &'b mut [u8],
+    ) -> Result<BTree<'b, K, V, A>, MemTreeError> {
+        let mut ctx = BNodeContext::try_with_allocator(new_buffer).map_err(|err| OutOfSpace {
+            bytes: err.required,
+        })?;
+        let entries = self
+            .iter()
+            .map(|(key, value)| LeafEntry {
+                key: key.clone(),
+                value: value.clone(),
+            })
+            .collect();
+        let root = unsafe { build(&ctx, entries)? };
+        ctx.replace_root(root);
+        Ok(BTree::with_context(ctx))
+    }
+
+    /// Copies every entry into an in-memory [`crate::BTree`]
+    pub fn to_btree(&self) -> crate::tree::BTree<K, V> {
+        crate::tree::BTree::from_sorted(
+            self.iter().map(|(key, value)| (key.clone(), value.clone())),
+        )
+    }
+}
+
+impl<K: Clone, V: Clone, A: BufferAllocator> BTree<'_, K, V, A> {
+    /// Like [`BTree::try_insert`], but ordering keys with `cmp`
+    fn try_insert_by(
+        &mut self,
+        key: K,
+        value: V,
+        cmp: &impl Comparator<K>,
+    ) -> Result<Option<V>, MemTreeError> {
+        self.ctx.invalidate_checksum();
+        let mut journal = Journal::default();
+        match self.insert_journaled(key, value, cmp, &mut journal) {
+            Ok(previous) => {
+                unsafe { journal.commit(&self.ctx) };
+                Ok(previous)
+            }
+            Err(err) => {
+                unsafe { journal.undo(&self.ctx) };
+                Err(err)
+            }
+        }
+    }
+
+    fn insert_journaled(
+        &mut self,
+        key: K,
+        value: V,
+        cmp: &impl Comparator<K>,
+        journal: &mut Journal<K, V>,
+    ) -> Result<Option<V>, MemTreeError> {
+        let insertion = insert(&self.ctx, self.ctx.root(), key, value, cmp, journal)?;
+        let new_root = match (insertion.replacement, insertion.split) {
+            (Some(new_root), None) => new_root,
+            (Some(left), Some(split)) => {
+                // The root split in two, so both halves go beneath a new root
+                let interval = separator(lowest_key(&self.ctx, &left));
+                unsafe {
+                    let (new_root, branch) = journal.alloc_branch(&self.ctx, 2)?;
+                    branch.children[0] = MaybeUninit::new(BranchEntry {
+                        interval,
+                        node_id: left,
+                    });
+                    branch.children[1] = MaybeUninit::new(split);
+                    journal.track_interval(&mut branch.children[0]);
+                    journal.track_interval(&mut branch.children[1]);
+                    recount(&self.ctx, branch.assume_init_mut());
+                    new_root
+                }
+            }
+            (None, _) => return Ok(insertion.previous),
+        };
+        journal.replaced.push(self.ctx.set_root(new_root));
+        Ok(insertion.previous)
+    }
+}
+
+impl<K: Ord + Clone, V: Clone, const MAX: usize> crate::tree::BTree<K, V, MAX> {
+    /// Writes a copy of the tree into a buffer, overwriting anything already there, and returns it
+    /// as a [`crate::MemTree`]
+    ///
+    /// The nodes are built bottom-up and packed full, the same as [`BTree::compact`] leaves them.
+    /// A buffer too small to even hold the header reports the space it needs in the error.
+    pub fn write_to<'a>(&self, buffer: &'a mut [u8]) -> Result<BTree<'a, K, V>, MemTreeError> {
+        let mut tree = BTree::try_new(buffer).map_err(|err| OutOfSpace {
+            bytes: err.required,
+        })?;
+        let entries = self
+            .iter()
+            .map(|(key, value)| LeafEntry {
+                key: key.clone(),
+                value: value.clone(),
+            })
+            .collect();
+        unsafe {
+            let root = build(&tree.ctx, entries)?;
+            tree.ctx.replace_root(root);
+        }
+        Ok(tree)
+    }
+}
+
+impl<K, V, A: BufferAllocator> Drop for BTree<'_, K, V, A> {
+    fn drop(&mut self) {
+        if std::mem::needs_drop::<K>() || std::mem::needs_drop::<V>() {
+            self.ctx.invalidate_checksum();
+            unsafe {
+                let old_root = self.ctx.root().alias();
+                drop_node(&self.ctx, old_root);
+                let (new_root, _) = self.ctx.alloc_branch(0);
+                self.ctx.set_root(new_root);
+            }
+        }
+        // The buffer outlives the tree, so it's left with every node's checksum up to date
+        self.ctx.seal();
+    }
+}
+
+pub struct BTreeIter<'a, K, V, A: BufferAllocator = FreeList> {
+    ctx: &'a BNodeContext<'a, K, V, A>,
+    stack: Vec<std::slice::Iter<'a, BranchEntry<K>>>,
+    leaf: std::slice::Iter<'a, LeafEntry<K, V>>,
+}
+
+impl<'a, K, V, A: BufferAllocator> BTreeIter<'a, K, V, A> {
+    fn new(ctx: &'a BNodeContext<'a, K, V, A>) -> Self {
+        let root = match unsafe { ctx.node(ctx.root()) } {
+            NodeRef::Branch(root) => root,
+            NodeRef::Leaf(_) => unreachable!("the root is always a branch"),
+        };
+        BTreeIter {
+            ctx,
+            stack: vec![root.children.iter()],
+            leaf: [].iter(),
         }
-        old_value
     }
 }
 
-fn get<'a, K: Ord + Debug, V: Debug>(
-    ctx: &'a BNodeContext<'_, K, V>,
+impl<'a, K, V, A: BufferAllocator> Iterator for BTreeIter<'a, K, V, A> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let ctx = self.ctx;
+        loop {
+            if let Some(entry) = self.leaf.next() {
+                return Some((&entry.key, &entry.value));
+            }
+            match self.stack.last_mut()?.next() {
+                Some(child) => match unsafe { ctx.node(&child.node_id) } {
+                    NodeRef::Branch(branch) => self.stack.push(branch.children.iter()),
+                    NodeRef::Leaf(leaf) => self.leaf = leaf.children.iter(),
+                },
+                None => {
+                    self.stack.pop();
+                }
+            }
+        }
+    }
+}
+
+fn get<'a, K, V, A: BufferAllocator>(
+    ctx: &'a BNodeContext<'_, K, V, A>,
     node_id: &NodeId,
     key: &K,
-) -> Option<&'a V> {
-    match dbg!(unsafe { ctx.node(node_id) }) {
+    cmp: &impl Comparator<K>,
+) -> Result<Option<&'a V>, MemTreeError> {
+    match unsafe { ctx.try_node(node_id)? } {
         NodeRef::Branch(branch) => {
-            let idx = find_idx_from_interval(&branch.children[..], key);
-            if idx >= branch.children.len() {
-                None
-            } else {
-                let child_id = &branch.children[idx].node_id;
-                get(ctx, child_id, key)
+            let idx = find_idx_from_interval(&branch.children[..], key, cmp);
+            match branch.children.get(idx) {
+                Some(child) => get(ctx, &child.node_id, key, cmp),
+                None => Ok(None),
             }
         }
-        NodeRef::Leaf(leaf) => {
+        NodeRef::Leaf(leaf) => Ok(leaf
+            .children
+            .binary_search_by(|entry| cmp.compare(&entry.key, key))
+            .ok()
+            .map(|idx| &leaf.children[idx].value)),
+    }
+}
+
+/// Callers must hold the tree mutably, since the context hands out nodes through a shared reference
+#[allow(clippy::mut_from_ref)]
+fn get_mut<'a, K, V, A: BufferAllocator>(
+    ctx: &'a BNodeContext<'_, K, V, A>,
+    node_id: &NodeId,
+    key: &K,
+    cmp: &impl Comparator<K>,
+) -> Option<&'a mut V> {
+    match unsafe { ctx.node_mut(node_id) } {
+        NodeMut::Branch(branch) => {
+            let idx = find_idx_from_interval(&branch.children[..], key, cmp);
+            let child_id = &branch.children.get(idx)?.node_id;
+            get_mut(ctx, child_id, key, cmp)
+        }
+        NodeMut::Leaf(leaf) => {
             let idx = leaf
                 .children
-                .binary_search_by(|entry| entry.key.cmp(key))
+                .binary_search_by(|entry| cmp.compare(&entry.key, key))
                 .ok()?;
-            Some(&leaf.children[idx].value)
+            Some(&mut leaf.children[idx].value)
+        }
+    }
+}
+
+/// The nodes allocated and replaced over the course of an insert, so that it can be rolled back if
+/// the buffer fills up partway through
+///
+/// Nodes are never modified in place before the last allocation an insert makes, so undoing one is
+/// a matter of freeing what it allocated.
+struct Journal<K, V> {
+    allocated: Vec<NodeId>,
+    /// Nodes whose entries have been moved elsewhere, which are freed once the insert succeeds
+    replaced: Vec<NodeId>,
+    /// The entry being inserted, once it has been written into a newly allocated leaf
+    inserted: Option<*mut LeafEntry<K, V>>,
+    /// Intervals cloned into the new nodes, rather than copied out of nodes still in the tree
+    intervals: Vec<*mut K>,
+}
+
+impl<K, V> Default for Journal<K, V> {
+    fn default() -> Self {
+        Journal {
+            allocated: Vec::new(),
+            replaced: Vec::new(),
+            inserted: None,
+            intervals: Vec::new(),
+        }
+    }
+}
+
+impl<K, V> Journal<K, V> {
+    /// # Safety
+    /// See [`BNodeContext::try_alloc_leaf`]
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn alloc_leaf<'a, A: BufferAllocator>(
+        &mut self,
+        ctx: &'a BNodeContext<'_, K, V, A>,
+        len: usize,
+    ) -> Result<(NodeId, &'a mut LeafMaybeUninit<K, V>), MemTreeError> {
+        let (node_id, leaf) = ctx.try_alloc_leaf(len)?;
+        self.allocated.push(node_id.alias());
+        Ok((node_id, leaf))
+    }
+
+    /// # Safety
+    /// See [`BNodeContext::alloc_branch`]
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn alloc_branch<'a, A: BufferAllocator>(
+        &mut self,
+        ctx: &'a BNodeContext<'_, K, V, A>,
+        len: usize,
+    ) -> Result<(NodeId, &'a mut BranchMaybeUninit<K>), MemTreeError> {
+        let (node_id, branch) = ctx.try_alloc_branch(len)?;
+        self.allocated.push(node_id.alias());
+        Ok((node_id, branch))
+    }
+
+    /// Records that an entry written to a new branch holds an interval of its own, which has to be
+    /// dropped if the insert is undone
+    ///
+    /// # Safety
+    /// The entry must be initialized
+    unsafe fn track_interval(&mut self, entry: &mut MaybeUninit<BranchEntry<K>>) {
+        self.intervals
+            .push(ptr::addr_of_mut!((*entry.as_mut_ptr()).interval));
+    }
+
+    /// # Safety
+    /// The new nodes must all be reachable from the root
+    unsafe fn commit<A: BufferAllocator>(self, ctx: &BNodeContext<'_, K, V, A>) {
+        for node_id in self.replaced {
+            ctx.free(node_id);
+        }
+    }
+
+    /// # Safety
+    /// None of the new nodes may be reachable from the root
+    unsafe fn undo<A: BufferAllocator>(self, ctx: &BNodeContext<'_, K, V, A>) {
+        // Everything else in the new nodes is a copy of an entry still owned by the tree
+        if let Some(inserted) = self.inserted {
+            ptr::drop_in_place(inserted);
+        }
+        for interval in self.intervals {
+            ptr::drop_in_place(interval);
+        }
+        for node_id in self.allocated {
+            ctx.free(node_id);
         }
     }
 }
 
-fn insert<'a, K: Ord + Clone + Debug, V: Clone + Debug>(
-    ctx: &'a BNodeContext<'_, K, V>,
+/// The outcome of inserting into a node
+struct Insertion<K, V> {
+    /// A node that takes the place of the one inserted into, which the caller must replace
+    replacement: Option<NodeId>,
+    /// A node split off from the one inserted into, which belongs directly after it
+    split: Option<BranchEntry<K>>,
+    previous: Option<V>,
+}
+
+fn insert<K: Clone, V: Clone, A: BufferAllocator>(
+    ctx: &BNodeContext<'_, K, V, A>,
     node_id: &NodeId,
     key: K,
     mut value: V,
-) -> (Option<NodeId>, Option<V>) {
-    match dbg!(unsafe { ctx.node_mut(node_id) }) {
+    cmp: &impl Comparator<K>,
+    journal: &mut Journal<K, V>,
+) -> Result<Insertion<K, V>, MemTreeError> {
+    match unsafe { ctx.node_mut(node_id) } {
         NodeMut::Branch(branch) => {
-            if branch.children.len() == 0 {
+            if branch.children.is_empty() {
+                let interval = separator(&key);
                 let new_child_node_id = unsafe {
-                    let (new_node_id, new_node) = ctx.alloc_leaf(1);
-                    new_node.children[0] = MaybeUninit::new(LeafEntry {
-                        key: key.clone(),
-                        value,
-                    });
+                    let (new_node_id, new_node) = journal.alloc_leaf(ctx, 1)?;
+                    new_node.children[0] = MaybeUninit::new(LeafEntry { key, value });
+                    journal.inserted = Some(new_node.children[0].as_mut_ptr());
 
                     new_node_id
                 };
                 let new_root_node_id = unsafe {
-                    let (new_root_node_id, new_root) = ctx.alloc_branch(1);
+                    let (new_root_node_id, new_root) = journal.alloc_branch(ctx, 1)?;
                     new_root.children[0] = MaybeUninit::new(BranchEntry {
-                        interval: key,
+                        interval,
                         node_id: new_child_node_id,
                     });
+                    journal.track_interval(&mut new_root.children[0]);
+                    new_root.assume_init_mut().set_subtree_len(1);
 
                     new_root_node_id
                 };
 
-                return (Some(new_root_node_id), None);
+                return Ok(Insertion {
+                    replacement: Some(new_root_node_id),
+                    split: None,
+                    previous: None,
+                });
             }
-            let idx = find_idx_from_interval(&branch.children[..], &key);
+            let idx = find_idx_from_interval(&branch.children[..], &key, cmp);
             let child_node_id = &branch.children[idx].node_id;
-            let (new_child_id, previous_val) = insert(ctx, child_node_id, key, value);
+            let child = insert(ctx, child_node_id, key, value, cmp, journal)?;
 
-            if let Some(mut new_child_id) = new_child_id {
-                // TODO: this might cause a new interval
-                std::mem::swap(&mut branch.children[idx].node_id, &mut new_child_id);
-                unsafe {
-                    ctx.free(new_child_id);
+            match (child.replacement, child.split) {
+                (Some(new_child_id), None) => {
+                    // Nothing allocates after this, so the branch can be updated in place
+                    let old_child_id =
+                        std::mem::replace(&mut branch.children[idx].node_id, new_child_id);
+                    journal.replaced.push(old_child_id);
+                    if child.previous.is_none() {
+                        branch.set_subtree_len(branch.subtree_len() + 1);
+                    }
+                    Ok(Insertion {
+                        replacement: None,
+                        split: None,
+                        previous: child.previous,
+                    })
                 }
-            }
+                (Some(new_child_id), Some(split)) => {
+                    // Nothing allocates after this either, so use any room left in the branch
+                    if branch.children.len() < MAX_ITEMS_IN_NODE {
+                        if let Some(grown) = unsafe { ctx.grow_branch(node_id) } {
+                            let old_child_id = unsafe {
+                                let old_child = grown.children[idx].assume_init_mut();
+                                std::mem::replace(&mut old_child.node_id, new_child_id)
+                            };
+                            journal.replaced.push(old_child_id);
+                            unsafe {
+                                insert_slot(&mut grown.children, idx + 1, split);
+                                recount(ctx, grown.assume_init_mut());
+                            }
+                            return Ok(Insertion {
+                                replacement: None,
+                                split: None,
+                                previous: child.previous,
+                            });
+                        }
+                    }
+                    let (left_len, right_len) = split_lens(branch.children.len() + 1);
+                    let (replacement, split) = unsafe {
+                        let left = journal.alloc_branch(ctx, left_len)?;
+                        let right = match right_len {
+                            Some(right_len) => Some(journal.alloc_branch(ctx, right_len)?),
+                            None => None,
+                        };
 
-            /*if let Some(new_child_id) = new_child_id {
-                if children[idx].len() > MAX_ITEMS_IN_NODE {
-                    let new_node = children[idx].split();
-                    new_node.debug_validate_intervals();
-                    let (new_first_key, _) = new_node.first().unwrap();
-                    // TODO: can we avoid cloning here by storing references?
-                    intervals.insert(idx, new_first_key.clone());
-                    children.insert(idx + 1, new_node);
-                }
-                debug_assert!(children[idx].len() <= MAX_ITEMS_IN_NODE);
-            }
-
-            if children.len() > MAX_ITEMS_IN_NODE {
-                let new_node = self.split();
-                new_node.debug_validate_intervals();
-                let old_node = std::mem::take(self);
-                let (new_first_key, _) = new_node.first().unwrap();
-                *self = BNode::Branch {
-                    // TODO: can we avoid cloning here by storing references?
-                    intervals: vec![new_first_key.clone()],
-                    children: vec![old_node, new_node],
-                };
-            }*/
+                        let mut entries = take_entries(&branch.children);
+                        let old_child_id =
+                            std::mem::replace(&mut entries[idx].node_id, new_child_id);
+                        journal.replaced.push(old_child_id);
+                        entries.insert(idx + 1, split);
 
-            (None, previous_val)
+                        let mut entries = entries.into_iter();
+                        let (replacement, left) = left;
+                        fill(&mut left.children, &mut entries);
+                        if idx + 1 < left_len {
+                            journal.track_interval(&mut left.children[idx + 1]);
+                        }
+                        recount(ctx, left.assume_init_mut());
+                        let split = right.map(|(right_id, right)| {
+                            fill(&mut right.children, &mut entries);
+                            if idx + 1 >= left_len {
+                                journal.track_interval(&mut right.children[idx + 1 - left_len]);
+                            }
+                            recount(ctx, right.assume_init_mut());
+                            BranchEntry {
+                                interval: separator(&right.children[0].assume_init_ref().interval),
+                                node_id: right_id,
+                            }
+                        });
+                        (replacement, split)
+                    };
+                    Ok(Insertion {
+                        replacement: Some(replacement),
+                        split,
+                        previous: child.previous,
+                    })
+                }
+                (None, _) => {
+                    if child.previous.is_none() {
+                        branch.set_subtree_len(branch.subtree_len() + 1);
+                    }
+                    Ok(Insertion {
+                        replacement: None,
+                        split: None,
+                        previous: child.previous,
+                    })
+                }
+            }
         }
-        NodeMut::Leaf(leaf) => match leaf.children.binary_search_by(|entry| entry.key.cmp(&key)) {
+        NodeMut::Leaf(leaf) => match leaf
+            .children
+            .binary_search_by(|entry| cmp.compare(&entry.key, &key))
+        {
             Ok(idx) => {
                 let child_value = &mut leaf.children[idx].value;
                 std::mem::swap(&mut value, child_value);
-                (None, Some(value))
+                Ok(Insertion {
+                    replacement: None,
+                    split: None,
+                    previous: Some(value),
+                })
             }
             Err(insertion_idx) => {
-                let new_node_id = unsafe {
-                    let (new_node_id, new_leaf) = ctx.alloc_leaf(leaf.children.len() + 1);
-                    for (i, child) in leaf.children.iter().enumerate() {
-                        // TODO: get rid of this clone somehow
-                        let new_leaf_idx = if i < insertion_idx { i } else { i + 1 };
-                        new_leaf.children[new_leaf_idx] = MaybeUninit::new(child.clone());
+                // Nothing allocates after an insert into a leaf with room to spare, so it can't
+                // need undoing and can be done in place
+                if leaf.children.len() < MAX_ITEMS_IN_NODE {
+                    if let Some(grown) = unsafe { ctx.grow_leaf(node_id) } {
+                        unsafe {
+                            insert_slot(
+                                &mut grown.children,
+                                insertion_idx,
+                                LeafEntry { key, value },
+                            )
+                        };
+                        return Ok(Insertion {
+                            replacement: None,
+                            split: None,
+                            previous: None,
+                        });
                     }
-                    new_leaf.children[insertion_idx] = MaybeUninit::new(LeafEntry { key, value });
+                }
+                let (left_len, right_len) = split_lens(leaf.children.len() + 1);
+                let (replacement, split) = unsafe {
+                    let (replacement, left) = journal.alloc_leaf(ctx, left_len)?;
+                    let right = match right_len {
+                        Some(right_len) => Some(journal.alloc_leaf(ctx, right_len)?),
+                        None => None,
+                    };
 
-                    new_node_id
+                    let mut entries = take_entries(&leaf.children);
+                    entries.insert(insertion_idx, LeafEntry { key, value });
+
+                    let mut entries = entries.into_iter();
+                    fill(&mut left.children, &mut entries);
+                    let split = match right {
+                        Some((right_id, right)) => {
+                            fill(&mut right.children, &mut entries);
+                            if insertion_idx >= left_len {
+                                journal.inserted =
+                                    Some(right.children[insertion_idx - left_len].as_mut_ptr());
+                            }
+                            Some(BranchEntry {
+                                interval: separator(&right.children[0].assume_init_ref().key),
+                                node_id: right_id,
+                            })
+                        }
+                        None => None,
+                    };
+                    if insertion_idx < left_len {
+                        journal.inserted = Some(left.children[insertion_idx].as_mut_ptr());
+                    }
+                    (replacement, split)
                 };
-                (Some(new_node_id), None)
+                Ok(Insertion {
+                    replacement: Some(replacement),
+                    split,
+                    previous: None,
+                })
             }
         },
     }
 }
 
-fn remove<'a, K: Ord + Clone + Debug, V: Clone + Debug>(
-    ctx: &'a BNodeContext<'_, K, V>,
+/// How many entries go in each half of a node holding `len` entries, if it has to split at all
+fn split_lens(len: usize) -> (usize, Option<usize>) {
+    if len <= MAX_ITEMS_IN_NODE {
+        (len, None)
+    } else {
+        (len / 2, Some(len - len / 2))
+    }
+}
+
+/// Moves entries into freshly allocated slots, taking as many as there are slots
+fn fill<T>(slots: &mut [MaybeUninit<T>], entries: &mut impl Iterator<Item = T>) {
+    for slot in slots {
+        *slot = MaybeUninit::new(entries.next().expect("not enough entries to fill the node"));
+    }
+}
+
+/// Shifts entries along to make way for a new one at `idx`, in a node that was just grown
+///
+/// # Safety
+/// Every slot but the last must be initialized
+unsafe fn insert_slot<T>(slots: &mut [MaybeUninit<T>], idx: usize, entry: T) {
+    let start = slots.as_mut_ptr().add(idx);
+    ptr::copy(start, start.add(1), slots.len() - 1 - idx);
+    *start = MaybeUninit::new(entry);
+}
+
+/// Moves the entry at `idx` out of a node, shifting the ones after it along to fill the gap
+///
+/// # Safety
+/// The node must be shrunk by one straight after, since its last slot is left holding a copy
+unsafe fn remove_slot<T>(slots: &mut [T], idx: usize) -> T {
+    let start = slots.as_mut_ptr().add(idx);
+    let removed = ptr::read(start);
+    ptr::copy(start.add(1), start, slots.len() - 1 - idx);
+    removed
+}
+
+/// Moves every entry out of a node, which must then be freed without being used again
+///
+/// # Safety
+/// The node the entries came from must not be read from after this
+unsafe fn take_entries<T>(entries: &[T]) -> Vec<T> {
+    entries.iter().map(|entry| ptr::read(entry)).collect()
+}
+
+/// Removes a key from beneath a node, returning the value that was stored under it
+fn remove<K: Clone, V, A: BufferAllocator>(
+    ctx: &BNodeContext<'_, K, V, A>,
     node_id: &NodeId,
     key: &K,
-) -> (Option<NodeId>, Option<V>) {
-    match dbg!(unsafe { ctx.node_mut(node_id) }) {
+    cmp: &impl Comparator<K>,
+) -> Option<V> {
+    match unsafe { ctx.node_mut(node_id) } {
         NodeMut::Branch(branch) => {
             if branch.children.is_empty() {
-                return (None, None);
+                return None;
             }
 
-            let idx = find_idx_from_interval(&branch.children[..], key);
-            let child_node_id = &branch.children[idx].node_id;
-            // TODO: intervals can change
-            let (_new_node_id, previous_val) = remove(ctx, child_node_id, key);
-
-            /*if children[idx].len() < MIN_ITEMS_IN_NODE {
-                if idx > 0 {
-                    // TODO: This could be an expensive clone
-                    children[idx] = children[idx - 1].merged(&children[idx]);
-                    children.remove(idx - 1);
-                    intervals.remove(idx - 1);
-                } else if idx + 1 < children.len() {
-                    // TODO: This could be an expensive clone
-                    children[idx] = children[idx].merged(&children[idx + 1]);
-                    children.remove(idx + 1);
-                    intervals.remove(idx);
-                }
+            let idx = find_idx_from_interval(&branch.children[..], key, cmp);
+            let previous = remove(ctx, &branch.children[idx].node_id, key, cmp)?;
+            branch.set_subtree_len(branch.subtree_len() - 1);
+            // If the child's smallest key was removed, its interval moves up to the new smallest
+            if cmp.compare(key, &branch.children[idx].interval).is_eq()
+                && node_len(ctx, &branch.children[idx].node_id) > 0
+            {
+                branch.children[idx].interval =
+                    separator(lowest_key(ctx, &branch.children[idx].node_id));
             }
-            if children.len() > 1 {
-                debug_assert!(children[idx].len() >= MIN_ITEMS_IN_NODE);
-            }*/
 
-            (None, previous_val)
+            let child_len = node_len(ctx, &branch.children[idx].node_id);
+            if branch.children.len() > 1 && child_len < MIN_ITEMS_IN_NODE {
+                // Pair the underfull child with its left sibling, or its right one if it's first
+                unsafe { rebalance(ctx, node_id, idx.saturating_sub(1)) };
+            } else if child_len == 0 {
+                // A lone child only comes about when the buffer was too full to merge this branch,
+                // and once it's empty it goes, leaving this branch for its own parent to merge
+                unsafe {
+                    let child = remove_slot(&mut branch.children, 0);
+                    ctx.resize_branch(node_id, 0);
+                    ctx.free(child.node_id);
+                }
+            }
+            Some(previous)
         }
         NodeMut::Leaf(leaf) => {
-            match leaf
+            let idx = leaf
                 .children
-                .binary_search_by(|child_key| child_key.key.cmp(key))
-            {
-                Ok(idx) => (None, Some(leaf.remove(idx).value)),
-                Err(_) => (None, None),
+                .binary_search_by(|child_key| cmp.compare(&child_key.key, key))
+                .ok()?;
+            let removed = unsafe {
+                let removed = remove_slot(&mut leaf.children, idx);
+                ctx.resize_leaf(node_id, leaf.children.len() - 1);
+                removed
+            };
+            Some(removed.value)
+        }
+    }
+}
+
+/// Drops every entry beneath a node, freeing the node and its descendants
+///
+/// # Safety
+/// The node must not be reachable from the tree afterwards
+unsafe fn drop_node<K, V, A: BufferAllocator>(ctx: &BNodeContext<'_, K, V, A>, node_id: NodeId) {
+    match ctx.node(&node_id) {
+        NodeRef::Branch(branch) => {
+            for child in take_entries(&branch.children) {
+                drop_node(ctx, child.node_id);
+            }
+        }
+        NodeRef::Leaf(leaf) => drop(take_entries(&leaf.children)),
+    }
+    ctx.free(node_id);
+}
+
+/// Copies a tree's entries, in ascending order, with sorted changes applied on top of them, where
+/// `None` removes a key
+fn merged_entries<'t, K: Ord + Clone + 't, V: Clone + 't>(
+    entries: impl Iterator<Item = (&'t K, &'t V)>,
+    changes: impl IntoIterator<Item = (K, Option<V>)>,
+) -> Vec<LeafEntry<K, V>> {
+    let mut merged = Vec::new();
+    let mut entries = entries.peekable();
+    let mut changes = changes.into_iter().peekable();
+    loop {
+        let order = match (entries.peek(), changes.peek()) {
+            (None, None) => break,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some((key, _)), Some((changed, _))) => (*key).cmp(changed),
+        };
+        if order == Ordering::Less {
+            let (key, value) = entries.next().unwrap();
+            merged.push(LeafEntry {
+                key: key.clone(),
+                value: value.clone(),
+            });
+            continue;
+        }
+        if order == Ordering::Equal {
+            entries.next();
+        }
+        if let (key, Some(value)) = changes.next().unwrap() {
+            merged.push(LeafEntry { key, value });
+        }
+    }
+    merged
+}
+
+/// Builds a tree bottom-up out of sorted entries, packing nodes as full as possible, and returns
+/// its root
+///
+/// If the buffer fills up partway through, everything built so far is dropped, along with the
+/// entries that hadn't been reached yet.
+unsafe fn build<K: Clone, V, A: BufferAllocator>(
+    ctx: &BNodeContext<'_, K, V, A>,
+    entries: Vec<LeafEntry<K, V>>,
+) -> Result<NodeId, MemTreeError> {
+    let mut level = Vec::new();
+    for entries in chunk_evenly(entries) {
+        let (node_id, leaf) = match ctx.try_alloc_leaf(entries.len()) {
+            Ok(leaf) => leaf,
+            Err(err) => {
+                abandon(ctx, level);
+                return Err(err);
+            }
+        };
+        let interval = separator(&entries[0].key);
+        fill(&mut leaf.children, &mut entries.into_iter());
+        level.push(BranchEntry { interval, node_id });
+    }
+    while level.len() > MAX_ITEMS_IN_NODE {
+        let mut next_level = Vec::new();
+        let mut chunks = chunk_evenly(level).into_iter();
+        while let Some(entries) = chunks.next() {
+            let interval = separator(&entries[0].interval);
+            match try_alloc_branch_with(ctx, entries) {
+                Ok(node_id) => next_level.push(BranchEntry { interval, node_id }),
+                Err((err, entries)) => {
+                    abandon(
+                        ctx,
+                        next_level
+                            .into_iter()
+                            .chain(entries)
+                            .chain(chunks.flatten()),
+                    );
+                    return Err(err);
+                }
             }
         }
+        level = next_level;
+    }
+    try_alloc_branch_with(ctx, level).map_err(|(err, level)| {
+        abandon(ctx, level);
+        err
+    })
+}
+
+/// Allocates a branch holding `entries`, or hands them back if the buffer is full
+///
+/// # Safety
+/// See [`BNodeContext::alloc_branch`]
+unsafe fn try_alloc_branch_with<K, V, A: BufferAllocator>(
+    ctx: &BNodeContext<'_, K, V, A>,
+    entries: Vec<BranchEntry<K>>,
+) -> Result<NodeId, (MemTreeError, Vec<BranchEntry<K>>)> {
+    match ctx.try_alloc_branch(entries.len()) {
+        Ok((node_id, branch)) => {
+            fill(&mut branch.children, &mut entries.into_iter());
+            recount(ctx, branch.assume_init_mut());
+            Ok(node_id)
+        }
+        Err(err) => Err((err, entries)),
+    }
+}
+
+/// Drops nodes that were built but never made part of the tree, along with everything in them
+///
+/// # Safety
+/// None of the nodes may be reachable from the tree
+unsafe fn abandon<K, V, A: BufferAllocator>(
+    ctx: &BNodeContext<'_, K, V, A>,
+    entries: impl IntoIterator<Item = BranchEntry<K>>,
+) {
+    for entry in entries {
+        drop_node(ctx, entry.node_id);
     }
 }
 
-fn find_idx_from_interval<K: Ord>(entries: &[BranchEntry<K>], key: &K) -> usize {
-    find_idx_from_interval_recursive(&entries[1..], key)
+/// Splits entries into as few nodes as will hold them, with sizes differing by at most one so that
+/// none is underfull
+fn chunk_evenly<T>(entries: Vec<T>) -> Vec<Vec<T>> {
+    let chunk_count = entries.len().div_ceil(MAX_ITEMS_IN_NODE);
+    let mut entries = entries.into_iter();
+    let mut remaining = entries.len();
+    (0..chunk_count)
+        .rev()
+        .map(|chunks_left| {
+            let len = remaining.div_ceil(chunks_left + 1);
+            remaining -= len;
+            entries.by_ref().take(len).collect()
+        })
+        .collect()
 }
 
-fn find_idx_from_interval_recursive<K: Ord>(entries: &[BranchEntry<K>], key: &K) -> usize {
-    if entries.is_empty() {
+/// Evens out the entries of the children at `left_idx` and `left_idx + 1` of the branch at
+/// `node_id`, or merges them if there aren't enough to go around
+///
+/// Entries are shifted in place as far as the nodes have room for them, so the only time this
+/// allocates is to merge two nodes that are both too small to take the other's entries. If the
+/// buffer is full then, the underfull node is left as it is, which lookups don't mind, and a later
+/// remove or [`BTree::compact`] evens it out.
+///
+/// # Safety
+/// No other reference to the branch or its children may be in use
+unsafe fn rebalance<K: Clone, V, A: BufferAllocator>(
+    ctx: &BNodeContext<'_, K, V, A>,
+    node_id: &NodeId,
+    left_idx: usize,
+) {
+    let NodeMut::Branch(branch) = ctx.node_mut(node_id) else {
+        unreachable!("only branches have children to rebalance")
+    };
+    let right_idx = left_idx + 1;
+    let left_id = &branch.children[left_idx].node_id;
+    let right_id = &branch.children[right_idx].node_id;
+    let (left_old, right_old) = (node_len(ctx, left_id), node_len(ctx, right_id));
+    let (left_cap, right_cap) = (ctx.capacity(left_id), ctx.capacity(right_id));
+    let total = left_old + right_old;
+    let left_len = if total >= MIN_ITEMS_IN_NODE * 2 {
+        // Neither node ends up underfull, since each has room for at least the minimum
+        (total / 2).clamp(total.saturating_sub(right_cap), left_cap)
+    } else if left_cap >= total {
+        total
+    } else if right_cap >= total {
         0
     } else {
-        let halfway = entries.len() / 2;
-        match key.cmp(&entries[halfway].interval) {
-            std::cmp::Ordering::Less => find_idx_from_interval_recursive(&entries[0..halfway], key),
-            std::cmp::Ordering::Equal => halfway + 1,
-            std::cmp::Ordering::Greater => {
-                halfway + 1 + find_idx_from_interval_recursive(&entries[(halfway + 1)..], key)
+        if let Ok(merged) = merge_into_new(ctx, branch, right_idx) {
+            let old_left = std::mem::replace(&mut branch.children[left_idx].node_id, merged);
+            let old_right = remove_slot(&mut branch.children, right_idx);
+            ctx.resize_branch(node_id, branch.children.len() - 1);
+            ctx.free(old_left);
+            ctx.free(old_right.node_id);
+        }
+        return;
+    };
+    let right_len = total - left_len;
+
+    let right_interval = match (ctx.node_mut(left_id), ctx.node_mut(right_id)) {
+        (NodeMut::Leaf(_), NodeMut::Leaf(_)) => {
+            let left = ctx.resize_leaf(left_id, left_old.max(left_len)).unwrap();
+            let right = ctx.resize_leaf(right_id, right_old.max(right_len)).unwrap();
+            shift_between(
+                &mut left.children,
+                left_old,
+                &mut right.children,
+                right_old,
+                left_len,
+            );
+            ctx.resize_leaf(left_id, left_len);
+            let right = ctx.resize_leaf(right_id, right_len).unwrap();
+            right
+                .children
+                .first()
+                .map(|entry| separator(&entry.assume_init_ref().key))
+        }
+        (NodeMut::Branch(_), NodeMut::Branch(right)) => {
+            // The first interval of a branch isn't used for routing, so pull the real separator
+            // down from the parent before the entry moves or stops being first
+            if let Some(first) = right.children.first_mut() {
+                first.interval = separator(&branch.children[right_idx].interval);
+            }
+            let left = ctx.resize_branch(left_id, left_old.max(left_len)).unwrap();
+            let right = ctx
+                .resize_branch(right_id, right_old.max(right_len))
+                .unwrap();
+            shift_between(
+                &mut left.children,
+                left_old,
+                &mut right.children,
+                right_old,
+                left_len,
+            );
+            recount(
+                ctx,
+                ctx.resize_branch(left_id, left_len)
+                    .unwrap()
+                    .assume_init_mut(),
+            );
+            let right = ctx
+                .resize_branch(right_id, right_len)
+                .unwrap()
+                .assume_init_mut();
+            recount(ctx, right);
+            right
+                .children
+                .first()
+                .map(|entry| separator(&entry.interval))
+        }
+        _ => unreachable!("siblings are always at the same height"),
+    };
+
+    if left_len == 0 {
+        // Everything went into the right node, which takes the left one's place
+        let (left, right) = branch.children.split_at_mut(right_idx);
+        std::mem::swap(&mut left[left_idx].node_id, &mut right[0].node_id);
+    }
+    match right_interval.filter(|_| left_len > 0) {
+        Some(interval) => branch.children[right_idx].interval = interval,
+        None => {
+            let emptied = remove_slot(&mut branch.children, right_idx);
+            ctx.resize_branch(node_id, branch.children.len() - 1);
+            ctx.free(emptied.node_id);
+        }
+    }
+}
+
+/// Allocates a node holding the entries of the children at `right_idx - 1` and `right_idx`, in
+/// order, leaving the two of them to be freed
+///
+/// # Safety
+/// See [`rebalance`]
+unsafe fn merge_into_new<K: Clone, V, A: BufferAllocator>(
+    ctx: &BNodeContext<'_, K, V, A>,
+    branch: &Branch<K>,
+    right_idx: usize,
+) -> Result<NodeId, MemTreeError> {
+    let left_id = &branch.children[right_idx - 1].node_id;
+    let right_id = &branch.children[right_idx].node_id;
+    Ok(match (ctx.node_mut(left_id), ctx.node_mut(right_id)) {
+        (NodeMut::Leaf(left), NodeMut::Leaf(right)) => {
+            let (merged_id, merged) =
+                ctx.try_alloc_leaf(left.children.len() + right.children.len())?;
+            let (to_left, to_right) = merged.children.split_at_mut(left.children.len());
+            ptr::copy_nonoverlapping(
+                left.children.as_ptr(),
+                to_left.as_mut_ptr().cast(),
+                to_left.len(),
+            );
+            ptr::copy_nonoverlapping(
+                right.children.as_ptr(),
+                to_right.as_mut_ptr().cast(),
+                to_right.len(),
+            );
+            merged_id
+        }
+        (NodeMut::Branch(left), NodeMut::Branch(right)) => {
+            let (merged_id, merged) =
+                ctx.try_alloc_branch(left.children.len() + right.children.len())?;
+            // Nothing can fail after this, so the separator can be pulled down from the parent
+            if let Some(first) = right.children.first_mut() {
+                first.interval = separator(&branch.children[right_idx].interval);
             }
+            let (to_left, to_right) = merged.children.split_at_mut(left.children.len());
+            ptr::copy_nonoverlapping(
+                left.children.as_ptr(),
+                to_left.as_mut_ptr().cast(),
+                to_left.len(),
+            );
+            ptr::copy_nonoverlapping(
+                right.children.as_ptr(),
+                to_right.as_mut_ptr().cast(),
+                to_right.len(),
+            );
+            recount(ctx, merged.assume_init_mut());
+            merged_id
+        }
+        _ => unreachable!("siblings are always at the same height"),
+    })
+}
+
+/// Moves entries between the end of one node and the start of the next, keeping them in order,
+/// until the left holds `left_len` of them
+///
+/// # Safety
+/// The first `left_old` and `right_old` slots must be initialized, and each node must currently
+/// have room for both the entries it held and the ones it will hold
+unsafe fn shift_between<T>(
+    left: &mut [MaybeUninit<T>],
+    left_old: usize,
+    right: &mut [MaybeUninit<T>],
+    right_old: usize,
+    left_len: usize,
+) {
+    let (left, right) = (left.as_mut_ptr(), right.as_mut_ptr());
+    if left_len >= left_old {
+        let moved = left_len - left_old;
+        ptr::copy_nonoverlapping(right, left.add(left_old), moved);
+        ptr::copy(right.add(moved), right, right_old - moved);
+    } else {
+        let moved = left_old - left_len;
+        ptr::copy(right, right.add(moved), right_old);
+        ptr::copy_nonoverlapping(left.add(left_len), right, moved);
+    }
+}
+
+fn node_len<K, V, A: BufferAllocator>(ctx: &BNodeContext<'_, K, V, A>, node_id: &NodeId) -> usize {
+    match unsafe { ctx.node(node_id) } {
+        NodeRef::Branch(branch) => branch.children.len(),
+        NodeRef::Leaf(leaf) => leaf.children.len(),
+    }
+}
+
+/// How many entries are beneath a node, or in it if it's a leaf
+fn subtree_len<K, V, A: BufferAllocator>(
+    ctx: &BNodeContext<'_, K, V, A>,
+    node_id: &NodeId,
+) -> usize {
+    match unsafe { ctx.node(node_id) } {
+        NodeRef::Branch(branch) => branch.subtree_len(),
+        NodeRef::Leaf(leaf) => leaf.children.len(),
+    }
+}
+
+/// Sums up the entries beneath a branch's children, once they've all been counted themselves
+fn recount<K, V, A: BufferAllocator>(ctx: &BNodeContext<'_, K, V, A>, branch: &mut Branch<K>) {
+    let len = branch
+        .children
+        .iter()
+        .map(|child| subtree_len(ctx, &child.node_id))
+        .sum();
+    branch.set_subtree_len(len);
+}
+
+/// Copies a key to serve as a branch's interval
+///
+/// Intervals are stored in the buffer by value, so they can't share a key with the leaf entry or
+/// interval they're copied from. Every interval the tree makes is copied here, so apart from
+/// copying whole entries into another tree, this is the only place keys are cloned.
+fn separator<K: Clone>(key: &K) -> K {
+    key.clone()
+}
+
+/// The smallest key beneath a node
+///
+/// The first interval of a branch isn't used for routing, so it isn't kept up to date when smaller
+/// keys are inserted, and this has to go all the way down to a leaf.
+fn lowest_key<'a, K, V, A: BufferAllocator>(
+    ctx: &'a BNodeContext<'_, K, V, A>,
+    node_id: &NodeId,
+) -> &'a K {
+    match unsafe { ctx.node(node_id) } {
+        NodeRef::Branch(branch) => lowest_key(ctx, &branch.children[0].node_id),
+        NodeRef::Leaf(leaf) => &leaf.children[0].key,
+    }
+}
+
+/// Checks a node and everything beneath it against their checksums
+///
+/// # Safety
+/// The node must be reachable from the tree
+unsafe fn verify_node<K, V, A: BufferAllocator>(
+    ctx: &BNodeContext<'_, K, V, A>,
+    node_id: &NodeId,
+) -> Result<(), CorruptNode> {
+    // Checked before the node is read, since its length may be what was damaged
+    ctx.verify_node(node_id)?;
+    if let NodeRef::Branch(branch) = ctx.node(node_id) {
+        for child in branch.children.iter() {
+            verify_node(ctx, &child.node_id)?;
+        }
+    }
+    Ok(())
+}
+
+/// The index of the child that `key` belongs in
+///
+/// The first interval of a branch isn't used for routing, so this is the number of the other
+/// intervals that are less than or equal to `key`.
+fn find_idx_from_interval<K>(
+    entries: &[BranchEntry<K>],
+    key: &K,
+    cmp: &impl Comparator<K>,
+) -> usize {
+    let Some(entries) = entries.get(1..) else {
+        return 0;
+    };
+    let (mut low, mut high) = (0, entries.len());
+    while low < high {
+        let halfway = low + (high - low) / 2;
+        match cmp.compare(key, &entries[halfway].interval) {
+            std::cmp::Ordering::Less => high = halfway,
+            // Keys equal to an interval belong to the child after it
+            std::cmp::Ordering::Equal => return halfway + 1,
+            std::cmp::Ordering::Greater => low = halfway + 1,
         }
     }
+    low
 }
@@ -0,0 +1,129 @@
+use std::cmp::Ordering;
+
+use crate::tree::Comparator;
+
+use super::context::{stored_bytes, Le64};
+use super::{get, get_mut, BNodeContext, BTree, LoadError, MemTreeError};
+
+/// A buffer-backed tree keyed by byte strings of any length
+///
+/// Nodes can only hold fixed-size entries, so each key's bytes are stored separately in the buffer
+/// behind a length prefix, and the entries hold their offsets. Separators in branches share the
+/// bytes of the keys they were copied from, which is why keys can't be removed.
+pub struct BytesMemTree<'a, V> {
+    tree: BTree<'a, StoredKey, V>,
+}
+
+/// The offset of a key's bytes in the buffer
+#[derive(Clone, Debug)]
+#[repr(transparent)]
+struct StoredKey(Le64);
+
+impl StoredKey {
+    /// Stands in for a key being looked up, which isn't stored in the buffer. Offset 0 is the
+    /// header, so no stored key is ever there.
+    const QUERY: StoredKey = StoredKey(Le64::new(0));
+}
+
+/// Orders keys by their bytes, reading them out of the buffer
+struct ByteOrder<'q> {
+    base: *const u8,
+    query: &'q [u8],
+}
+
+impl ByteOrder<'_> {
+    fn bytes(&self, key: &StoredKey) -> &[u8] {
+        if key.0 == StoredKey::QUERY.0 {
+            self.query
+        } else {
+            unsafe { stored_bytes(self.base, key.0.get()) }
+        }
+    }
+}
+
+impl Comparator<StoredKey> for ByteOrder<'_> {
+    fn compare(&self, a: &StoredKey, b: &StoredKey) -> Ordering {
+        self.bytes(a).cmp(self.bytes(b))
+    }
+}
+
+impl<V: Clone> BytesMemTree<'_, V> {
+    /// Creates an empty tree at the start of the buffer, overwriting anything already there
+    pub fn new(buffer: &mut [u8]) -> Self {
+        BytesMemTree {
+            tree: BTree::with_context(BNodeContext::new(buffer)),
+        }
+    }
+
+    /// Picks up a tree previously written to the buffer by [`BytesMemTree::new`]
+    pub fn load(buffer: &mut [u8]) -> Result<Self, LoadError> {
+        Ok(BytesMemTree {
+            tree: BTree::with_context(BNodeContext::load(buffer)?),
+        })
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&V> {
+        let ctx = &self.tree.ctx;
+        get(ctx, ctx.root(), &StoredKey::QUERY, &self.order(key))
+            .unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Inserts a key-value pair, returning the value previously stored under the key
+    ///
+    /// # Panics
+    /// If the buffer runs out of space; see [`BytesMemTree::try_insert`] for a fallible version
+    pub fn insert(&mut self, key: &[u8], value: V) -> Option<V> {
+        self.try_insert(key, value)
+            .unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// Inserts a key-value pair, returning the value previously stored under the key
+    ///
+    /// The key's bytes are only copied into the buffer if it isn't there already. If the buffer
+    /// doesn't have room for them or for the nodes the insert needs, the tree is left as it was
+    /// and the value is dropped.
+    pub fn try_insert(&mut self, key: &[u8], value: V) -> Result<Option<V>, MemTreeError> {
+        self.tree.ctx.invalidate_checksum();
+        let ctx = &self.tree.ctx;
+        if let Some(previous) = get_mut(ctx, ctx.root(), &StoredKey::QUERY, &self.order(key)) {
+            return Ok(Some(std::mem::replace(previous, value)));
+        }
+
+        let offset = ctx.try_alloc_bytes(key)?;
+        let order = self.order(&[]);
+        match self
+            .tree
+            .try_insert_by(StoredKey(Le64::new(offset)), value, &order)
+        {
+            Ok(previous) => Ok(previous),
+            Err(err) => {
+                unsafe { self.tree.ctx.free_stored_bytes(offset) };
+                Err(err)
+            }
+        }
+    }
+
+    /// Iterates over every entry in ascending key order
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], &V)> {
+        let base = self.tree.ctx.base();
+        self.tree
+            .iter()
+            .map(move |(key, value)| (unsafe { stored_bytes(base, key.0.get()) }, value))
+    }
+
+    /// See [`BTree::checkpoint`]
+    pub fn checkpoint(&mut self) {
+        self.tree.ctx.checkpoint();
+    }
+
+    fn order<'q>(&self, query: &'q [u8]) -> ByteOrder<'q> {
+        ByteOrder {
+            base: self.tree.ctx.base(),
+            query,
+        }
+    }
+}
@@ -1,22 +1,30 @@
 use std::{
-    alloc::{GlobalAlloc, Layout},
+    alloc::Layout,
+    cell::{Cell, RefCell},
+    fmt,
     marker::PhantomData,
     mem::MaybeUninit,
+    num::NonZeroU64,
     ptr,
 };
 
-use linked_list_allocator::LockedHeap;
+use super::FreeList;
 
 // TODO: branch and leaf children are always MaybeUninit, and it's just part of the safety contract
 // to initialize them?
 
-// TODO: track capacity in the node header to allow nodes to grow and shrink a bit
-
 #[repr(C)]
 #[derive(Debug)]
 struct NodeHeader {
     tag: NodeTag,
-    len: usize,
+    /// A crc32 of the rest of the node, kept in a cell so it can be brought up to date while the
+    /// node is borrowed
+    checksum: Cell<Le32>,
+    len: Le64,
+    /// How many entries the node has room for, which is what it was allocated and is freed with
+    cap: Le64,
+    /// How many entries are in the leaves beneath a branch. Leaves leave this at 0.
+    subtree_len: Le64,
 }
 
 #[repr(C)]
@@ -26,6 +34,17 @@ pub struct Branch<K> {
     pub children: [BranchEntry<K>],
 }
 
+impl<K> Branch<K> {
+    /// How many entries are in the leaves beneath the branch
+    pub fn subtree_len(&self) -> usize {
+        self.header.subtree_len.get()
+    }
+
+    pub(super) fn set_subtree_len(&mut self, len: usize) {
+        self.header.subtree_len = Le64::new(len);
+    }
+}
+
 #[repr(C)]
 #[derive(Debug)]
 pub struct BranchMaybeUninit<K> {
@@ -33,21 +52,20 @@ pub struct BranchMaybeUninit<K> {
     pub children: [MaybeUninit<BranchEntry<K>>],
 }
 
+impl<K> BranchMaybeUninit<K> {
+    /// # Safety
+    /// Every entry must be initialized
+    pub unsafe fn assume_init_mut(&mut self) -> &mut Branch<K> {
+        &mut *(self as *mut BranchMaybeUninit<K> as *mut Branch<K>)
+    }
+}
+
 #[derive(Debug)]
 pub struct BranchEntry<K> {
     pub interval: K,
     pub node_id: NodeId,
 }
 
-impl<K: Clone> Clone for BranchEntry<K> {
-    fn clone(&self) -> Self {
-        BranchEntry {
-            interval: self.interval.clone(),
-            node_id: NodeId(self.node_id.0),
-        }
-    }
-}
-
 #[repr(C)]
 #[derive(Debug)]
 pub struct Leaf<K, V> {
@@ -55,28 +73,6 @@ pub struct Leaf<K, V> {
     pub children: [LeafEntry<K, V>],
 }
 
-impl<K, V> Leaf<K, V> {
-    pub fn remove(&mut self, idx: usize) -> LeafEntry<K, V> {
-        assert!(idx < self.children.len());
-
-        // infallible
-        let ret;
-        unsafe {
-            // the place we are taking from.
-            let ptr = self.children.as_mut_ptr().add(idx);
-            // copy it out, unsafely having a copy of the value on
-            // the stack and in the vector at the same time.
-            ret = ptr::read(ptr);
-
-            // Shift everything down to fill in that spot.
-            ptr::copy(ptr.add(1), ptr, self.header.len - idx - 1);
-        }
-        self.header.len -= 1;
-
-        ret
-    }
-}
-
 #[repr(C)]
 #[derive(Debug)]
 pub struct LeafMaybeUninit<K, V> {
@@ -91,23 +87,283 @@ pub struct LeafEntry<K, V> {
     pub value: V,
 }
 
+/// Where a node starts in the buffer, stored in little-endian byte order like `Le64`
+///
+/// The header takes up the start of the buffer, so no node is ever at offset 0, and an
+/// `Option<NodeId>` takes up no more space than a `NodeId`.
 #[derive(Debug)]
 #[repr(transparent)]
-pub struct NodeId(usize);
+pub struct NodeId(NonZeroU64);
+
+impl NodeId {
+    fn new(offset: usize) -> Self {
+        // Swapping the byte order doesn't change whether a number is zero
+        NodeId(NonZeroU64::new((offset as u64).to_le()).expect("no node is at offset 0"))
+    }
 
-pub struct BNodeContext<'a, K, V> {
-    allocator: &'a LockedHeap,
+    fn offset(&self) -> usize {
+        u64::from_le(self.0.get()) as usize
+    }
+
+    /// Makes a second handle to the same node
+    ///
+    /// # Safety
+    /// At most one of the handles may be freed
+    pub unsafe fn alias(&self) -> NodeId {
+        NodeId(self.0)
+    }
+}
+
+/// A `u64` stored in little-endian byte order, so that it reads the same on any host
+///
+/// Every integer the crate keeps in the buffer is stored this way: the header, node headers and
+/// ids, and [`FreeList`]'s holes. Keys and values are stored however their types lay themselves
+/// out, and so is a custom allocator's state, so a buffer can only move to a host with the other
+/// byte order if those lay out the same on both, like byte arrays do. There's no mode for reading
+/// a buffer with its integers byte-swapped; `load` rejects it by its version.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(transparent)]
+pub(super) struct Le64(u64);
+
+impl Le64 {
+    pub(super) const fn new(value: usize) -> Self {
+        Le64((value as u64).to_le())
+    }
+
+    pub(super) fn get(self) -> usize {
+        u64::from_le(self.0) as usize
+    }
+}
+
+/// A `u32` stored in little-endian byte order, like [`Le64`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(transparent)]
+struct Le32(u32);
+
+impl Le32 {
+    fn new(value: u32) -> Self {
+        Le32(value.to_le())
+    }
+
+    fn get(self) -> u32 {
+        u32::from_le(self.0)
+    }
+}
+
+/// The buffer backing a tree doesn't have a large enough gap left for a new node
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutOfSpace {
+    /// The size in bytes of the allocation that couldn't be made
+    pub bytes: usize,
+}
+
+impl fmt::Display for OutOfSpace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "ran out of space in the buffer while allocating {} bytes",
+            self.bytes
+        )
+    }
+}
+
+impl std::error::Error for OutOfSpace {}
+
+/// A buffer handed to `try_new` can't fit the header and an empty root node
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TooSmall {
+    /// The smallest buffer in bytes that a tree can be created in
+    pub required: usize,
+}
+
+impl fmt::Display for TooSmall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "buffer is too small to hold a tree, which needs at least {} bytes",
+            self.required
+        )
+    }
+}
+
+impl std::error::Error for TooSmall {}
+
+/// A node's contents don't match its checksum, so it was only partly written or has been damaged
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CorruptNode {
+    /// Where the node starts in the buffer
+    pub offset: usize,
+}
+
+impl fmt::Display for CorruptNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "node at offset {} doesn't match its checksum",
+            self.offset
+        )
+    }
+}
+
+impl std::error::Error for CorruptNode {}
+
+/// Something that stopped the tree from reading or changing its buffer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemTreeError {
+    /// The buffer doesn't have a large enough gap left for a new node
+    OutOfSpace(OutOfSpace),
+    /// A node's header holds a tag that's neither a branch's nor a leaf's, so the buffer has been
+    /// damaged
+    BadTag { offset: usize, tag: u8 },
+    /// A node lies partly or wholly outside the buffer
+    OffsetOverflow { offset: usize },
+    /// A node doesn't match its checksum; only checked with the `verify-nodes` feature
+    CorruptNode(CorruptNode),
+}
+
+impl From<OutOfSpace> for MemTreeError {
+    fn from(err: OutOfSpace) -> Self {
+        MemTreeError::OutOfSpace(err)
+    }
+}
+
+impl From<CorruptNode> for MemTreeError {
+    fn from(err: CorruptNode) -> Self {
+        MemTreeError::CorruptNode(err)
+    }
+}
+
+impl fmt::Display for MemTreeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MemTreeError::OutOfSpace(err) => err.fmt(f),
+            MemTreeError::BadTag { offset, tag } => {
+                write!(f, "node at offset {offset} has unknown tag {tag:#04x}")
+            }
+            MemTreeError::OffsetOverflow { offset } => {
+                write!(f, "node at offset {offset} doesn't fit in the buffer")
+            }
+            MemTreeError::CorruptNode(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for MemTreeError {}
+
+/// Hands out space for nodes and stored keys within the part of the buffer after the header
+///
+/// The allocator is kept in the buffer's header and read back by `load`, which may find the buffer
+/// at a different address than it was created at, such as a file mapped back in. So its state must
+/// be plain data that doesn't point into the buffer: blocks are handed out as offsets from the
+/// start of the buffer, and the buffer's current address is passed in whenever the allocator needs
+/// to read or write it. A buffer has to be loaded with the same allocator it was created with. The
+/// default is [`FreeList`], which reuses freed space.
+///
+/// # Safety
+/// `alloc` must only return `None` or the offset of a block of at least the layout's size that
+/// lies within the region handed to `init`, is aligned to the layout's alignment at `base`, and
+/// doesn't overlap any other live block.
+pub unsafe trait BufferAllocator {
+    /// Makes an allocator over the `size` bytes starting `start` bytes into the buffer at `base`
+    ///
+    /// # Safety
+    /// The region must be valid for writes and only be used through the allocator
+    unsafe fn init(base: *mut u8, start: usize, size: usize) -> Self;
+
+    /// Allocates a block in the buffer at `base`, or returns `None` if there's no room for it
+    ///
+    /// # Safety
+    /// The layout must have a non-zero size, and `base` must be where the buffer is now
+    unsafe fn alloc(&self, base: *mut u8, layout: Layout) -> Option<usize>;
+
+    /// Frees a block, which an allocator is free to never reuse
+    ///
+    /// # Safety
+    /// The block must have come from `alloc` with the same layout and not been freed already, and
+    /// `base` must be where the buffer is now
+    unsafe fn dealloc(&self, base: *mut u8, offset: usize, layout: Layout);
+
+    /// How many bytes are left to allocate, whether or not they're contiguous
+    fn free(&self) -> usize;
+}
+
+pub struct BNodeContext<'a, K, V, A: BufferAllocator = FreeList> {
+    allocator: &'a A,
     buffer: *mut u8,
+    len: usize,
+    /// Offsets of the nodes written to since their checksums were last computed
+    unsealed: RefCell<Vec<usize>>,
+    #[cfg(test)]
+    allocations: std::cell::Cell<usize>,
     _k: PhantomData<K>,
     _v: PhantomData<V>,
 }
 
+/// Identifies a buffer as holding a tree written by this crate
+const MAGIC: [u8; 8] = *b"CATALOG\0";
+/// Bumped whenever the layout of the header or of nodes changes
+const FORMAT_VERSION: u32 = 7;
+
 #[repr(C)]
-struct BNodeContextHeader {
-    allocator: LockedHeap,
-    root: NodeId,
+struct BNodeContextHeader<A> {
+    /// Comes first so it can be checked before trusting anything else in the buffer
+    magic: [u8; 8],
+    version: Le32,
+    /// Non-zero if `checksum` was computed by a checkpoint and nothing has changed since
+    checksummed: Le32,
+    /// A crc32 of the whole buffer, apart from the checksum fields themselves
+    checksum: Le32,
+    key_size: Le64,
+    value_size: Le64,
+    allocator: A,
+    /// Only `None` until the root is allocated while the tree is created
+    root: Option<NodeId>,
+}
+
+/// A header read without knowing the allocator, which comes after every field this can access, so
+/// they sit at the same offsets whatever it is
+type AnyHeader = BNodeContextHeader<()>;
+
+/// A buffer handed to `load` doesn't hold a tree that can be read back
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoadError {
+    /// The buffer is too small to even hold the header
+    TooSmall,
+    /// The buffer doesn't start with the magic bytes written by `new`
+    BadMagic,
+    /// The buffer was written by an incompatible version of the format
+    UnsupportedVersion(u32),
+    /// The buffer was written with keys or values of a different size, so holds other types
+    LayoutMismatch { key_size: usize, value_size: usize },
+    /// The buffer was checkpointed, but its contents no longer match the checksum
+    ChecksumMismatch,
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::TooSmall => write!(f, "buffer is too small to hold a tree"),
+            LoadError::BadMagic => write!(f, "buffer doesn't hold a tree"),
+            LoadError::UnsupportedVersion(version) => {
+                write!(
+                    f,
+                    "tree was written with unsupported format version {version}"
+                )
+            }
+            LoadError::LayoutMismatch {
+                key_size,
+                value_size,
+            } => write!(
+                f,
+                "tree was written with {key_size} byte keys and {value_size} byte values"
+            ),
+            LoadError::ChecksumMismatch => write!(f, "buffer doesn't match its checksum"),
+        }
+    }
 }
 
+impl std::error::Error for LoadError {}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 #[repr(u8)]
 enum NodeTag {
@@ -129,19 +385,61 @@ pub enum NodeMut<'a, K, V> {
 
 impl<K, V> BNodeContext<'_, K, V> {
     pub fn new(buffer: &mut [u8]) -> Self {
-        let heap = LockedHeap::empty();
-        let heap_size = buffer.len() - std::mem::size_of::<BNodeContextHeader>();
-        let avaialable_heap_start =
-            buffer[std::mem::size_of::<BNodeContextHeader>()..].as_mut_ptr();
-        unsafe {
-            heap.lock().init(avaialable_heap_start, heap_size);
+        Self::with_allocator(buffer)
+    }
+
+    pub fn load(buffer: &mut [u8]) -> Result<Self, LoadError> {
+        Self::load_with_allocator(buffer)
+    }
+}
+
+impl<K, V, A: BufferAllocator> BNodeContext<'_, K, V, A> {
+    /// Like [`BNodeContext::new`], but handing out the buffer's space with `A`
+    pub fn with_allocator(buffer: &mut [u8]) -> Self {
+        match Self::try_with_allocator(buffer) {
+            Ok(ctx) => ctx,
+            Err(err) => panic!("{err}"),
+        }
+    }
+
+    /// The smallest buffer that fits the header and an empty root node
+    pub fn min_buffer_size() -> usize {
+        let root = node_layout::<BranchEntry<K>>(capacity_for(0));
+        // The default allocator needs room for a hole's size and link in any gap it leaves, so it
+        // refuses to leave one that's smaller. Leaving room for one after the root means that any
+        // larger buffer fits the root too.
+        let free_list_node = 2 * std::mem::size_of::<u64>();
+        // An over-aligned root can also need a gap before it
+        let root_padding = match root.align() > std::mem::align_of::<u64>() {
+            true => free_list_node + root.align(),
+            false => 0,
+        };
+        std::mem::size_of::<BNodeContextHeader<A>>() + root.size() + free_list_node + root_padding
+    }
+
+    /// Like [`BNodeContext::with_allocator`], but reports a buffer that's too small instead of
+    /// panicking
+    pub fn try_with_allocator(buffer: &mut [u8]) -> Result<Self, TooSmall> {
+        let required = Self::min_buffer_size();
+        if buffer.len() < required {
+            return Err(TooSmall { required });
         }
 
+        let heap_start = std::mem::size_of::<BNodeContextHeader<A>>();
+        let heap_size = buffer.len() - heap_start;
+        let heap = unsafe { A::init(buffer.as_mut_ptr(), heap_start, heap_size) };
+
         let header = BNodeContextHeader {
+            magic: MAGIC,
+            version: Le32::new(FORMAT_VERSION),
+            checksummed: Le32::new(0),
+            checksum: Le32::new(0),
+            key_size: Le64::new(std::mem::size_of::<K>()),
+            value_size: Le64::new(std::mem::size_of::<V>()),
             allocator: heap,
-            root: NodeId(0),
+            root: None,
         };
-        let header_ptr = buffer.as_mut_ptr() as *mut BNodeContextHeader;
+        let header_ptr = buffer.as_mut_ptr() as *mut BNodeContextHeader<A>;
         let allocator = unsafe {
             header_ptr.write(header);
             &header_ptr.as_ref().unwrap().allocator
@@ -150,176 +448,621 @@ impl<K, V> BNodeContext<'_, K, V> {
         let ctx = BNodeContext {
             allocator,
             buffer: buffer.as_mut_ptr(),
+            len: buffer.len(),
+            unsealed: RefCell::new(Vec::new()),
+            #[cfg(test)]
+            allocations: std::cell::Cell::new(0),
             _k: PhantomData,
             _v: PhantomData,
         };
 
         // allocate root node
         unsafe {
-            let (root, _) = ctx.alloc_branch(0);
-            let header = (ctx.buffer as *mut BNodeContextHeader).as_mut().unwrap();
-            header.root = root;
+            let (root, _) = ctx.try_alloc_branch(0).map_err(|_| TooSmall { required })?;
+            let header = (ctx.buffer as *mut BNodeContextHeader<A>).as_mut().unwrap();
+            header.root = Some(root);
         }
 
-        ctx
+        Ok(ctx)
     }
 
-    pub fn load(buffer: &mut [u8]) -> Self {
-        let buffer = buffer.as_mut_ptr();
+    /// Like [`BNodeContext::load`], for a buffer created with [`BNodeContext::with_allocator`]
+    pub fn load_with_allocator(buffer: &mut [u8]) -> Result<Self, LoadError> {
+        unsafe { Self::load_shared(buffer) }
+    }
 
-        let allocator = unsafe {
-            &(buffer as *const BNodeContextHeader)
+    /// Like [`BNodeContext::load_with_allocator`], but over a buffer that's only borrowed to be read
+    ///
+    /// # Safety
+    /// Nothing may be written through the context: no nodes may be allocated, freed or handed out
+    /// mutably, and the checksum and root must be left alone
+    pub unsafe fn load_shared(buffer: &[u8]) -> Result<Self, LoadError> {
+        if buffer.len() < std::mem::size_of::<BNodeContextHeader<A>>() {
+            return Err(LoadError::TooSmall);
+        }
+        let header = unsafe {
+            (buffer.as_ptr() as *const BNodeContextHeader<A>)
                 .as_ref()
                 .unwrap()
-                .allocator
         };
+        if header.magic != MAGIC {
+            return Err(LoadError::BadMagic);
+        }
+        if header.version.get() != FORMAT_VERSION {
+            return Err(LoadError::UnsupportedVersion(header.version.get()));
+        }
+        if header.key_size.get() != std::mem::size_of::<K>()
+            || header.value_size.get() != std::mem::size_of::<V>()
+        {
+            return Err(LoadError::LayoutMismatch {
+                key_size: header.key_size.get(),
+                value_size: header.value_size.get(),
+            });
+        }
+        if header.checksummed.get() != 0 && header.checksum.get() != checksum(buffer) {
+            return Err(LoadError::ChecksumMismatch);
+        }
 
-        BNodeContext {
-            allocator,
-            buffer,
+        Ok(BNodeContext {
+            allocator: &header.allocator,
+            buffer: buffer.as_ptr() as *mut u8,
+            len: buffer.len(),
+            unsealed: RefCell::new(Vec::new()),
+            #[cfg(test)]
+            allocations: std::cell::Cell::new(0),
             _k: PhantomData,
             _v: PhantomData,
-        }
+        })
     }
 
     pub fn root(&self) -> &NodeId {
-        dbg!(unsafe {
-            &(self.buffer as *const BNodeContextHeader)
+        unsafe {
+            (self.buffer as *const BNodeContextHeader<A>)
                 .as_ref()
                 .unwrap()
                 .root
-        })
+                .as_ref()
+                .expect("the root is allocated when the tree is created")
+        }
+    }
+
+    /// Whether the id itself is stored in the buffer, as the root's and every branch's child ids are
+    pub fn holds(&self, node_id: &NodeId) -> bool {
+        let start = self.buffer as usize;
+        (start..start + self.len).contains(&(node_id as *const NodeId as usize))
+    }
+
+    /// Swaps in a new root and frees the old one, whose entries must already have been moved out
+    pub fn replace_root(&mut self, root: NodeId) {
+        let old_root = self.set_root(root);
+        unsafe {
+            self.free(old_root);
+        }
+    }
+
+    /// Records a checksum of the buffer in its header, which `load` checks until the next change
+    pub fn checkpoint(&self) {
+        self.seal();
+        let checksum = checksum(unsafe { std::slice::from_raw_parts(self.buffer, self.len) });
+        let header = self.header_mut();
+        header.checksum = Le32::new(checksum);
+        header.checksummed = Le32::new(1);
+    }
+
+    /// Marks the checksum as stale, which must happen before the buffer is changed
+    ///
+    /// Whatever changed the buffer last is done with it by now, so the nodes it wrote to are
+    /// sealed first.
+    pub fn invalidate_checksum(&self) {
+        self.seal();
+        self.header_mut().checksummed = Le32::new(0);
+    }
+
+    /// Brings the checksums of the nodes written to since the last seal up to date
+    ///
+    /// Nodes are written through references handed out by the context, so it can't tell when a
+    /// write is done. This must be called once no more are in use, and before the buffer is
+    /// persisted.
+    pub fn seal(&self) {
+        let mut unsealed = self.unsealed.borrow_mut();
+        unsealed.sort_unstable();
+        unsealed.dedup();
+        for &offset in unsealed.iter() {
+            unsafe {
+                let header = &*(self.buffer.add(offset) as *const NodeHeader);
+                let checksum = self.node_checksum(offset).expect("nodes are written whole");
+                header.checksum.set(Le32::new(checksum));
+            }
+        }
+        unsealed.clear();
+    }
+
+    /// Checks a node against its checksum, unless it's been written to since the last seal
+    ///
+    /// # Safety
+    /// node_id must have been generated by this context and not yet freed
+    pub unsafe fn verify_node(&self, node_id: &NodeId) -> Result<(), CorruptNode> {
+        let offset = node_id.offset();
+        if self.unsealed.borrow().contains(&offset) {
+            return Ok(());
+        }
+        let header = &*self.header(node_id);
+        match self.node_checksum(offset) {
+            Some(checksum) if checksum == header.checksum.get().get() => Ok(()),
+            _ => Err(CorruptNode { offset }),
+        }
+    }
+
+    /// Computes the checksum of the node at the offset, or `None` if its header is too damaged
+    /// to tell where the node ends
+    ///
+    /// # Safety
+    /// There must be a node at the offset
+    unsafe fn node_checksum(&self, offset: usize) -> Option<u32> {
+        if offset.checked_add(std::mem::size_of::<NodeHeader>())? > self.len {
+            return None;
+        }
+        let ptr = self.buffer.add(offset);
+        // Read field by field, and the tag as a byte, since a damaged tag may not be a valid
+        // `NodeTag`
+        let header = ptr as *const NodeHeader;
+        let tag = ptr.read();
+        let (len, cap) = ((*header).len.get(), (*header).cap.get());
+        // No node has room for more entries than the buffer has bytes, and checking that first
+        // keeps the layout from overflowing
+        if len > cap || cap > self.len {
+            return None;
+        }
+        let (layout, entries) = if tag == NodeTag::Branch as u8 {
+            let start = children_offset::<BranchEntry<K>>();
+            let size = len.checked_mul(std::mem::size_of::<BranchEntry<K>>())?;
+            (self.branch_layout(cap), start..start + size)
+        } else if tag == NodeTag::Leaf as u8 {
+            let start = children_offset::<LeafEntry<K, V>>();
+            let size = len.checked_mul(std::mem::size_of::<LeafEntry<K, V>>())?;
+            (self.leaf_layout(cap), start..start + size)
+        } else {
+            return None;
+        };
+        if offset.checked_add(layout.size())? > self.len {
+            return None;
+        }
+
+        let node = std::slice::from_raw_parts(ptr, layout.size());
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&[tag]);
+        hasher.update(
+            &node[std::mem::offset_of!(NodeHeader, len)..std::mem::size_of::<NodeHeader>()],
+        );
+        hasher.update(&node[entries]);
+        Some(hasher.finalize())
+    }
+
+    fn mark_unsealed(&self, node_id: &NodeId) {
+        self.unsealed.borrow_mut().push(node_id.offset());
     }
 
-    pub fn replace_root(&mut self, mut root: NodeId) {
+    #[allow(clippy::mut_from_ref)]
+    fn header_mut(&self) -> &mut BNodeContextHeader<A> {
         unsafe {
-            let root_ref = &mut (self.buffer as *mut BNodeContextHeader)
+            (self.buffer as *mut BNodeContextHeader<A>)
                 .as_mut()
                 .unwrap()
-                .root;
-            std::mem::swap(root_ref, &mut root);
-            self.free(root);
         }
     }
 
+    /// How many bytes of the buffer aren't taken up by nodes, whether or not they're contiguous
+    pub fn free_bytes(&self) -> usize {
+        self.allocator.free()
+    }
+
+    /// Swaps in a new root node, handing back the old one without freeing it
+    pub fn set_root(&mut self, root: NodeId) -> NodeId {
+        self.header_mut()
+            .root
+            .replace(root)
+            .expect("the root is allocated when the tree is created")
+    }
+
     /// # Safety
     /// You must initialize all data in the BranchMaybeUninit immediately before calling any other
     /// methods on BNodeContext
+    #[allow(clippy::mut_from_ref)]
     pub unsafe fn alloc_branch(&self, len: usize) -> (NodeId, &mut BranchMaybeUninit<K>) {
-        let header = NodeHeader {
-            tag: NodeTag::Branch,
-            len,
-        };
-        let layout = self.branch_layout(len);
-        unsafe {
-            let ptr = self.allocator.alloc(layout);
-            assert!(!ptr.is_null());
-            let header_ptr = ptr as *mut NodeHeader;
-            header_ptr.write(header);
-
-            let node_id = NodeId(
-                ptr.offset_from(self.buffer)
-                    .try_into()
-                    .expect("allocations must be within buffer"),
-            );
+        self.try_alloc_branch(len)
+            .unwrap_or_else(|err| panic!("{err}"))
+    }
 
-            let ptr_slice = ptr::slice_from_raw_parts(ptr, layout.size());
-            let reference = (ptr_slice as *mut BranchMaybeUninit<K>).as_mut().unwrap();
+    /// Like [`BNodeContext::alloc_branch`], but reports a full buffer instead of panicking
+    ///
+    /// # Safety
+    /// See [`BNodeContext::alloc_branch`]
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn try_alloc_branch(
+        &self,
+        len: usize,
+    ) -> Result<(NodeId, &mut BranchMaybeUninit<K>), MemTreeError> {
+        let cap = capacity_for(len);
+        let layout = self.branch_layout(cap);
+        let (node_id, ptr) = self.alloc(NodeTag::Branch, len, cap, layout)?;
+        let ptr_slice = ptr::slice_from_raw_parts(ptr, len);
+        let reference = (ptr_slice as *mut BranchMaybeUninit<K>).as_mut().unwrap();
+        debug_assert_eq!(
+            reference.children.as_ptr() as *const u8,
+            ptr.add(children_offset::<BranchEntry<K>>())
+        );
 
-            (node_id, reference)
-        }
+        Ok((node_id, reference))
     }
 
+    /// Allocates a leaf with room for `len` entries, or reports a full buffer
+    ///
     /// # Safety
     /// You must initialize all data in the LeafMaybeUninit immediately before calling any other
     /// methods on BNodeContext
-    pub unsafe fn alloc_leaf(&self, len: usize) -> (NodeId, &mut LeafMaybeUninit<K, V>) {
-        let header = NodeHeader {
-            tag: NodeTag::Leaf,
-            len,
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn try_alloc_leaf(
+        &self,
+        len: usize,
+    ) -> Result<(NodeId, &mut LeafMaybeUninit<K, V>), MemTreeError> {
+        let cap = capacity_for(len);
+        let layout = self.leaf_layout(cap);
+        let (node_id, ptr) = self.alloc(NodeTag::Leaf, len, cap, layout)?;
+        let ptr_slice = ptr::slice_from_raw_parts(ptr, len);
+        let reference = (ptr_slice as *mut LeafMaybeUninit<K, V>).as_mut().unwrap();
+        debug_assert_eq!(
+            reference.children.as_ptr() as *const u8,
+            ptr.add(children_offset::<LeafEntry<K, V>>())
+        );
+
+        Ok((node_id, reference))
+    }
+
+    /// Makes room for one more entry at the end of a branch, if it was allocated with space to
+    /// spare, returning the branch with the new slot uninitialized
+    ///
+    /// # Safety
+    /// You must initialize the last entry immediately before calling any other methods on
+    /// BNodeContext, and must not use any other reference to the branch after this
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn grow_branch(&self, node_id: &NodeId) -> Option<&mut BranchMaybeUninit<K>> {
+        let len = (*self.header(node_id)).len.get();
+        self.resize_branch(node_id, len + 1)
+    }
+
+    /// Makes room for one more entry at the end of a leaf, if it was allocated with space to
+    /// spare, returning the leaf with the new slot uninitialized
+    ///
+    /// # Safety
+    /// See [`BNodeContext::grow_branch`]
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn grow_leaf(&self, node_id: &NodeId) -> Option<&mut LeafMaybeUninit<K, V>> {
+        let len = (*self.header(node_id)).len.get();
+        self.resize_leaf(node_id, len + 1)
+    }
+
+    /// Sets how many entries a branch holds, if it was allocated with room for that many,
+    /// returning the branch with any new slots uninitialized
+    ///
+    /// # Safety
+    /// Entries past the new length must already have been moved out, and new slots must be
+    /// initialized before calling any other methods on BNodeContext. No other reference to the
+    /// branch may be used after this.
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn resize_branch(
+        &self,
+        node_id: &NodeId,
+        len: usize,
+    ) -> Option<&mut BranchMaybeUninit<K>> {
+        let header_ptr = self.resize(node_id, NodeTag::Branch, len)?;
+        let ptr_slice = ptr::slice_from_raw_parts(header_ptr as *mut u8, len);
+        (ptr_slice as *mut BranchMaybeUninit<K>).as_mut()
+    }
+
+    /// Sets how many entries a leaf holds, if it was allocated with room for that many, returning
+    /// the leaf with any new slots uninitialized
+    ///
+    /// # Safety
+    /// See [`BNodeContext::resize_branch`]
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn resize_leaf(
+        &self,
+        node_id: &NodeId,
+        len: usize,
+    ) -> Option<&mut LeafMaybeUninit<K, V>> {
+        let header_ptr = self.resize(node_id, NodeTag::Leaf, len)?;
+        let ptr_slice = ptr::slice_from_raw_parts(header_ptr as *mut u8, len);
+        (ptr_slice as *mut LeafMaybeUninit<K, V>).as_mut()
+    }
+
+    unsafe fn resize(&self, node_id: &NodeId, tag: NodeTag, len: usize) -> Option<*mut NodeHeader> {
+        let header_ptr = self.header(node_id);
+        let header = &mut *header_ptr;
+        debug_assert_eq!(header.tag, tag);
+        if len > header.cap.get() {
+            return None;
+        }
+        header.len = Le64::new(len);
+        self.mark_unsealed(node_id);
+        Some(header_ptr)
+    }
+
+    unsafe fn alloc(
+        &self,
+        tag: NodeTag,
+        len: usize,
+        cap: usize,
+        layout: Layout,
+    ) -> Result<(NodeId, *mut u8), MemTreeError> {
+        let Some(offset) = self.allocator.alloc(self.buffer, layout) else {
+            return Err(OutOfSpace {
+                bytes: layout.size(),
+            }
+            .into());
         };
-        let layout = self.leaf_layout(len);
-        unsafe {
-            let ptr = self.allocator.alloc(layout);
-            assert!(!ptr.is_null());
-            let header_ptr = ptr as *mut NodeHeader;
-            header_ptr.write(header);
+        // The allocator's bookkeeping lives in the buffer, so a damaged buffer can lead it astray.
+        // Handing the block back would only lead it further, so the space is lost instead
+        if offset
+            .checked_add(layout.size())
+            .is_none_or(|end| end > self.len)
+        {
+            return Err(MemTreeError::OffsetOverflow { offset });
+        }
+        let ptr = self.buffer.add(offset);
+        #[cfg(test)]
+        self.allocations.set(self.allocations.get() + 1);
+        let header_ptr = ptr as *mut NodeHeader;
+        header_ptr.write(NodeHeader {
+            tag,
+            checksum: Cell::new(Le32::new(0)),
+            len: Le64::new(len),
+            cap: Le64::new(cap),
+            subtree_len: Le64::new(0),
+        });
 
-            let node_id = NodeId(
-                ptr.offset_from(self.buffer)
-                    .try_into()
-                    .expect("allocations must be within buffer"),
-            );
+        let node_id = NodeId::new(offset);
+        self.mark_unsealed(&node_id);
 
-            let ptr_slice = ptr::slice_from_raw_parts(ptr, layout.size());
-            let reference = (ptr_slice as *mut LeafMaybeUninit<K, V>).as_mut().unwrap();
+        Ok((node_id, ptr))
+    }
 
-            (node_id, reference)
+    /// Copies bytes into the buffer behind a length prefix, returning the offset they're stored at
+    pub fn try_alloc_bytes(&self, bytes: &[u8]) -> Result<usize, MemTreeError> {
+        let layout = bytes_layout(bytes.len());
+        unsafe {
+            let Some(offset) = self.allocator.alloc(self.buffer, layout) else {
+                return Err(OutOfSpace {
+                    bytes: layout.size(),
+                }
+                .into());
+            };
+            let ptr = self.buffer.add(offset);
+            (ptr as *mut Le64).write(Le64::new(bytes.len()));
+            ptr.add(std::mem::size_of::<Le64>())
+                .copy_from_nonoverlapping(bytes.as_ptr(), bytes.len());
+            Ok(offset)
         }
     }
 
+    /// Where the buffer starts, for reading bytes back with [`stored_bytes`] without borrowing the
+    /// context
+    pub fn base(&self) -> *const u8 {
+        self.buffer
+    }
+
+    /// Deallocates bytes stored by [`BNodeContext::try_alloc_bytes`]
+    ///
+    /// # Safety
+    /// The offset must have come from `try_alloc_bytes` and not been freed already
+    pub unsafe fn free_stored_bytes(&self, offset: usize) {
+        let len = stored_bytes(self.buffer, offset).len();
+        self.allocator
+            .dealloc(self.buffer, offset, bytes_layout(len));
+    }
+
+    /// Deallocates a node without dropping its entries, which must already have been moved out
+    ///
     /// # Safety
     /// You must not free the same node_id twice
     pub unsafe fn free(&self, node_id: NodeId) {
-        let ptr = self.buffer.add(node_id.0);
+        let offset = node_id.offset();
+        self.unsealed
+            .borrow_mut()
+            .retain(|&unsealed| unsealed != offset);
+        let ptr = self.buffer.add(offset);
         let header_ptr = ptr as *const NodeHeader;
         let header = header_ptr.read();
         let layout = match header.tag {
-            NodeTag::Branch => self.branch_layout(header.len),
-            NodeTag::Leaf => self.leaf_layout(header.len),
+            NodeTag::Branch => self.branch_layout(header.cap.get()),
+            NodeTag::Leaf => self.leaf_layout(header.cap.get()),
         };
-        self.allocator.dealloc(ptr, layout);
+        self.allocator.dealloc(self.buffer, offset, layout);
+    }
+
+    /// How many nodes have been allocated since the context was made
+    #[cfg(test)]
+    pub fn allocations(&self) -> usize {
+        self.allocations.get()
+    }
+
+    /// How many entries a node was allocated with room for
+    ///
+    /// # Safety
+    /// node_id must have been generated by this context and not yet freed
+    pub unsafe fn capacity(&self, node_id: &NodeId) -> usize {
+        (*self.header(node_id)).cap.get()
+    }
+
+    /// How many bytes a branch allocated for `len` entries takes up
+    pub fn branch_size(&self, len: usize) -> usize {
+        self.branch_layout(capacity_for(len)).size()
+    }
+
+    /// How many bytes a leaf allocated for `len` entries takes up
+    pub fn leaf_size(&self, len: usize) -> usize {
+        self.leaf_layout(capacity_for(len)).size()
     }
 
     fn branch_layout(&self, len: usize) -> Layout {
-        let size = std::mem::size_of::<NodeHeader>() + len * std::mem::size_of::<BranchEntry<K>>();
-        Layout::from_size_align(
-            size,
-            std::mem::align_of::<NodeHeader>().max(std::mem::align_of::<BranchEntry<K>>()),
-        )
-        .unwrap()
+        node_layout::<BranchEntry<K>>(len)
     }
 
     fn leaf_layout(&self, len: usize) -> Layout {
-        let size = std::mem::size_of::<NodeHeader>() + len * std::mem::size_of::<LeafEntry<K, V>>();
-        Layout::from_size_align(
-            size,
-            std::mem::align_of::<NodeHeader>().max(std::mem::align_of::<LeafEntry<K, V>>()),
-        )
-        .unwrap()
+        node_layout::<LeafEntry<K, V>>(len)
     }
 
     /// # Safety
     /// node_id must have been generated by this context and not yet freed
     unsafe fn header(&self, node_id: &NodeId) -> *mut NodeHeader {
-        self.buffer.add(node_id.0) as *mut NodeHeader
+        self.buffer.add(node_id.offset()) as *mut NodeHeader
     }
 
+    /// # Safety
+    /// node_id must have been generated by this context and not yet freed
+    ///
+    /// # Panics
+    /// If the node has been damaged; see [`BNodeContext::try_node`]
     pub unsafe fn node(&self, node_id: &NodeId) -> NodeRef<'_, K, V> {
-        let header_ptr = self.header(node_id);
-        let header = header_ptr.read();
-        match header.tag {
+        self.try_node(node_id).unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// Like [`BNodeContext::node`], but reports a damaged node instead of panicking
+    ///
+    /// The node's header is checked before anything is read through it, and with the
+    /// `verify-nodes` feature so is its checksum.
+    ///
+    /// # Safety
+    /// See [`BNodeContext::node`]
+    pub unsafe fn try_node(&self, node_id: &NodeId) -> Result<NodeRef<'_, K, V>, MemTreeError> {
+        let header_ptr = self.checked_header(node_id)?;
+        Ok(match (*header_ptr).tag {
             NodeTag::Branch => NodeRef::Branch(to_branch(header_ptr).as_ref().unwrap()),
             NodeTag::Leaf => NodeRef::Leaf(to_leaf(header_ptr).as_ref().unwrap()),
-        }
+        })
     }
 
+    /// # Safety
+    /// See [`BNodeContext::node`]
+    ///
+    /// # Panics
+    /// See [`BNodeContext::node`]
     pub unsafe fn node_mut(&self, node_id: &NodeId) -> NodeMut<'_, K, V> {
-        let header_ptr = self.header(node_id);
-        let header = header_ptr.read();
-        match header.tag {
+        let header_ptr = self
+            .checked_header(node_id)
+            .unwrap_or_else(|err| panic!("{err}"));
+        self.mark_unsealed(node_id);
+        match (*header_ptr).tag {
             NodeTag::Branch => NodeMut::Branch(to_branch(header_ptr).as_mut().unwrap()),
             NodeTag::Leaf => NodeMut::Leaf(to_leaf(header_ptr).as_mut().unwrap()),
         }
     }
+
+    /// Checks that a node has a known tag and lies within the buffer, returning its header
+    ///
+    /// # Safety
+    /// See [`BNodeContext::node`]
+    unsafe fn checked_header(&self, node_id: &NodeId) -> Result<*mut NodeHeader, MemTreeError> {
+        let offset = node_id.offset();
+        let header_ptr = self.header(node_id);
+        let overflow = MemTreeError::OffsetOverflow { offset };
+        let header_end = offset.checked_add(std::mem::size_of::<NodeHeader>());
+        if header_end.is_none_or(|end| end > self.len) {
+            return Err(overflow);
+        }
+        // Read as a byte, since a damaged tag may not be a valid `NodeTag`
+        let tag = (header_ptr as *const u8).read();
+        let (entries_start, entry_size) = if tag == NodeTag::Branch as u8 {
+            let start = children_offset::<BranchEntry<K>>();
+            (start, std::mem::size_of::<BranchEntry<K>>())
+        } else if tag == NodeTag::Leaf as u8 {
+            let start = children_offset::<LeafEntry<K, V>>();
+            (start, std::mem::size_of::<LeafEntry<K, V>>())
+        } else {
+            return Err(MemTreeError::BadTag { offset, tag });
+        };
+        let node_end = (*header_ptr)
+            .len
+            .get()
+            .checked_mul(entry_size)
+            .and_then(|entries| entries.checked_add(entries_start))
+            .and_then(|size| size.checked_add(offset));
+        if node_end.is_none_or(|end| end > self.len) {
+            return Err(overflow);
+        }
+        #[cfg(feature = "verify-nodes")]
+        self.verify_node(node_id)?;
+        Ok(header_ptr)
+    }
+}
+
+/// Checks that a buffer holds a tree that was checkpointed and hasn't been changed or damaged since
+pub fn verify(buffer: &[u8]) -> bool {
+    if buffer.len() < std::mem::size_of::<AnyHeader>() {
+        return false;
+    }
+    let header = unsafe { (buffer.as_ptr() as *const AnyHeader).as_ref().unwrap() };
+    header.magic == MAGIC
+        && header.checksummed.get() != 0
+        && header.checksum.get() == checksum(buffer)
+}
+
+fn checksum(buffer: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&buffer[..std::mem::offset_of!(AnyHeader, checksummed)]);
+    hasher.update(&buffer[std::mem::offset_of!(AnyHeader, key_size)..]);
+    hasher.finalize()
+}
+
+/// The layout of a node holding `len` entries, including any padding needed between the header
+/// and the first entry to keep entries aligned
+fn node_layout<E>(len: usize) -> Layout {
+    let (layout, _) = Layout::new::<NodeHeader>()
+        .extend(Layout::array::<E>(len).unwrap())
+        .unwrap();
+    layout.pad_to_align()
+}
+
+/// Reads back bytes stored by [`BNodeContext::try_alloc_bytes`] in the buffer starting at `base`
+///
+/// # Safety
+/// The offset must have come from `try_alloc_bytes` on a context over this buffer and not been
+/// freed, and the bytes must not be freed while the slice is in use
+pub unsafe fn stored_bytes<'b>(base: *const u8, offset: usize) -> &'b [u8] {
+    let ptr = base.add(offset);
+    let len = (ptr as *const Le64).read().get();
+    std::slice::from_raw_parts(ptr.add(std::mem::size_of::<Le64>()), len)
+}
+
+fn bytes_layout(len: usize) -> Layout {
+    let (layout, _) = Layout::new::<Le64>()
+        .extend(Layout::array::<u8>(len).unwrap())
+        .unwrap();
+    layout.pad_to_align()
+}
+
+/// How many entries to make room for in a node allocated with `len` of them
+///
+/// Rounding up lets a node take a few more entries in place before it has to be reallocated.
+fn capacity_for(len: usize) -> usize {
+    len.next_power_of_two()
+}
+
+/// How far into a node its entries start, which matches where `repr(C)` puts the `children` field
+fn children_offset<E>() -> usize {
+    let (_, offset) = Layout::new::<NodeHeader>()
+        .extend(Layout::new::<E>())
+        .unwrap();
+    offset
 }
 
 /// # Safety
 /// header_ptr must be a pointer to a valid Leaf
 unsafe fn to_leaf<K, V>(header_ptr: *mut NodeHeader) -> *mut Leaf<K, V> {
     let header = header_ptr.read();
-    assert_eq!(header.tag, NodeTag::Leaf);
-    let wide_ptr = ptr::slice_from_raw_parts(header_ptr as *mut u8, header.len);
+    debug_assert_eq!(header.tag, NodeTag::Leaf);
+    // The slice length is the number of entries; the cast places them after the header with the
+    // same padding as `node_layout`
+    let wide_ptr = ptr::slice_from_raw_parts(header_ptr as *mut u8, header.len.get());
     wide_ptr as *mut Leaf<K, V>
 }
 
@@ -327,7 +1070,9 @@ unsafe fn to_leaf<K, V>(header_ptr: *mut NodeHeader) -> *mut Leaf<K, V> {
 /// header_ptr must be a pointer to a valid Branch
 unsafe fn to_branch<K>(header_ptr: *mut NodeHeader) -> *mut Branch<K> {
     let header = header_ptr.read();
-    assert_eq!(header.tag, NodeTag::Branch);
-    let wide_ptr = ptr::slice_from_raw_parts(header_ptr as *mut u8, header.len);
+    debug_assert_eq!(header.tag, NodeTag::Branch);
+    // The slice length is the number of entries; the cast places them after the header with the
+    // same padding as `node_layout`
+    let wide_ptr = ptr::slice_from_raw_parts(header_ptr as *mut u8, header.len.get());
     wide_ptr as *mut Branch<K>
 }
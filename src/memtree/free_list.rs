@@ -0,0 +1,161 @@
+use std::{alloc::Layout, cell::Cell};
+
+use super::context::{BufferAllocator, Le64};
+
+/// The default [`BufferAllocator`], which keeps the free space in a list of holes and reuses it
+///
+/// The holes are linked through the buffer itself in address order, so neighbouring holes can be
+/// merged as blocks are freed. Each hole records its size and the offset of the next one, and the
+/// header only holds the offset of the first, so the whole list is plain little-endian offsets that
+/// still make sense wherever the buffer is mapped.
+#[repr(C)]
+pub struct FreeList {
+    /// The offset of the first hole, or 0 if there's no free space left
+    head: Cell<Le64>,
+    /// How many bytes the holes add up to
+    free: Cell<Le64>,
+}
+
+/// The bookkeeping written at the start of every hole
+#[repr(C)]
+struct Hole {
+    size: Le64,
+    /// The offset of the next hole, or 0 if this is the last
+    next: Le64,
+}
+
+/// Blocks are kept to multiples of this, so that every hole has room for its bookkeeping
+const HOLE_ALIGN: usize = std::mem::align_of::<Hole>();
+const HOLE_SIZE: usize = std::mem::size_of::<Hole>();
+
+impl FreeList {
+    /// The bytes a block for `layout` takes up, which is also what it gives back when it's freed
+    fn block_size(layout: Layout) -> usize {
+        layout.size().max(HOLE_SIZE).next_multiple_of(HOLE_ALIGN)
+    }
+
+    /// Reads the hole at `offset`, or `None` for the end of the list
+    unsafe fn hole(base: *mut u8, offset: usize) -> Option<(usize, usize)> {
+        if offset == 0 {
+            return None;
+        }
+        let hole = (base.add(offset) as *const Hole).read_unaligned();
+        Some((hole.size.get(), hole.next.get()))
+    }
+
+    unsafe fn write_hole(base: *mut u8, offset: usize, size: usize, next: usize) {
+        let hole = Hole {
+            size: Le64::new(size),
+            next: Le64::new(next),
+        };
+        (base.add(offset) as *mut Hole).write_unaligned(hole);
+    }
+
+    /// Points the hole at `prev`, or the head of the list if there's none, at `next`
+    unsafe fn link(&self, base: *mut u8, prev: Option<usize>, next: usize) {
+        match prev {
+            Some(prev) => {
+                let (size, _) = Self::hole(base, prev).expect("holes are never at offset 0");
+                Self::write_hole(base, prev, size, next);
+            }
+            None => self.head.set(Le64::new(next)),
+        }
+    }
+
+    fn add_free(&self, bytes: isize) {
+        let free = self.free.get().get().wrapping_add_signed(bytes);
+        self.free.set(Le64::new(free));
+    }
+}
+
+unsafe impl BufferAllocator for FreeList {
+    unsafe fn init(base: *mut u8, start: usize, size: usize) -> Self {
+        let list = FreeList {
+            head: Cell::new(Le64::new(0)),
+            free: Cell::new(Le64::new(0)),
+        };
+        let size = size - size % HOLE_ALIGN;
+        if size >= HOLE_SIZE {
+            Self::write_hole(base, start, size, 0);
+            list.head.set(Le64::new(start));
+            list.free.set(Le64::new(size));
+        }
+        list
+    }
+
+    unsafe fn alloc(&self, base: *mut u8, layout: Layout) -> Option<usize> {
+        let size = Self::block_size(layout);
+        let align = layout.align().max(HOLE_ALIGN);
+        let aligned =
+            |offset: usize| (base as usize + offset).next_multiple_of(align) - base as usize;
+
+        let mut prev = None;
+        let mut current = self.head.get().get();
+        while let Some((hole_size, next)) = Self::hole(base, current) {
+            let hole_end = current + hole_size;
+            // Any gap left in front of the block has to be big enough to stay a hole
+            let mut start = aligned(current);
+            if start != current && start - current < HOLE_SIZE {
+                start = aligned(current + HOLE_SIZE);
+            }
+            let end = start + size;
+            // So does any gap left behind it, which can't be handed out with the block, since
+            // `dealloc` only gets the layout to tell how big the block was
+            let fits = end == hole_end || end + HOLE_SIZE <= hole_end;
+            if fits {
+                let after = match end < hole_end {
+                    true => {
+                        Self::write_hole(base, end, hole_end - end, next);
+                        end
+                    }
+                    false => next,
+                };
+                match start > current {
+                    true => Self::write_hole(base, current, start - current, after),
+                    false => self.link(base, prev, after),
+                }
+                self.add_free(-(size as isize));
+                return Some(start);
+            }
+            prev = Some(current);
+            current = next;
+        }
+        None
+    }
+
+    unsafe fn dealloc(&self, base: *mut u8, offset: usize, layout: Layout) {
+        let size = Self::block_size(layout);
+        let mut prev = None;
+        let mut next = self.head.get().get();
+        while next != 0 && next < offset {
+            prev = Some(next);
+            next = Self::hole(base, next)
+                .expect("the loop stops at the end of the list")
+                .1;
+        }
+
+        // Merge the block with the holes on either side of it, if they touch
+        let mut end = offset + size;
+        let mut after = next;
+        if let Some((next_size, next_next)) = Self::hole(base, next) {
+            if end == next {
+                end += next_size;
+                after = next_next;
+            }
+        }
+        match prev.map(|prev| (prev, Self::hole(base, prev).unwrap().0)) {
+            Some((prev, prev_size)) if prev + prev_size == offset => {
+                Self::write_hole(base, prev, end - prev, after);
+            }
+            _ => {
+                Self::write_hole(base, offset, end - offset, after);
+                self.link(base, prev, offset);
+            }
+        }
+        self.add_free(size as isize);
+    }
+
+    fn free(&self) -> usize {
+        self.free.get().get()
+    }
+}
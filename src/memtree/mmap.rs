@@ -0,0 +1,49 @@
+use std::{fs::OpenOptions, io, path::Path};
+
+use memmap2::MmapMut;
+
+use super::BTree;
+
+/// Trees kept in a memory-mapped file, which own the mapping so it can't be dropped out from under
+/// them
+///
+/// The buffer only refers to its own contents by offset, so a file can be mapped back in at a
+/// different address than it was created at. Like any memory-mapped file, the tree is only sound
+/// as long as nothing else changes the file while it's open.
+impl<K: Ord + Clone, V: Clone> BTree<'static, K, V> {
+    /// Creates a file of `size` bytes at `path`, replacing anything already there, and starts an
+    /// empty tree in it
+    pub fn create(path: impl AsRef<Path>, size: usize) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.set_len(size as u64)?;
+        let mut mmap = unsafe { MmapMut::map_mut(&file)? };
+        let mut tree = BTree::new(unsafe { mapped_buffer(&mut mmap) });
+        tree.mmap = Some(mmap);
+        Ok(tree)
+    }
+
+    /// Opens a file holding a tree, such as one made by [`BTree::create`]
+    ///
+    /// A file that doesn't hold a tree that can be loaded is reported as
+    /// [`io::ErrorKind::InvalidData`], wrapping the [`LoadError`](super::LoadError).
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let mut mmap = unsafe { MmapMut::map_mut(&file)? };
+        let mut tree = BTree::load(unsafe { mapped_buffer(&mut mmap) })
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        tree.mmap = Some(mmap);
+        Ok(tree)
+    }
+}
+
+/// # Safety
+/// The buffer must not be used once the mapping is dropped, which holds as long as the mapping is
+/// stored in the tree built on the buffer
+unsafe fn mapped_buffer(mmap: &mut MmapMut) -> &'static mut [u8] {
+    std::slice::from_raw_parts_mut(mmap.as_mut_ptr(), mmap.len())
+}
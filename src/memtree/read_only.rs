@@ -0,0 +1,49 @@
+use crate::tree::NaturalOrder;
+
+use super::{get, BNodeContext, BTree, BTreeIter, BufferAllocator, FreeList, LoadError};
+
+/// A tree read out of a buffer that's only borrowed immutably, such as a read-only memory map
+///
+/// Made by [`BTree::load_ro`]. It can only look entries up, so it never writes to the buffer, not
+/// even to mark the checksum stale.
+pub struct ReadOnlyMemTree<'a, K, V, A: BufferAllocator = FreeList> {
+    ctx: BNodeContext<'a, K, V, A>,
+}
+
+impl<K: Ord + Clone, V: Clone> BTree<'_, K, V> {
+    /// Picks up a tree previously written to the buffer, like [`BTree::load`], without needing to
+    /// borrow the buffer mutably
+    pub fn load_ro(buffer: &[u8]) -> Result<ReadOnlyMemTree<'_, K, V>, LoadError> {
+        BTree::load_ro_with_allocator(buffer)
+    }
+}
+
+impl<K: Ord + Clone, V: Clone, A: BufferAllocator> BTree<'_, K, V, A> {
+    /// Like [`BTree::load_ro`], for a buffer created with [`BTree::with_allocator`] and the same `A`
+    pub fn load_ro_with_allocator(
+        buffer: &[u8],
+    ) -> Result<ReadOnlyMemTree<'_, K, V, A>, LoadError> {
+        // The tree only reads through the context
+        let ctx = unsafe { BNodeContext::load_shared(buffer)? };
+        Ok(ReadOnlyMemTree { ctx })
+    }
+}
+
+impl<K: Ord, V, A: BufferAllocator> ReadOnlyMemTree<'_, K, V, A> {
+    /// Looks up the value stored under a key
+    ///
+    /// # Panics
+    /// If the buffer has been damaged
+    pub fn get(&self, key: &K) -> Option<&V> {
+        get(&self.ctx, self.ctx.root(), key, &NaturalOrder).unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Iterates over every entry in ascending key order
+    pub fn iter(&self) -> BTreeIter<'_, K, V, A> {
+        BTreeIter::new(&self.ctx)
+    }
+}
@@ -0,0 +1,75 @@
+use super::{build, drop_node, merged_entries, BTree, BufferAllocator, FreeList, MemTreeError};
+
+/// A batch of inserts and removes that reaches the tree all at once or not at all
+///
+/// Changes are staged in memory until [`Transaction::commit`], which builds a new tree holding
+/// them alongside the untouched nodes' entries and then swaps it in by replacing the root in the
+/// buffer's header. Until that swap the buffer only ever holds the old tree, so a crash partway
+/// through leaves it as it was. Dropping a transaction without committing discards its changes.
+///
+/// Nothing of the old tree is reused, so a commit copies every entry however few were changed,
+/// taking time in proportion to the size of the whole tree, and it needs enough free space for a
+/// second full copy of the tree. For a handful of changes that don't have to land together, plain
+/// [`BTree::insert`] and [`BTree::remove`] are far cheaper.
+pub struct Transaction<'t, 'a, K, V, A: BufferAllocator = FreeList> {
+    tree: &'t mut BTree<'a, K, V, A>,
+    /// Staged values, where `None` marks a key as removed
+    staged: crate::tree::BTree<K, Option<V>>,
+}
+
+impl<'a, K: Ord + Clone, V: Clone, A: BufferAllocator> BTree<'a, K, V, A> {
+    /// Starts staging changes to the tree to be applied together
+    pub fn transaction(&mut self) -> Transaction<'_, 'a, K, V, A> {
+        Transaction {
+            tree: self,
+            staged: crate::tree::BTree::new(),
+        }
+    }
+}
+
+impl<K: Ord + Clone, V: Clone, A: BufferAllocator> Transaction<'_, '_, K, V, A> {
+    /// Looks a key up as though the staged changes had already been committed
+    pub fn get(&self, key: &K) -> Option<&V> {
+        match self.staged.get(key) {
+            Some(staged) => staged.as_ref(),
+            None => self.tree.get(key),
+        }
+    }
+
+    /// Stages an insert, returning the value the key would have had before it
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        self.stage(key, Some(value))
+    }
+
+    /// Stages a removal, returning the value the key would have had before it
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        self.stage(key.clone(), None)
+    }
+
+    fn stage(&mut self, key: K, value: Option<V>) -> Option<V> {
+        match self.staged.get(&key) {
+            Some(_) => self.staged.insert(key, value).flatten(),
+            None => {
+                let previous = self.tree.get(&key).cloned();
+                self.staged.insert(key, value);
+                previous
+            }
+        }
+    }
+
+    /// Applies every staged change to the tree at once
+    ///
+    /// This rebuilds the whole tree alongside the old one, so the buffer needs room for both. If it
+    /// doesn't have it, the tree is left as it was and the staged changes are dropped.
+    pub fn commit(self) -> Result<(), MemTreeError> {
+        let entries = merged_entries(self.tree.iter(), self.staged);
+        let ctx = &mut self.tree.ctx;
+        ctx.invalidate_checksum();
+        unsafe {
+            let new_root = build(ctx, entries)?;
+            let old_root = ctx.set_root(new_root);
+            drop_node(ctx, old_root);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,105 @@
+use std::{
+    borrow::Borrow,
+    fmt,
+    sync::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+use crate::tree::{BTree, Comparator, NaturalOrder};
+
+/// A [`BTree`] that can be shared between threads, behind a single [`RwLock`]
+///
+/// Any number of lookups can run at once, but each insert or remove locks the whole tree. Values
+/// are cloned out of the tree by [`get`](Self::get) so that no lock is held once it returns.
+///
+/// If a thread panics while it holds the lock, the tree may be left half-modified, so every later
+/// call panics as well.
+pub struct SyncBTree<K, V, const MAX: usize = 4, C = NaturalOrder> {
+    tree: RwLock<BTree<K, V, MAX, C>>,
+}
+
+impl<K, V> SyncBTree<K, V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<K, V, const MAX: usize, C: Default> Default for SyncBTree<K, V, MAX, C> {
+    fn default() -> Self {
+        BTree::default().into()
+    }
+}
+
+impl<K, V, const MAX: usize, C> From<BTree<K, V, MAX, C>> for SyncBTree<K, V, MAX, C> {
+    fn from(tree: BTree<K, V, MAX, C>) -> Self {
+        SyncBTree {
+            tree: RwLock::new(tree),
+        }
+    }
+}
+
+impl<K: fmt::Debug, V: fmt::Debug, const MAX: usize, C> fmt::Debug for SyncBTree<K, V, MAX, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SyncBTree")
+            .field("tree", &*self.read())
+            .finish()
+    }
+}
+
+impl<K, V, const MAX: usize, C> SyncBTree<K, V, MAX, C> {
+    fn read(&self) -> RwLockReadGuard<'_, BTree<K, V, MAX, C>> {
+        self.tree
+            .read()
+            .expect("a thread panicked while modifying the tree")
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, BTree<K, V, MAX, C>> {
+        self.tree
+            .write()
+            .expect("a thread panicked while modifying the tree")
+    }
+
+    pub fn into_inner(self) -> BTree<K, V, MAX, C> {
+        self.tree
+            .into_inner()
+            .expect("a thread panicked while modifying the tree")
+    }
+
+    pub fn len(&self) -> usize {
+        self.read().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.read().is_empty()
+    }
+
+    pub fn get<Q: ?Sized>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        C: Comparator<Q>,
+        V: Clone,
+    {
+        self.read().get(key).cloned()
+    }
+
+    pub fn contains_key<Q: ?Sized>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        C: Comparator<Q>,
+    {
+        self.read().contains_key(key)
+    }
+}
+
+impl<K: Clone, V: Clone, const MAX: usize, C: Comparator<K>> SyncBTree<K, V, MAX, C> {
+    pub fn insert(&self, key: K, val: V) -> Option<V> {
+        self.write().insert(key, val)
+    }
+
+    pub fn remove<Q: ?Sized>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        C: Comparator<Q>,
+    {
+        self.write().remove(key)
+    }
+}
@@ -1,368 +1,2532 @@
-#[derive(Debug)]
-pub struct BTree<K, V> {
-    root: BNode<K, V>,
+mod comparator;
+mod entry;
+mod merge;
+mod multi;
+#[cfg(feature = "rayon")]
+mod par;
+#[cfg(feature = "serde")]
+mod serde;
+#[cfg(feature = "std")]
+mod stream;
+
+use alloc::{collections::BTreeMap, format, string::String, sync::Arc, vec, vec::Vec};
+use core::{
+    borrow::Borrow,
+    cmp::Ordering,
+    fmt,
+    hash::{Hash, Hasher},
+    iter::{FusedIterator, Rev},
+    marker::PhantomData,
+    ops::{Bound, Index, RangeBounds},
+};
+
+pub use comparator::{Comparator, Fallible, NaturalOrder, PartialOrder, TryComparator, Unordered};
+
+use comparator::Infallibly;
+pub use entry::{Entry, OccupiedEntry, VacantEntry};
+pub use merge::{merge_join, MergeSide};
+pub use multi::MultiBTree;
+
+/// A B+tree holding its nodes on the heap
+///
+/// `MAX` is the most entries a node can hold before it splits, and nodes are merged once they hold
+/// fewer than half that. Larger fanouts mean shallower trees and fewer splits, at the cost of
+/// shifting more entries around on each insert and remove.
+///
+/// Keys are kept in the order given by `C`, which is their [`Ord`] implementation by default.
+///
+/// Nodes are reference counted and copied on write, so cloning a tree or taking a
+/// [`snapshot`](BTree::snapshot) shares every node until one of the trees changes it.
+#[derive(Clone)]
+pub struct BTree<K, V, const MAX: usize = 4, C = NaturalOrder> {
+    root: Arc<BNode<K, V>>,
+    len: usize,
+    cmp: C,
+    split: SplitPolicy,
+}
+
+/// Where a node that's grown too large is divided, set with [`BTree::set_split_policy`]
+///
+/// Both halves of a split have to hold at least `MAX / 2` entries, so the policy only decides how
+/// the entries beyond that are shared out.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SplitPolicy {
+    /// Divides the entries evenly, leaving room in both halves for keys inserted anywhere
+    #[default]
+    Balanced,
+    /// Keeps as many entries in the left half as it can, leaving the right half with the fewest
+    /// a node may hold
+    ///
+    /// Keys inserted in ascending order only ever land in the right half, so the left one is never
+    /// filled any further and this wastes less of it. With the default fanout, leaves end up three
+    /// quarters full rather than half.
+    AppendOptimized,
+}
+
+impl SplitPolicy {
+    /// How many of a splitting node's `len` entries stay in the left half
+    fn left_len<const MAX: usize>(self, len: usize) -> usize {
+        match self {
+            SplitPolicy::Balanced => len / 2,
+            SplitPolicy::AppendOptimized => len - MAX / 2,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum BNode<K, V> {
+    Branch {
+        intervals: Vec<K>,
+        children: Vec<Arc<BNode<K, V>>>,
+    },
+    Leaf(Vec<(K, V)>),
+}
+
+/// Either end of a tree, which is reached by taking the first or last child of every node
+#[derive(Clone, Copy)]
+enum End {
+    First,
+    Last,
+}
+
+impl End {
+    /// The index of the child or entry of `node` on the way to this end
+    fn locate<K, V>(self, node: &BNode<K, V>) -> Option<usize> {
+        match self {
+            End::First => Some(0),
+            End::Last => node.len().checked_sub(1),
+        }
+    }
+}
+
+impl<K, V> Default for BNode<K, V> {
+    fn default() -> Self {
+        Self::Leaf(Vec::default())
+    }
+}
+
+impl<K, V, const MAX: usize, C: Default> Default for BTree<K, V, MAX, C> {
+    fn default() -> Self {
+        Self::empty(C::default())
+    }
+}
+
+// The comparator is usually a closure, which can't be printed
+impl<K: fmt::Debug, V: fmt::Debug, const MAX: usize, C> fmt::Debug for BTree<K, V, MAX, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BTree")
+            .field("root", &self.root)
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+// Equal trees can be shaped differently depending on the order of inserts and removes, so
+// equality compares entries rather than nodes
+impl<K: PartialEq, V: PartialEq, const MAX: usize, C> PartialEq for BTree<K, V, MAX, C> {
+    fn eq(&self, other: &Self) -> bool {
+        self.len == other.len && self.iter().eq(other.iter())
+    }
+}
+
+impl<K: Eq, V: Eq, const MAX: usize, C> Eq for BTree<K, V, MAX, C> {}
+
+/// Compares the entries lexicographically in ascending key order, like std's `BTreeMap`, so it
+/// agrees with equality whatever shape the nodes are
+///
+/// This walks both trees until they differ, which is O(n) when they're equal or one's entries are a
+/// prefix of the other's.
+impl<K: PartialOrd, V: PartialOrd, const MAX: usize, C> PartialOrd for BTree<K, V, MAX, C> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        self.iter().partial_cmp(other.iter())
+    }
+}
+
+impl<K: Ord, V: Ord, const MAX: usize, C> Ord for BTree<K, V, MAX, C> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.iter().cmp(other.iter())
+    }
+}
+
+/// Hashes the entries in ascending key order, preceded by their count, so like equality it reflects
+/// the tree's contents and not how its nodes happen to be shaped
+impl<K: Hash, V: Hash, const MAX: usize, C> Hash for BTree<K, V, MAX, C> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_usize(self.len);
+        for entry in self.iter() {
+            entry.hash(state);
+        }
+    }
+}
+
+impl<K, V> BTree<K, V> {
+    /// Creates an empty tree with the default fanout; use [`Default`] for other fanouts, e.g.
+    /// `BTree::<K, V, 64>::default()`
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<K, V, const MAX: usize, C: Fn(&K, &K) -> Ordering> BTree<K, V, MAX, C> {
+    /// Creates an empty tree that orders its keys with `cmp` rather than their [`Ord`]
+    /// implementation
+    ///
+    /// Lookups can then only be made with `&K`, rather than any type that `K` borrows as. Nothing
+    /// pins down the fanout, so it has to be named, e.g. `BTree::<K, V, 4, _>::with_comparator`.
+    pub fn with_comparator(cmp: C) -> Self {
+        Self::empty(cmp)
+    }
+}
+
+impl<K, V, const MAX: usize, C> BTree<K, V, MAX, C> {
+    fn empty(cmp: C) -> Self {
+        const {
+            assert!(
+                MAX >= 4,
+                "nodes must fit at least 4 entries so that halves of a split are never underfull"
+            )
+        };
+        BTree {
+            root: Arc::new(BNode::Branch {
+                intervals: Vec::new(),
+                children: Vec::new(),
+            }),
+            len: 0,
+            cmp,
+            split: SplitPolicy::Balanced,
+        }
+    }
+
+    /// Makes a copy of the tree as it is now, in constant time
+    ///
+    /// The copy shares every node with this tree. Changing either one copies the nodes on the path
+    /// from the root down to the change first, so neither sees the other's changes.
+    pub fn snapshot(&self) -> Self
+    where
+        C: Clone,
+    {
+        BTree {
+            root: Arc::clone(&self.root),
+            len: self.len,
+            cmp: self.cmp.clone(),
+            split: self.split,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Changes where nodes are divided when they split from now on, leaving existing nodes as they
+    /// are
+    pub fn set_split_policy(&mut self, policy: SplitPolicy) {
+        self.split = policy;
+    }
+
+    pub fn split_policy(&self) -> SplitPolicy {
+        self.split
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.take_all();
+    }
+
+    /// Empties the tree, handing back everything it held
+    fn take_all(&mut self) -> IntoIter<K, V> {
+        let root = core::mem::replace(
+            &mut self.root,
+            Arc::new(BNode::Branch {
+                intervals: Vec::new(),
+                children: Vec::new(),
+            }),
+        );
+        IntoIter {
+            stack: vec![vec![root].into_iter()],
+            leaf: Vec::new().into_iter(),
+            remaining: core::mem::take(&mut self.len),
+        }
+    }
+
+    /// Empties the tree, yielding everything it held in ascending order
+    ///
+    /// The tree is emptied up front, so it's left empty however much of the iterator is used, and
+    /// any entries that aren't reached are dropped along with it. Entries in nodes shared with
+    /// another tree are cloned out rather than moved, so iterating needs `K: Clone` and `V: Clone`.
+    pub fn drain(&mut self) -> Drain<'_, K, V> {
+        Drain {
+            entries: self.take_all(),
+            _tree: PhantomData,
+        }
+    }
+
+    pub fn iter(&self) -> BTreeIter<'_, K, V> {
+        let root: &BNode<K, V> = &self.root;
+        BTreeIter {
+            stack: vec![(root, 0)],
+            back_stack: vec![(root, root.len())],
+            remaining: self.len,
+        }
+    }
+
+    /// Iterates over every entry, letting values be changed
+    ///
+    /// Nodes shared with a snapshot are copied as the iterator reaches them, which is what the
+    /// `Clone` bounds are for; nodes that aren't shared are changed in place.
+    pub fn iter_mut(&mut self) -> BTreeIterMut<'_, K, V>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        BTreeIterMut {
+            stack: vec![core::slice::from_mut(&mut self.root).iter_mut()],
+            leaf: [].iter_mut(),
+            remaining: self.len,
+        }
+    }
+
+    pub fn first_key_value(&self) -> Option<(&K, &V)> {
+        self.root.first().map(|(key, val)| (key, val))
+    }
+
+    pub fn last_key_value(&self) -> Option<(&K, &V)> {
+        self.root.last().map(|(key, val)| (key, val))
+    }
+
+    /// The entry at `index` in ascending key order, in time linear in `index`
+    ///
+    /// Nodes don't count the entries beneath them, so this walks every entry before `index`. The
+    /// buffer-backed tree's branches do keep counts, which lets it skip whole subtrees instead.
+    pub fn nth(&self, index: usize) -> Option<(&K, &V)> {
+        self.iter().nth(index)
+    }
+
+    /// Like [`BTree::nth`], but lets the value be changed, copying the nodes on the way to it if
+    /// they're shared
+    pub fn nth_mut(&mut self, index: usize) -> Option<(&K, &mut V)>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        self.iter_mut().nth(index)
+    }
+
+    /// Renders the tree one node per line, indented by depth, showing branch intervals and leaf
+    /// entries
+    pub fn debug_structure(&self) -> String
+    where
+        K: core::fmt::Debug,
+        V: core::fmt::Debug,
+    {
+        let mut out = String::new();
+        self.root.write_structure(&mut out, 0);
+        out
+    }
+
+    /// The number of branch levels between the root and the leaves, or 0 for an empty tree
+    pub fn height(&self) -> usize {
+        self.root.height()
+    }
+
+    pub fn node_count(&self) -> usize {
+        let stats = self.stats();
+        stats.branch_count + stats.leaf_count
+    }
+
+    pub fn stats(&self) -> TreeStats {
+        let mut stats = TreeStats {
+            height: self.height(),
+            ..TreeStats::default()
+        };
+        self.root.collect_stats(&mut stats);
+        stats
+    }
+
+    /// Makes room in the leaves for about `additional` more entries, so that inserting them
+    /// reallocates less often
+    ///
+    /// This is only a hint. Leaves split once they hold more than `MAX` entries, and the halves of
+    /// a split get storage of their own, so only inserts into the leaves already in the tree
+    /// benefit. Leaves shared with a clone of the tree are left alone.
+    pub fn reserve(&mut self, mut additional: usize) {
+        if let Some(root) = Arc::get_mut(&mut self.root) {
+            root.reserve::<MAX>(&mut additional);
+        }
+    }
+
+    /// Releases the spare capacity each node's storage has been left with
+    ///
+    /// Nodes keep whatever room they grew to, so a tree that was once much larger than it is now
+    /// can hold on to a lot of unused memory. Nodes shared with a clone of the tree are left alone,
+    /// since shrinking them would mean copying them.
+    pub fn shrink_to_fit(&mut self) {
+        if let Some(root) = Arc::get_mut(&mut self.root) {
+            root.shrink_to_fit();
+        }
+    }
+
+    /// Checks every invariant the tree relies on, describing the first one found to be broken
+    ///
+    /// Intervals must separate the children on either side of them, every node apart from the
+    /// root and a lone leaf beneath it must hold between `MAX / 2` and `MAX` entries, every leaf
+    /// must be at the same depth, and keys must be in strictly ascending order. This walks the
+    /// whole tree, so it's meant for tests rather than regular use.
+    pub fn validate(&self) -> Result<(), String>
+    where
+        C: Comparator<K>,
+    {
+        if matches!(*self.root, BNode::Leaf(_)) {
+            return Err(String::from("the root is a leaf"));
+        }
+        let mut validation = Validation {
+            cmp: &self.cmp,
+            leaf_depth: None,
+            last_key: None,
+            entries: 0,
+        };
+        validation.node::<MAX, _>(&self.root, 0, 0, false, (None, None))?;
+        if validation.entries != self.len {
+            return Err(format!(
+                "the tree has a length of {} but holds {} entries",
+                self.len, validation.entries
+            ));
+        }
+        Ok(())
+    }
+
+    /// Whether every insert and remove runs [`BTree::validate`] afterwards, which the
+    /// `validate-mutations` feature turns on
+    ///
+    /// A change that breaks an invariant then panics straight away, with the shape of every node,
+    /// rather than leaving a corrupt tree to fail some later lookup. That makes it suited to fuzzing
+    /// and stress tests, e.g. `cargo fuzz run <target> --features validate-mutations` or
+    /// `cargo test --features validate-mutations`. Each change walks the whole tree, so it's far
+    /// too slow to leave on otherwise.
+    pub const fn debug_invariants_enabled() -> bool {
+        cfg!(feature = "validate-mutations")
+    }
+
+    /// Panics if the tree is broken after `change`, when [`BTree::debug_invariants_enabled`]
+    fn check_invariants(&self, change: &str)
+    where
+        C: Comparator<K>,
+    {
+        if !Self::debug_invariants_enabled() {
+            return;
+        }
+        if let Err(err) = self.validate() {
+            let mut shape = String::new();
+            self.root.write_shape(&mut shape, 0);
+            panic!("{change} broke the tree: {err}\n{shape}");
+        }
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.iter().map(|(key, _)| key)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.iter().map(|(_, val)| val)
+    }
+
+    /// Like [`BTree::iter_mut`], copying shared nodes as it reaches them
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut V>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        self.iter_mut().map(|(_, val)| val)
+    }
+
+    /// Keys in nodes shared with another tree are cloned rather than moved out
+    pub fn into_keys(self) -> impl Iterator<Item = K>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        self.into_iter().map(|(key, _)| key)
+    }
+
+    /// Values in nodes shared with another tree are cloned rather than moved out
+    pub fn into_values(self) -> impl Iterator<Item = V>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        self.into_iter().map(|(_, val)| val)
+    }
+}
+
+impl<K, V, const MAX: usize, C> BTree<K, V, MAX, C> {
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> Range<'_, K, V>
+    where
+        C: Comparator<K>,
+    {
+        Range::new(&self.root, range, &self.cmp)
+    }
+
+    /// Iterates over the entries in a range in descending key order, such as to find the most
+    /// recent entries under time-ordered keys
+    pub fn range_rev<R: RangeBounds<K>>(&self, range: R) -> Rev<Range<'_, K, V>>
+    where
+        C: Comparator<K>,
+    {
+        self.range(range).rev()
+    }
+
+    /// How many keys fall in a range, in time linear in the count
+    pub fn count_range<R: RangeBounds<K>>(&self, range: R) -> usize
+    where
+        C: Comparator<K>,
+    {
+        self.range(range).count()
+    }
+
+    /// Iterates over the entries whose keys start with the bytes of `prefix`, in ascending key
+    /// order, such as every `"user:"` key
+    ///
+    /// Like [`BTree::range`], this descends straight to both ends: the first key at or after
+    /// `prefix`, and the last before the prefix with its final byte incremented, carrying past any
+    /// trailing 0xFF bytes. Keys sharing a prefix are only contiguous if the comparator orders keys
+    /// by their bytes, as the natural order of `String` and `Vec<u8>` does.
+    pub fn prefix(&self, prefix: &K) -> Range<'_, K, V>
+    where
+        K: AsRef<[u8]>,
+        C: Comparator<K>,
+    {
+        let bytes = prefix.as_ref();
+        Range::between(
+            &self.root,
+            |key| self.cmp.compare(key, prefix) == Ordering::Less,
+            |key| before_prefix_end(key.as_ref(), bytes),
+        )
+    }
+
+    pub fn get<Q: ?Sized>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        C: Comparator<Q>,
+    {
+        self.root.get(key, &self.cmp).map(|(_, val)| val)
+    }
+
+    /// Looks up a batch of keys at once, returning their values in the same order as the keys
+    ///
+    /// The keys are sorted and then looked up together in a single pass down the tree, so nodes
+    /// on the path to several of them are only visited once.
+    pub fn get_many(&self, keys: &[K]) -> Vec<Option<&V>>
+    where
+        C: Comparator<K>,
+    {
+        let mut queries: Vec<(usize, &K)> = keys.iter().enumerate().collect();
+        queries.sort_by(|(_, a), (_, b)| self.cmp.compare(a, b));
+        let mut found = vec![None; keys.len()];
+        self.root.get_many(&queries, &mut found, &self.cmp);
+        found
+    }
+
+    /// Looks up a key, returning the key stored in the tree along with its value
+    ///
+    /// The stored key can differ from the one looked up in ways that don't affect their order, or
+    /// be a different type that it borrows as.
+    pub fn get_key_value<Q: ?Sized>(&self, key: &Q) -> Option<(&K, &V)>
+    where
+        K: Borrow<Q>,
+        C: Comparator<Q>,
+    {
+        self.root.get(key, &self.cmp).map(|(key, val)| (key, val))
+    }
+
+    /// Any node on the way to the value that's shared with another tree is copied first, which
+    /// is what needs `K: Clone` and `V: Clone`
+    pub fn get_mut<Q: ?Sized>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q> + Clone,
+        V: Clone,
+        C: Comparator<Q>,
+    {
+        Arc::make_mut(&mut self.root).get_mut(key, &self.cmp)
+    }
+
+    /// The entry with the largest key less than or equal to `key`, if any
+    pub fn floor<Q: ?Sized>(&self, key: &Q) -> Option<(&K, &V)>
+    where
+        K: Borrow<Q>,
+        C: Comparator<Q>,
+    {
+        self.root
+            .last_before(key, &self.cmp, true)
+            .map(|(key, val)| (key, val))
+    }
+
+    /// The entry with the smallest key greater than or equal to `key`, if any
+    pub fn ceiling<Q: ?Sized>(&self, key: &Q) -> Option<(&K, &V)>
+    where
+        K: Borrow<Q>,
+        C: Comparator<Q>,
+    {
+        self.root
+            .first_after(key, &self.cmp, true)
+            .map(|(key, val)| (key, val))
+    }
+
+    /// The entry with the largest key strictly less than `key`, if any
+    ///
+    /// Unlike [`BTree::floor`], an entry under `key` itself is skipped, so this steps backwards
+    /// through the tree from any key whether or not it's present.
+    pub fn predecessor<Q: ?Sized>(&self, key: &Q) -> Option<(&K, &V)>
+    where
+        K: Borrow<Q>,
+        C: Comparator<Q>,
+    {
+        self.root
+            .last_before(key, &self.cmp, false)
+            .map(|(key, val)| (key, val))
+    }
+
+    /// The entry with the smallest key strictly greater than `key`, if any
+    ///
+    /// Unlike [`BTree::ceiling`], an entry under `key` itself is skipped, so this steps forwards
+    /// through the tree from any key whether or not it's present.
+    pub fn successor<Q: ?Sized>(&self, key: &Q) -> Option<(&K, &V)>
+    where
+        K: Borrow<Q>,
+        C: Comparator<Q>,
+    {
+        self.root
+            .first_after(key, &self.cmp, false)
+            .map(|(key, val)| (key, val))
+    }
+
+    pub fn contains_key<Q: ?Sized>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        C: Comparator<Q>,
+    {
+        self.root.contains(key, &self.cmp)
+    }
+
+    /// The number of keys in the tree that are less than `key`, in time linear in the result
+    pub fn rank<Q: ?Sized>(&self, key: &Q) -> usize
+    where
+        K: Borrow<Q>,
+        C: Comparator<Q>,
+    {
+        self.iter()
+            .take_while(|(entry_key, _)| {
+                self.cmp.compare((*entry_key).borrow(), key) == Ordering::Less
+            })
+            .count()
+    }
+
+    /// The entry with `n` smaller keys before it, i.e. the `n`th counting from zero
+    ///
+    /// The counterpart to [`BTree::rank`], and the same as [`BTree::nth`].
+    pub fn select(&self, n: usize) -> Option<(&K, &V)> {
+        self.nth(n)
+    }
+
+    /// A cursor starting on the first entry, which can be moved back and forth through the tree
+    /// without going back to the root each time
+    pub fn cursor(&self) -> Cursor<'_, K, V, C> {
+        let mut cursor = Cursor {
+            root: &self.root,
+            path: Vec::new(),
+            cmp: &self.cmp,
+        };
+        cursor.move_next();
+        cursor
+    }
+
+    pub fn entry(&mut self, key: K) -> Entry<'_, K, V, MAX, C>
+    where
+        C: Comparator<K>,
+    {
+        Entry::new(self, key)
+    }
+}
+
+impl<K: Clone, V: Clone, const MAX: usize, C: Comparator<K>> BTree<K, V, MAX, C> {
+    pub fn insert(&mut self, key: K, val: V) -> Option<V> {
+        let root = Arc::make_mut(&mut self.root);
+        let previous =
+            match root.insert_root::<MAX, _>(key, val, self.split, &Infallibly(&self.cmp)) {
+                Ok(previous) => previous,
+                Err(never) => match never {},
+            };
+        if previous.is_none() {
+            self.len += 1;
+        }
+        self.check_invariants("insert");
+        previous
+    }
+
+    /// Borrows the value stored under a key, inserting one made by `f` first if there isn't one
+    ///
+    /// `f` is only called if the key is missing, and the tree is only descended once either way,
+    /// unless [`BTree::debug_invariants_enabled`].
+    pub fn get_or_insert_with<F: FnOnce() -> V>(&mut self, key: K, f: F) -> &mut V {
+        if !Self::debug_invariants_enabled() {
+            return self.get_or_insert_with_unchecked(key, f);
+        }
+        // The tree can't be checked while one of its values is borrowed, so look the value up again
+        let lookup = key.clone();
+        self.get_or_insert_with_unchecked(key, f);
+        self.check_invariants("get_or_insert_with");
+        self.get_mut(&lookup)
+            .expect("the key was just looked up or inserted")
+    }
+
+    fn get_or_insert_with_unchecked<F: FnOnce() -> V>(&mut self, key: K, f: F) -> &mut V {
+        let root = Arc::make_mut(&mut self.root);
+        let (val, inserted) = root.get_or_insert_with::<MAX, C, F>(key, f, self.split, &self.cmp);
+        if inserted {
+            self.len += 1;
+        }
+        val
+    }
+
+    pub fn remove<Q: ?Sized>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        C: Comparator<Q>,
+    {
+        self.remove_entry(key).map(|(_, val)| val)
+    }
+
+    /// Like [`BTree::remove`], but also hands back the key that was stored, which can differ from
+    /// the one it was looked up by
+    pub fn remove_entry<Q: ?Sized>(&mut self, key: &Q) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+        C: Comparator<Q>,
+    {
+        self.remove_by::<Q>(|node, cmp| node.locate(key, cmp))
+    }
+
+    /// Removes the entry that `locate` leads to; see [`BNode::remove_by`]
+    fn remove_by<Q: ?Sized>(
+        &mut self,
+        mut locate: impl FnMut(&BNode<K, V>, &C) -> Option<usize>,
+    ) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+        C: Comparator<Q>,
+    {
+        let cmp = &self.cmp;
+        let previous = Arc::make_mut(&mut self.root)
+            .remove_by::<MAX, Q, C>(&mut |node| locate(node, cmp), cmp);
+        if previous.is_some() {
+            self.len -= 1;
+        }
+        // A root with a single branch beneath it is a wasted level, so promote the branch
+        while let BNode::Branch {
+            intervals: _,
+            children,
+        } = Arc::make_mut(&mut self.root)
+        {
+            if children.len() != 1 || !matches!(*children[0], BNode::Branch { .. }) {
+                break;
+            }
+            self.root = children.pop().unwrap();
+        }
+        self.check_invariants("remove");
+        previous
+    }
+
+    /// Removes every entry with a key in the range, returning how many were removed
+    pub fn remove_range<R: RangeBounds<K>>(&mut self, range: R) -> usize {
+        let keys: Vec<K> = self.range(range).map(|(key, _)| key.clone()).collect();
+        for key in keys.iter() {
+            self.remove(key);
+        }
+        keys.len()
+    }
+
+    pub fn split_off<Q: ?Sized>(&mut self, key: &Q) -> BTree<K, V, MAX, C>
+    where
+        K: Borrow<Q>,
+        C: Comparator<Q> + Clone,
+    {
+        let mut right = BTree::empty(self.cmp.clone());
+        right.split = self.split;
+        for (entry_key, val) in self.take_all() {
+            if self.cmp.compare(entry_key.borrow(), key) == Ordering::Less {
+                self.insert(entry_key, val);
+            } else {
+                right.insert(entry_key, val);
+            }
+        }
+        right
+    }
+
+    pub fn append(&mut self, other: &mut BTree<K, V, MAX, C>) {
+        self.extend(other.take_all());
+    }
+
+    /// Moves every entry of `other` into this tree, when they all have greater keys than any
+    /// already here
+    ///
+    /// Rather than inserting the entries one at a time, the shorter tree's root is hung beneath
+    /// the taller one's along the edge where they meet, so this only copies and splits nodes
+    /// along that edge and takes time proportional to the height. The keys must be in order;
+    /// this is only checked in debug builds.
+    pub fn append_sorted(&mut self, other: BTree<K, V, MAX, C>) {
+        debug_assert!(
+            match (self.last_key_value(), other.first_key_value()) {
+                (Some((last, _)), Some((first, _))) => {
+                    self.cmp.compare(last, first) == Ordering::Less
+                }
+                _ => true,
+            },
+            "append_sorted needs every key in other to be greater than every key in self"
+        );
+        if other.is_empty() {
+            return;
+        }
+        if self.is_empty() {
+            self.root = other.root;
+            self.len = other.len;
+            return;
+        }
+
+        let separator = other.root.first().unwrap().0.clone();
+        let (left_height, right_height) = (self.height(), other.height());
+        let left = Arc::unwrap_or_clone(core::mem::take(&mut self.root));
+        let right = Arc::unwrap_or_clone(other.root);
+        let (joined, split) = BNode::join::<MAX, C>(
+            (left, left_height),
+            separator,
+            (right, right_height),
+            &self.cmp,
+        );
+        self.root = Arc::new(match split {
+            Some((separator, new_node)) => BNode::Branch {
+                intervals: vec![separator],
+                children: vec![Arc::new(joined), Arc::new(new_node)],
+            },
+            None => joined,
+        });
+        self.len += other.len;
+        self.check_invariants("append_sorted");
+    }
+
+    /// Keeps only the entries that `f` returns true for, visiting them in ascending order
+    ///
+    /// `f` sees every entry before any are removed, so if it panics the tree keeps all of them,
+    /// along with whatever changes it made to the values.
+    pub fn retain<F: FnMut(&K, &mut V) -> bool>(&mut self, mut f: F) {
+        let rejected: Vec<K> = self
+            .iter_mut()
+            .filter_map(|(key, val)| (!f(key, val)).then(|| key.clone()))
+            .collect();
+        for key in rejected.iter() {
+            self.remove(key);
+        }
+    }
+
+    /// Removes the entries that `pred` returns true for, yielding them in ascending order
+    ///
+    /// Entries are only looked at as the iterator is advanced, and the tree is left valid after
+    /// each one, so whatever the iterator hasn't reached when it's dropped is kept.
+    ///
+    /// `pred` only gets to read the values, so that the path to an entry is only copied out of a
+    /// snapshot if it's removed; use [`BTree::retain`] to change the values that are kept.
+    pub fn extract_if<F: FnMut(&K, &V) -> bool>(
+        &mut self,
+        pred: F,
+    ) -> ExtractIf<'_, K, V, MAX, C, F> {
+        ExtractIf {
+            tree: self,
+            pred,
+            last: None,
+        }
+    }
+
+    pub fn pop_first(&mut self) -> Option<(K, V)> {
+        self.remove_by::<K>(|node, _| End::First.locate(node))
+    }
+
+    pub fn pop_last(&mut self) -> Option<(K, V)> {
+        self.remove_by::<K>(|node, _| End::Last.locate(node))
+    }
+
+    /// The entry with the smallest key, whose value can be changed or which can be removed
+    ///
+    /// Rather than holding a copy of the key and searching for it like other [`OccupiedEntry`]s,
+    /// it finds its way back by taking the first child of every node, without comparing keys.
+    pub fn first_entry(&mut self) -> Option<OccupiedEntry<'_, K, V, MAX, C>> {
+        self.root.first()?;
+        Some(OccupiedEntry::at_end(self, End::First))
+    }
+
+    /// The entry with the largest key; see [`BTree::first_entry`]
+    pub fn last_entry(&mut self) -> Option<OccupiedEntry<'_, K, V, MAX, C>> {
+        self.root.last()?;
+        Some(OccupiedEntry::at_end(self, End::Last))
+    }
+
+    /// Builds a tree from entries that are already in ascending order, packing leaves as full as
+    /// possible and stacking branches on top of them, which is much faster than inserting one by
+    /// one
+    ///
+    /// The entries must be strictly ascending; this is only checked in debug builds.
+    pub fn from_sorted<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self
+    where
+        C: Default,
+    {
+        let mut tree = Self::default();
+        let entries: Vec<_> = iter.into_iter().collect();
+        debug_assert!(
+            entries
+                .windows(2)
+                .all(|pair| tree.cmp.compare(&pair[0].0, &pair[1].0) == Ordering::Less),
+            "from_sorted needs strictly ascending keys"
+        );
+        tree.len = entries.len();
+
+        let mut level: Vec<_> = chunk_evenly(entries, MAX)
+            .into_iter()
+            .map(BNode::Leaf)
+            .collect();
+        while level.len() > MAX {
+            level = chunk_evenly(level, MAX)
+                .into_iter()
+                .map(BNode::branch_of)
+                .collect();
+        }
+        tree.root = Arc::new(BNode::branch_of(level));
+        tree
+    }
+}
+
+impl<K, V, const MAX: usize, E, F: Fn(&K, &K) -> Result<Ordering, E>>
+    BTree<K, V, MAX, Fallible<F>>
+{
+    /// Creates an empty tree that orders its keys with a comparison that can fail
+    ///
+    /// See [`Fallible`] for which methods report a failed comparison. As with
+    /// [`BTree::with_comparator`], the fanout has to be named.
+    pub fn with_try_comparator(cmp: F) -> Self {
+        Self::empty(Fallible(cmp))
+    }
+}
+
+impl<K, V, const MAX: usize, T> BTree<K, V, MAX, Fallible<T>> {
+    /// Like [`BTree::get`], but reports a failed comparison instead of panicking
+    pub fn try_get<Q: ?Sized>(&self, key: &Q) -> Result<Option<&V>, T::Error>
+    where
+        K: Borrow<Q>,
+        T: TryComparator<Q>,
+    {
+        let entry = self.root.try_get(key, &self.cmp.0)?;
+        Ok(entry.map(|(_, val)| val))
+    }
+
+    /// Like [`BTree::insert`], but reports a failed comparison instead of panicking
+    ///
+    /// Every comparison an insert makes comes before it changes anything, so if one fails the tree
+    /// is left as it was and the key and value are dropped. The checks the `validate-mutations`
+    /// feature runs afterwards are the exception: they go through [`BTree::validate`], which
+    /// panics like any other method if a comparison fails.
+    pub fn try_insert(&mut self, key: K, val: V) -> Result<Option<V>, T::Error>
+    where
+        K: Clone,
+        V: Clone,
+        T: TryComparator<K>,
+    {
+        let root = Arc::make_mut(&mut self.root);
+        let previous = root.insert_root::<MAX, T>(key, val, self.split, &self.cmp.0)?;
+        if previous.is_none() {
+            self.len += 1;
+        }
+        self.check_invariants("try_insert");
+        Ok(previous)
+    }
+}
+
+impl<K: Clone, V: Clone, const MAX: usize, C: Comparator<K> + Default> FromIterator<(K, V)>
+    for BTree<K, V, MAX, C>
+{
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut tree = BTree::default();
+        tree.extend(iter);
+        tree
+    }
+}
+
+impl<K: Clone, V: Clone, const MAX: usize, C: Comparator<K>> Extend<(K, V)>
+    for BTree<K, V, MAX, C>
+{
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        for (key, val) in iter {
+            self.insert(key, val);
+        }
+    }
+}
+
+/// Bulk-loads the map's entries, which are already in order. Like [`BTree::new`], this makes a tree
+/// with the default fanout, so the type can be inferred.
+impl<K: Ord + Clone, V: Clone> From<BTreeMap<K, V>> for BTree<K, V> {
+    fn from(map: BTreeMap<K, V>) -> Self {
+        BTree::from_sorted(map)
+    }
+}
+
+impl<K: Ord + Clone, V: Clone, const MAX: usize> From<BTree<K, V, MAX>> for BTreeMap<K, V> {
+    fn from(tree: BTree<K, V, MAX>) -> Self {
+        tree.into_iter().collect()
+    }
+}
+
+impl<K: Borrow<Q>, V, Q: ?Sized, const MAX: usize, C: Comparator<Q>> Index<&Q>
+    for BTree<K, V, MAX, C>
+{
+    type Output = V;
+
+    /// Borrows the value stored under a key
+    ///
+    /// # Panics
+    /// If the key isn't in the tree; see [`BTree::get`] for a version that doesn't panic
+    fn index(&self, key: &Q) -> &V {
+        self.get(key).expect("no entry found for key")
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TreeStats {
+    pub height: usize,
+    pub leaf_count: usize,
+    pub branch_count: usize,
+    pub entries: usize,
+    /// How many entries the leaves have room for without reallocating
+    pub capacity: usize,
+}
+
+/// What [`BTree::validate`] has seen of the nodes it's walked so far
+struct Validation<'a, K, C> {
+    cmp: &'a C,
+    leaf_depth: Option<usize>,
+    last_key: Option<&'a K>,
+    entries: usize,
+}
+
+impl<'a, K, C: Comparator<K>> Validation<'a, K, C> {
+    /// Checks a node and everything beneath it, where `bounds` are the intervals on either side of
+    /// it in its parent: every key must be at least the lower one and less than the upper one
+    fn node<const MAX: usize, V>(
+        &mut self,
+        node: &'a BNode<K, V>,
+        depth: usize,
+        idx: usize,
+        exempt: bool,
+        bounds: (Option<&'a K>, Option<&'a K>),
+    ) -> Result<(), String> {
+        let len = node.len();
+        if len > MAX || (!exempt && depth > 0 && len < MAX / 2) {
+            return Err(format!(
+                "child {idx} at depth {depth} has {len} entries, outside of {}..={MAX}",
+                MAX / 2
+            ));
+        }
+        match node {
+            BNode::Branch {
+                intervals,
+                children,
+            } => {
+                if children.is_empty() && depth == 0 {
+                    return Ok(());
+                }
+                if intervals.len() + 1 != children.len() {
+                    return Err(format!(
+                        "child {idx} at depth {depth} has {} intervals for {} children",
+                        intervals.len(),
+                        children.len()
+                    ));
+                }
+                let lone_child = depth == 0 && children.len() == 1;
+                for (i, child) in children.iter().enumerate() {
+                    let lower = if i == 0 {
+                        bounds.0
+                    } else {
+                        intervals.get(i - 1)
+                    };
+                    let upper = intervals.get(i).or(bounds.1);
+                    self.node::<MAX, V>(child, depth + 1, i, lone_child, (lower, upper))?;
+                }
+                Ok(())
+            }
+            BNode::Leaf(entries) => {
+                if *self.leaf_depth.get_or_insert(depth) != depth {
+                    return Err(format!(
+                        "child {idx} at depth {depth} is a leaf, but other leaves are at depth {}",
+                        self.leaf_depth.unwrap()
+                    ));
+                }
+                for (key, _) in entries {
+                    let in_bounds = bounds
+                        .0
+                        .is_none_or(|lower| self.cmp.compare(lower, key) != Ordering::Greater)
+                        && bounds
+                            .1
+                            .is_none_or(|upper| self.cmp.compare(key, upper) == Ordering::Less);
+                    if !in_bounds {
+                        return Err(format!(
+                            "entry {} of child {idx} at depth {depth} is outside of the intervals \
+                             around it",
+                            self.entries
+                        ));
+                    }
+                    if let Some(last_key) = self.last_key {
+                        if self.cmp.compare(last_key, key) != Ordering::Less {
+                            return Err(format!(
+                                "entry {} isn't greater than the entry before it",
+                                self.entries
+                            ));
+                        }
+                    }
+                    self.last_key = Some(key);
+                    self.entries += 1;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<K, V> BNode<K, V> {
+    fn first(&self) -> Option<&(K, V)> {
+        match self {
+            BNode::Branch {
+                intervals: _,
+                children,
+            } => children.first().and_then(|child| child.first()),
+            BNode::Leaf(children) => children.first(),
+        }
+    }
+
+    fn last(&self) -> Option<&(K, V)> {
+        match self {
+            BNode::Branch {
+                intervals: _,
+                children,
+            } => children.last().and_then(|child| child.last()),
+            BNode::Leaf(children) => children.last(),
+        }
+    }
+
+    fn end(&self, end: End) -> Option<&(K, V)> {
+        match end {
+            End::First => self.first(),
+            End::Last => self.last(),
+        }
+    }
+
+    /// Like [`BNode::end`], copying any shared nodes on the way so that the entry can be changed
+    fn end_mut(&mut self, end: End) -> Option<&mut (K, V)>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        match self {
+            BNode::Branch {
+                intervals: _,
+                children,
+            } => {
+                let child = match end {
+                    End::First => children.first_mut(),
+                    End::Last => children.last_mut(),
+                };
+                Arc::make_mut(child?).end_mut(end)
+            }
+            BNode::Leaf(children) => match end {
+                End::First => children.first_mut(),
+                End::Last => children.last_mut(),
+            },
+        }
+    }
+
+    fn height(&self) -> usize {
+        match self {
+            BNode::Branch {
+                intervals: _,
+                children,
+            } => children.first().map_or(0, |child| child.height() + 1),
+            BNode::Leaf(_) => 0,
+        }
+    }
+
+    fn collect_stats(&self, stats: &mut TreeStats) {
+        match self {
+            BNode::Branch {
+                intervals: _,
+                children,
+            } => {
+                stats.branch_count += 1;
+                for child in children {
+                    child.collect_stats(stats);
+                }
+            }
+            BNode::Leaf(children) => {
+                stats.leaf_count += 1;
+                stats.entries += children.len();
+                stats.capacity += children.capacity();
+            }
+        }
+    }
+
+    /// Grows leaves to the most entries they ever hold, until room has been made for `additional`
+    fn reserve<const MAX: usize>(&mut self, additional: &mut usize) {
+        match self {
+            BNode::Branch {
+                intervals: _,
+                children,
+            } => {
+                for child in children.iter_mut().filter_map(Arc::get_mut) {
+                    if *additional == 0 {
+                        break;
+                    }
+                    child.reserve::<MAX>(additional);
+                }
+            }
+            BNode::Leaf(children) => {
+                // A leaf holds one entry past `MAX` until its parent splits it
+                let room = (MAX + 1).saturating_sub(children.len()).min(*additional);
+                children.reserve_exact(room);
+                *additional -= room;
+            }
+        }
+    }
+
+    fn shrink_to_fit(&mut self) {
+        match self {
+            BNode::Branch {
+                intervals,
+                children,
+            } => {
+                intervals.shrink_to_fit();
+                children.shrink_to_fit();
+                for child in children.iter_mut().filter_map(Arc::get_mut) {
+                    child.shrink_to_fit();
+                }
+            }
+            BNode::Leaf(children) => children.shrink_to_fit(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            BNode::Branch {
+                intervals: _,
+                children,
+            } => children.len(),
+            BNode::Leaf(children) => children.len(),
+        }
+    }
+}
+
+impl<K: core::fmt::Debug, V: core::fmt::Debug> BNode<K, V> {
+    fn write_structure(&self, out: &mut String, depth: usize) {
+        use core::fmt::Write;
+
+        let indent = "  ".repeat(depth);
+        match self {
+            BNode::Branch {
+                intervals,
+                children,
+            } => {
+                writeln!(out, "{indent}Branch {intervals:?}").unwrap();
+                for child in children {
+                    child.write_structure(out, depth + 1);
+                }
+            }
+            BNode::Leaf(children) => writeln!(out, "{indent}Leaf {children:?}").unwrap(),
+        }
+    }
+}
+
+impl<K, V> BNode<K, V> {
+    /// Like [`BNode::write_structure`], but with how many entries each node has rather than what
+    /// they are, so it works for any keys and values
+    fn write_shape(&self, out: &mut String, depth: usize) {
+        use core::fmt::Write;
+
+        let indent = "  ".repeat(depth);
+        match self {
+            BNode::Branch {
+                intervals,
+                children,
+            } => {
+                writeln!(out, "{indent}Branch of {} intervals", intervals.len()).unwrap();
+                for child in children {
+                    child.write_shape(out, depth + 1);
+                }
+            }
+            BNode::Leaf(children) => writeln!(out, "{indent}Leaf of {}", children.len()).unwrap(),
+        }
+    }
+
+    fn get<Q: ?Sized, C: Comparator<Q>>(&self, key: &Q, cmp: &C) -> Option<&(K, V)>
+    where
+        K: Borrow<Q>,
+    {
+        match self.try_get(key, &Infallibly(cmp)) {
+            Ok(entry) => entry,
+            Err(never) => match never {},
+        }
+    }
+
+    fn try_get<Q: ?Sized, T: TryComparator<Q>>(
+        &self,
+        key: &Q,
+        cmp: &T,
+    ) -> Result<Option<&(K, V)>, T::Error>
+    where
+        K: Borrow<Q>,
+    {
+        match self {
+            BNode::Branch {
+                intervals,
+                children,
+            } => match children.get(try_find_idx_from_interval(intervals, key, cmp)?) {
+                Some(child) => child.try_get(key, cmp),
+                None => Ok(None),
+            },
+            BNode::Leaf(children) => {
+                let found = try_binary_search_by(children, |(child_key, _)| {
+                    cmp.try_compare(child_key.borrow(), key)
+                })?;
+                Ok(found.ok().map(|idx| &children[idx]))
+            }
+        }
+    }
+
+    fn get_mut<Q: ?Sized, C: Comparator<Q>>(&mut self, key: &Q, cmp: &C) -> Option<&mut V>
+    where
+        K: Borrow<Q> + Clone,
+        V: Clone,
+    {
+        match self {
+            BNode::Branch {
+                intervals,
+                children,
+            } => Arc::make_mut(children.get_mut(find_idx_from_interval(intervals, key, cmp))?)
+                .get_mut(key, cmp),
+            BNode::Leaf(children) => {
+                let idx = children
+                    .binary_search_by(|(child_key, _)| cmp.compare(child_key.borrow(), key))
+                    .ok()?;
+                Some(&mut children[idx].1)
+            }
+        }
+    }
+
+    fn contains<Q: ?Sized, C: Comparator<Q>>(&self, key: &Q, cmp: &C) -> bool
+    where
+        K: Borrow<Q>,
+    {
+        match self {
+            BNode::Branch {
+                intervals,
+                children,
+            } => children
+                .get(find_idx_from_interval(intervals, key, cmp))
+                .is_some_and(|child| child.contains(key, cmp)),
+            BNode::Leaf(children) => children
+                .binary_search_by(|(child_key, _)| cmp.compare(child_key.borrow(), key))
+                .is_ok(),
+        }
+    }
+
+    /// Looks up keys sorted in ascending order, storing the value for each in `found` at the index
+    /// paired with it
+    fn get_many<'a, C: Comparator<K>>(
+        &'a self,
+        queries: &[(usize, &K)],
+        found: &mut [Option<&'a V>],
+        cmp: &C,
+    ) {
+        match self {
+            BNode::Branch {
+                intervals,
+                children,
+            } => {
+                // Keys equal to an interval belong to the child after it, as in
+                // find_idx_from_interval
+                let mut queries = queries;
+                for (idx, child) in children.iter().enumerate() {
+                    let child_query_count = match intervals.get(idx) {
+                        Some(interval) => queries.partition_point(|(_, key)| {
+                            cmp.compare(key, interval) == Ordering::Less
+                        }),
+                        None => queries.len(),
+                    };
+                    let (child_queries, rest) = queries.split_at(child_query_count);
+                    if !child_queries.is_empty() {
+                        child.get_many(child_queries, found, cmp);
+                    }
+                    queries = rest;
+                }
+            }
+            BNode::Leaf(children) => {
+                for &(query_idx, key) in queries {
+                    found[query_idx] = children
+                        .binary_search_by(|(child_key, _)| cmp.compare(child_key, key))
+                        .ok()
+                        .map(|idx| &children[idx].1);
+                }
+            }
+        }
+    }
+
+    /// The entry with the largest key less than `key`, or equal to it if `inclusive`
+    fn last_before<Q: ?Sized, C: Comparator<Q>>(
+        &self,
+        key: &Q,
+        cmp: &C,
+        inclusive: bool,
+    ) -> Option<&(K, V)>
+    where
+        K: Borrow<Q>,
+    {
+        match self {
+            BNode::Branch {
+                intervals,
+                children,
+            } => {
+                let idx = find_idx_from_interval(intervals, key, cmp);
+                // Every key in the child before is smaller, so its last entry is the next best
+                children
+                    .get(idx)?
+                    .last_before(key, cmp, inclusive)
+                    .or_else(|| idx.checked_sub(1).and_then(|idx| children[idx].last()))
+            }
+            BNode::Leaf(children) => {
+                let idx = children.partition_point(|(child_key, _)| {
+                    match cmp.compare(child_key.borrow(), key) {
+                        Ordering::Less => true,
+                        Ordering::Equal => inclusive,
+                        Ordering::Greater => false,
+                    }
+                });
+                idx.checked_sub(1).map(|idx| &children[idx])
+            }
+        }
+    }
+
+    /// The entry with the smallest key greater than `key`, or equal to it if `inclusive`
+    fn first_after<Q: ?Sized, C: Comparator<Q>>(
+        &self,
+        key: &Q,
+        cmp: &C,
+        inclusive: bool,
+    ) -> Option<&(K, V)>
+    where
+        K: Borrow<Q>,
+    {
+        match self {
+            BNode::Branch {
+                intervals,
+                children,
+            } => {
+                let idx = find_idx_from_interval(intervals, key, cmp);
+                // Every key in the child after is larger, so its first entry is the next best
+                children
+                    .get(idx)?
+                    .first_after(key, cmp, inclusive)
+                    .or_else(|| children.get(idx + 1)?.first())
+            }
+            BNode::Leaf(children) => {
+                let idx = children.partition_point(|(child_key, _)| {
+                    match cmp.compare(child_key.borrow(), key) {
+                        Ordering::Less => true,
+                        Ordering::Equal => !inclusive,
+                        Ordering::Greater => false,
+                    }
+                });
+                children.get(idx)
+            }
+        }
+    }
+
+    /// Checks that each interval is the first key of the child after it
+    ///
+    /// A comparison that fails says nothing about whether they match, so that pair is skipped
+    /// rather than panicking in the middle of a `try_` method.
+    fn debug_validate_intervals<T: TryComparator<K>>(&self, cmp: &T) {
+        #[cfg(debug_assertions)]
+        match self {
+            BNode::Branch {
+                intervals,
+                children,
+            } => {
+                debug_assert_eq!(intervals.len() + 1, children.len());
+                for i in 0..intervals.len() {
+                    debug_assert!(cmp
+                        .try_compare(&intervals[i], &children[i + 1].first().unwrap().0)
+                        .map_or(true, |order| order == Ordering::Equal));
+                }
+            }
+            BNode::Leaf(_) => {}
+        }
+        #[cfg(not(debug_assertions))]
+        let _ = cmp;
+    }
+}
+
+impl<K: Clone, V: Clone> BNode<K, V> {
+    /// Moves the back of this node's entries, as many as the policy leaves for the right half,
+    /// into a new node, returning it along with the smallest key beneath it to separate the two
+    ///
+    /// A branch already holds that key as the interval between its halves, so it's moved out
+    /// rather than cloned; only a leaf has to clone its key to share it with the parent.
+    fn split<const MAX: usize, T: TryComparator<K>>(
+        &mut self,
+        policy: SplitPolicy,
+        cmp: &T,
+    ) -> (K, Self) {
+        match self {
+            BNode::Branch {
+                intervals,
+                children,
+            } => {
+                let children_halfway = policy.left_len::<MAX>(children.len());
+                let split_children = children.drain(children_halfway..).collect();
+
+                let interval_halfway = children_halfway - 1;
+                let split_interval = intervals.drain((interval_halfway + 1)..).collect();
+                let separator = intervals.remove(interval_halfway);
+
+                self.debug_validate_intervals(cmp);
+
+                let new_node = BNode::Branch {
+                    intervals: split_interval,
+                    children: split_children,
+                };
+                (separator, new_node)
+            }
+            BNode::Leaf(children) => {
+                let halfway = policy.left_len::<MAX>(children.len());
+                let split_children: Vec<_> = children.drain(halfway..).collect();
+                let separator = split_children[0].0.clone();
+                (separator, BNode::Leaf(split_children))
+            }
+        }
+    }
+
+    /// Makes a branch over children that are already in order
+    fn branch_of(children: Vec<Self>) -> Self {
+        let intervals = children
+            .iter()
+            .skip(1)
+            .map(|child| child.first().unwrap().0.clone())
+            .collect();
+        BNode::Branch {
+            intervals,
+            children: children.into_iter().map(Arc::new).collect(),
+        }
+    }
+
+    /// Inserts beneath the root of a tree, growing it taller if the root overflows
+    fn insert_root<const MAX: usize, T: TryComparator<K>>(
+        &mut self,
+        key: K,
+        val: V,
+        policy: SplitPolicy,
+        cmp: &T,
+    ) -> Result<Option<V>, T::Error> {
+        let previous = self.insert::<MAX, T>(key, val, policy, cmp)?;
+        // Overflowing children are split by their parent, so only the root grows the tree taller
+        if self.len() > MAX {
+            self.split_root::<MAX, T>(policy, cmp);
+        }
+        Ok(previous)
+    }
+
+    /// Splits the root in two beneath a new root, making the tree one level taller
+    fn split_root<const MAX: usize, T: TryComparator<K>>(&mut self, policy: SplitPolicy, cmp: &T) {
+        let (separator, new_node) = self.split::<MAX, T>(policy, cmp);
+        new_node.debug_validate_intervals(cmp);
+        let old_node = core::mem::take(self);
+        *self = BNode::Branch {
+            intervals: vec![separator],
+            children: vec![Arc::new(old_node), Arc::new(new_node)],
+        };
+    }
+
+    /// Looks up a key beneath the root, inserting a value made by `f` if it's missing, in a single
+    /// pass down the tree. Returns the value and whether it was inserted.
+    ///
+    /// Nodes can't be split on the way back up while a reference into one of them is being handed
+    /// back, so any full node on the path is split before it's entered instead. Both halves of a
+    /// full node are at least half full, so this keeps the tree valid even if the key turns out
+    /// to be there already.
+    fn get_or_insert_with<const MAX: usize, C: Comparator<K>, F: FnOnce() -> V>(
+        &mut self,
+        key: K,
+        f: F,
+        policy: SplitPolicy,
+        cmp: &C,
+    ) -> (&mut V, bool) {
+        if self.len() >= MAX {
+            self.split_root::<MAX, _>(policy, &Infallibly(cmp));
+        }
+        self.get_or_insert_with_below::<MAX, C, F>(key, f, policy, cmp)
+    }
+
+    /// See [`BNode::get_or_insert_with`], which makes sure this node isn't full
+    fn get_or_insert_with_below<const MAX: usize, C: Comparator<K>, F: FnOnce() -> V>(
+        &mut self,
+        key: K,
+        f: F,
+        policy: SplitPolicy,
+        cmp: &C,
+    ) -> (&mut V, bool) {
+        match self {
+            BNode::Branch {
+                intervals,
+                children,
+            } => {
+                if children.is_empty() {
+                    children.push(Arc::new(BNode::Leaf(vec![(key, f())])));
+                    return match Arc::make_mut(&mut children[0]) {
+                        BNode::Leaf(entries) => (&mut entries[0].1, true),
+                        BNode::Branch { .. } => unreachable!("a leaf was just pushed"),
+                    };
+                }
+
+                let mut idx = find_idx_from_interval(intervals, &key, cmp);
+                let child = Arc::make_mut(&mut children[idx]);
+                if child.len() >= MAX {
+                    let (separator, new_node) = child.split::<MAX, _>(policy, &Infallibly(cmp));
+                    // Keys equal to an interval belong to the child after it
+                    let goes_right = cmp.compare(&key, &separator) != Ordering::Less;
+                    intervals.insert(idx, separator);
+                    children.insert(idx + 1, Arc::new(new_node));
+                    if goes_right {
+                        idx += 1;
+                    }
+                }
+                Arc::make_mut(&mut children[idx])
+                    .get_or_insert_with_below::<MAX, C, F>(key, f, policy, cmp)
+            }
+            BNode::Leaf(children) => {
+                match children.binary_search_by(|(child_key, _)| cmp.compare(child_key, &key)) {
+                    Ok(idx) => (&mut children[idx].1, false),
+                    Err(idx) => {
+                        children.insert(idx, (key, f()));
+                        (&mut children[idx].1, true)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Inserts beneath this node, splitting any child that grows past `MAX` entries
+    ///
+    /// Every comparison is made on the way down, before anything is changed, so a failed one
+    /// leaves the node as it was
+    fn insert<const MAX: usize, T: TryComparator<K>>(
+        &mut self,
+        key: K,
+        mut val: V,
+        policy: SplitPolicy,
+        cmp: &T,
+    ) -> Result<Option<V>, T::Error> {
+        match self {
+            BNode::Branch {
+                intervals,
+                children,
+            } => {
+                if children.is_empty() {
+                    children.push(Arc::new(BNode::Leaf(vec![(key, val)])));
+                    return Ok(None);
+                }
+
+                let idx = try_find_idx_from_interval(intervals, &key, cmp)?;
+                let child = Arc::make_mut(&mut children[idx]);
+                let previous_val = child.insert::<MAX, T>(key, val, policy, cmp)?;
+                if child.len() > MAX {
+                    let (separator, new_node) = child.split::<MAX, T>(policy, cmp);
+                    new_node.debug_validate_intervals(cmp);
+                    intervals.insert(idx, separator);
+                    children.insert(idx + 1, Arc::new(new_node));
+                }
+                debug_assert!(children[idx].len() <= MAX);
+
+                Ok(previous_val)
+            }
+            BNode::Leaf(children) => {
+                match try_binary_search_by(children, |child_key| {
+                    cmp.try_compare(&child_key.0, &key)
+                })? {
+                    Ok(idx) => {
+                        let (_, child_value) = &mut children[idx];
+                        core::mem::swap(&mut val, child_value);
+                        Ok(Some(val))
+                    }
+                    Err(idx) => {
+                        children.insert(idx, (key, val));
+                        Ok(None)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Where `key` is among this node's children, or its entries if it's a leaf
+    fn locate<Q: ?Sized, C: Comparator<Q>>(&self, key: &Q, cmp: &C) -> Option<usize>
+    where
+        K: Borrow<Q>,
+    {
+        match self {
+            BNode::Branch {
+                intervals,
+                children: _,
+            } => Some(find_idx_from_interval(intervals, key, cmp)),
+            BNode::Leaf(children) => children
+                .binary_search_by(|child_key| cmp.compare(child_key.0.borrow(), key))
+                .ok(),
+        }
+    }
+
+    /// Removes the entry that `locate` leads to, rebalancing on the way back up
+    ///
+    /// `locate` is given each node on the way down, and returns the index of the child to descend
+    /// into or, at the leaf, of the entry to remove.
+    fn remove_by<const MAX: usize, Q: ?Sized, C: Comparator<Q>>(
+        &mut self,
+        locate: &mut impl FnMut(&Self) -> Option<usize>,
+        cmp: &C,
+    ) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+    {
+        if self.len() == 0 {
+            return None;
+        }
+        let idx = locate(self)?;
+        match self {
+            BNode::Branch {
+                intervals,
+                children,
+            } => {
+                let previous =
+                    Arc::make_mut(&mut children[idx]).remove_by::<MAX, Q, C>(locate, cmp)?;
+
+                // Removing the first key of a child leaves its separator pointing at a missing key
+                if idx > 0
+                    && cmp.compare(intervals[idx - 1].borrow(), previous.0.borrow())
+                        == Ordering::Equal
+                {
+                    if let Some((new_first_key, _)) = children[idx].first() {
+                        intervals[idx - 1] = new_first_key.clone();
+                    }
+                }
+
+                let mut merged_idx = idx;
+                if children[idx].len() < MAX / 2 {
+                    if idx > 0 && children[idx - 1].len() > MAX / 2 {
+                        let (left, right) = children.split_at_mut(idx);
+                        Arc::make_mut(&mut right[0]).take_last_from(
+                            Arc::make_mut(&mut left[idx - 1]),
+                            &mut intervals[idx - 1],
+                        );
+                    } else if idx + 1 < children.len() && children[idx + 1].len() > MAX / 2 {
+                        let (left, right) = children.split_at_mut(idx + 1);
+                        Arc::make_mut(&mut left[idx])
+                            .take_first_from(Arc::make_mut(&mut right[0]), &mut intervals[idx]);
+                    } else if idx > 0 {
+                        let node = children.remove(idx);
+                        let separator = intervals.remove(idx - 1);
+                        Arc::make_mut(&mut children[idx - 1])
+                            .merge(separator, Arc::unwrap_or_clone(node));
+                        merged_idx = idx - 1;
+                    } else if idx + 1 < children.len() {
+                        let node = children.remove(idx + 1);
+                        let separator = intervals.remove(idx);
+                        Arc::make_mut(&mut children[idx])
+                            .merge(separator, Arc::unwrap_or_clone(node));
+                    }
+                }
+                if children.len() > 1 {
+                    debug_assert!(children[merged_idx].len() >= MAX / 2);
+                }
+                // Siblings are only merged when neither can spare an entry, so they fit in one node
+                debug_assert!(children[merged_idx].len() <= MAX);
+
+                Some(previous)
+            }
+            BNode::Leaf(children) => Some(children.remove(idx)),
+        }
+    }
+
+    /// Moves the last entry of `left`, the sibling before this node, to the front of this node
+    fn take_last_from(&mut self, left: &mut Self, separator: &mut K) {
+        match (left, self) {
+            (BNode::Leaf(left), BNode::Leaf(right)) => {
+                let entry = left.pop().unwrap();
+                *separator = entry.0.clone();
+                right.insert(0, entry);
+            }
+            (
+                BNode::Branch {
+                    intervals: left_intervals,
+                    children: left_children,
+                },
+                BNode::Branch {
+                    intervals: right_intervals,
+                    children: right_children,
+                },
+            ) => {
+                // The moved child's separator goes up, and the old one comes down in front of
+                // what was this node's first child
+                let child_separator = left_intervals.pop().unwrap();
+                right_intervals.insert(0, core::mem::replace(separator, child_separator));
+                right_children.insert(0, left_children.pop().unwrap());
+            }
+            _ => unreachable!("siblings are always at the same height"),
+        }
+    }
+
+    /// Moves the first entry of `right`, the sibling after this node, to the end of this node
+    fn take_first_from(&mut self, right: &mut Self, separator: &mut K) {
+        match (self, right) {
+            (BNode::Leaf(left), BNode::Leaf(right)) => {
+                left.push(right.remove(0));
+                *separator = right[0].0.clone();
+            }
+            (
+                BNode::Branch {
+                    intervals: left_intervals,
+                    children: left_children,
+                },
+                BNode::Branch {
+                    intervals: right_intervals,
+                    children: right_children,
+                },
+            ) => {
+                let next_separator = right_intervals.remove(0);
+                left_intervals.push(core::mem::replace(separator, next_separator));
+                left_children.push(right_children.remove(0));
+            }
+            _ => unreachable!("siblings are always at the same height"),
+        }
+    }
+
+    /// Moves every entry of `other`, the sibling after this node, onto the end of this node
+    ///
+    /// `separator` is the interval that sat between the two in their parent, which becomes the
+    /// interval in front of `other`'s first child when merging branches.
+    ///
+    /// The tree only gains or loses a level at the root, so siblings are always the same height
+    /// and a leaf is never merged with a branch. Those cases are still handled the way a lopsided
+    /// tree would need, rather than panicking in release builds.
+    fn merge(&mut self, separator: K, other: Self) {
+        debug_assert_eq!(
+            self.height(),
+            other.height(),
+            "siblings should be the same height"
+        );
+        match (&mut *self, other) {
+            (
+                BNode::Branch {
+                    intervals,
+                    children,
+                },
+                BNode::Branch {
+                    intervals: other_intervals,
+                    children: other_children,
+                },
+            ) => {
+                intervals.push(separator);
+                intervals.extend(other_intervals);
+                children.extend(other_children);
+            }
+            (
+                BNode::Branch {
+                    intervals,
+                    children,
+                },
+                other @ BNode::Leaf(_),
+            ) => {
+                intervals.push(separator);
+                children.push(Arc::new(other));
+            }
+            (
+                BNode::Leaf(_),
+                BNode::Branch {
+                    mut intervals,
+                    mut children,
+                },
+            ) => {
+                intervals.insert(0, separator);
+                children.insert(0, Arc::new(core::mem::take(self)));
+                *self = BNode::Branch {
+                    intervals,
+                    children,
+                };
+            }
+            (BNode::Leaf(children), BNode::Leaf(other_children)) => {
+                children.extend(other_children);
+            }
+        }
+    }
+
+    /// Joins two branches, each paired with its height, into one at the taller one's height,
+    /// splitting it in two if it overflows
+    ///
+    /// Every key in `left` must be less than every key in `right`, the smallest of which is
+    /// `separator`. The shorter branch is merged into the edge of the taller one facing it, at the
+    /// level where they're the same height.
+    fn join<const MAX: usize, C: Comparator<K>>(
+        (mut left, left_height): (Self, usize),
+        separator: K,
+        (mut right, right_height): (Self, usize),
+        cmp: &C,
+    ) -> (Self, Option<(K, Self)>) {
+        let joined = match left_height.cmp(&right_height) {
+            Ordering::Greater => {
+                let BNode::Branch {
+                    intervals,
+                    children,
+                } = &mut left
+                else {
+                    unreachable!("only branches are taller than another node")
+                };
+                let last = Arc::unwrap_or_clone(children.pop().unwrap());
+                let (joined, split) = Self::join::<MAX, C>(
+                    (last, left_height - 1),
+                    separator,
+                    (right, right_height),
+                    cmp,
+                );
+                children.push(Arc::new(joined));
+                if let Some((separator, new_node)) = split {
+                    intervals.push(separator);
+                    children.push(Arc::new(new_node));
+                }
+                left
+            }
+            Ordering::Less => {
+                let BNode::Branch {
+                    intervals,
+                    children,
+                } = &mut right
+                else {
+                    unreachable!("only branches are taller than another node")
+                };
+                let first = Arc::unwrap_or_clone(children.remove(0));
+                let (joined, split) = Self::join::<MAX, C>(
+                    (left, left_height),
+                    separator,
+                    (first, right_height - 1),
+                    cmp,
+                );
+                children.insert(0, Arc::new(joined));
+                if let Some((separator, new_node)) = split {
+                    intervals.insert(0, separator);
+                    children.insert(1, Arc::new(new_node));
+                }
+                right
+            }
+            Ordering::Equal => {
+                let seam = left.len() - 1;
+                left.merge(separator, right);
+                left.fill_seam::<MAX>(seam);
+                left
+            }
+        };
+        if joined.len() > MAX {
+            let mut joined = joined;
+            // Seams can hold up to twice as many entries as a node, which only an even split
+            // divides into two that fit
+            let split = joined.split::<MAX, _>(SplitPolicy::Balanced, &Infallibly(cmp));
+            (joined, Some(split))
+        } else {
+            (joined, None)
+        }
+    }
+
+    /// Tops up the children either side of `seam` in a branch just merged from two, which may have
+    /// come from a root holding a lone leaf that's allowed fewer than `MAX / 2` entries
+    fn fill_seam<const MAX: usize>(&mut self, seam: usize) {
+        let BNode::Branch {
+            intervals,
+            children,
+        } = self
+        else {
+            unreachable!("only branches have seams")
+        };
+        let (left_len, right_len) = (children[seam].len(), children[seam + 1].len());
+        if left_len >= MAX / 2 && right_len >= MAX / 2 {
+            return;
+        }
+        if left_len + right_len <= MAX {
+            let node = children.remove(seam + 1);
+            let separator = intervals.remove(seam);
+            Arc::make_mut(&mut children[seam]).merge(separator, Arc::unwrap_or_clone(node));
+            return;
+        }
+        let (left, right) = children.split_at_mut(seam + 1);
+        let (left, right) = (Arc::make_mut(&mut left[seam]), Arc::make_mut(&mut right[0]));
+        while left.len() < MAX / 2 {
+            left.take_first_from(right, &mut intervals[seam]);
+        }
+        while right.len() < MAX / 2 {
+            right.take_last_from(left, &mut intervals[seam]);
+        }
+    }
 }
 
-#[derive(Clone, Debug)]
-enum BNode<K, V> {
-    Branch {
-        intervals: Vec<K>,
-        children: Vec<BNode<K, V>>,
-    },
-    Leaf(Vec<(K, V)>),
+/// Splits items into as few nodes of at most `max` as will hold them, with sizes differing by at
+/// most one so that none is underfull
+fn chunk_evenly<T>(items: Vec<T>, max: usize) -> Vec<Vec<T>> {
+    let chunk_count = items.len().div_ceil(max);
+    let mut remaining = items.len();
+    let mut items = items.into_iter();
+    (0..chunk_count)
+        .rev()
+        .map(|chunks_left| {
+            let len = remaining.div_ceil(chunks_left + 1);
+            remaining -= len;
+            items.by_ref().take(len).collect()
+        })
+        .collect()
 }
 
-impl<K: Ord + Eq + Clone, V: Clone> Default for BNode<K, V> {
-    fn default() -> Self {
-        Self::Leaf(Vec::default())
+/// Whether `key` comes before every key that's past the ones starting with `prefix` in byte order
+///
+/// The first such key is `prefix` with its last byte below 0xFF incremented and the bytes after
+/// that dropped, and if every byte is 0xFF, no key is past the prefix.
+fn before_prefix_end(key: &[u8], prefix: &[u8]) -> bool {
+    let Some(last) = prefix.iter().rposition(|&byte| byte != u8::MAX) else {
+        return true;
+    };
+    let (head, rest) = key.split_at(key.len().min(last));
+    match head.cmp(&prefix[..head.len()]) {
+        Ordering::Equal => rest.first().is_none_or(|&byte| byte <= prefix[last]),
+        ordering => ordering == Ordering::Less,
     }
 }
 
-impl<K, V> Default for BTree<K, V> {
-    fn default() -> Self {
-        Self::new()
+/// The index of the child that `key` belongs in, which is the number of intervals less than or
+/// equal to it
+pub(crate) fn find_idx_from_interval<K: Borrow<Q>, Q: ?Sized, C: Comparator<Q>>(
+    intervals: &[K],
+    key: &Q,
+    cmp: &C,
+) -> usize {
+    match try_find_idx_from_interval(intervals, key, &Infallibly(cmp)) {
+        Ok(idx) => idx,
+        Err(never) => match never {},
     }
 }
 
-impl<K, V> BTree<K, V> {
-    pub fn new() -> Self {
-        BTree {
-            root: BNode::Branch {
-                intervals: Vec::new(),
-                children: Vec::new(),
-            },
+fn try_find_idx_from_interval<K: Borrow<Q>, Q: ?Sized, T: TryComparator<Q>>(
+    intervals: &[K],
+    key: &Q,
+    cmp: &T,
+) -> Result<usize, T::Error> {
+    let (mut low, mut high) = (0, intervals.len());
+    while low < high {
+        let halfway = low + (high - low) / 2;
+        match cmp.try_compare(key, intervals[halfway].borrow())? {
+            Ordering::Less => high = halfway,
+            // Keys equal to an interval belong to the child after it
+            Ordering::Equal => return Ok(halfway + 1),
+            Ordering::Greater => low = halfway + 1,
         }
     }
+    Ok(low)
+}
 
-    pub fn iter(&self) -> BTreeIter<'_, K, V> {
-        BTreeIter {
-            stack: vec![(&self.root, 0)],
+/// Like [`slice::binary_search_by`], but giving up at the first comparison that fails
+fn try_binary_search_by<T, E>(
+    entries: &[T],
+    mut compare: impl FnMut(&T) -> Result<Ordering, E>,
+) -> Result<Result<usize, usize>, E> {
+    let (mut low, mut high) = (0, entries.len());
+    while low < high {
+        let halfway = low + (high - low) / 2;
+        match compare(&entries[halfway])? {
+            Ordering::Less => low = halfway + 1,
+            Ordering::Equal => return Ok(Ok(halfway)),
+            Ordering::Greater => high = halfway,
         }
     }
+    Ok(Err(low))
+}
+
+pub struct BTreeIter<'a, K, V> {
+    stack: Vec<(&'a BNode<K, V>, usize)>,
+    // Each entry holds the number of children not yet visited from the back
+    back_stack: Vec<(&'a BNode<K, V>, usize)>,
+    // The front and back cursors meet once every entry has been yielded
+    remaining: usize,
 }
 
-impl<K: Ord, V> BTree<K, V> {
-    pub fn get(&self, key: &K) -> Option<&V> {
-        self.root.get(key)
+impl<'a, K, V> Iterator for BTreeIter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let (key, val) = seek_front(&mut self.stack)?;
+        self.stack.last_mut().unwrap().1 += 1;
+        self.remaining -= 1;
+        Some((key, val))
     }
 
-    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
-        self.root.get_mut(key)
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
     }
 }
 
-impl<K: Ord + Eq + Clone, V: Clone> BTree<K, V> {
-    pub fn insert(&mut self, key: K, val: V) -> Option<V>
-    where
-        K: std::fmt::Debug,
-        V: std::fmt::Debug,
-    {
-        self.root.insert(key, val)
-    }
+impl<K, V> ExactSizeIterator for BTreeIter<'_, K, V> {}
+
+impl<K, V> FusedIterator for BTreeIter<'_, K, V> {}
 
-    pub fn remove(&mut self, key: &K) -> Option<V> {
-        self.root.remove(key)
+impl<K, V> DoubleEndedIterator for BTreeIter<'_, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let (key, val) = seek_back(&mut self.back_stack)?;
+        self.back_stack.last_mut().unwrap().1 -= 1;
+        self.remaining -= 1;
+        Some((key, val))
     }
 }
 
-const MIN_ITEMS_IN_NODE: usize = 2;
-const MAX_ITEMS_IN_NODE: usize = 4;
+pub struct Range<'a, K, V> {
+    front: Vec<(&'a BNode<K, V>, usize)>,
+    back: Vec<(&'a BNode<K, V>, usize)>,
+}
 
-impl<K: Ord, V> BNode<K, V> {
-    fn get(&self, key: &K) -> Option<&V> {
-        match self {
-            BNode::Branch {
-                intervals,
-                children,
-            } => children[find_idx_from_interval(intervals, key)].get(key),
-            BNode::Leaf(children) => {
-                let idx = children
-                    .binary_search_by(|(child_key, _)| child_key.cmp(key))
-                    .ok()?;
-                Some(&children[idx].1)
-            }
-        }
+impl<'a, K, V> Range<'a, K, V> {
+    fn new<R: RangeBounds<K>, C: Comparator<K>>(root: &'a BNode<K, V>, range: R, cmp: &C) -> Self {
+        let less = |a: &K, b: &K| cmp.compare(a, b) == Ordering::Less;
+        let less_or_equal = |a: &K, b: &K| cmp.compare(a, b) != Ordering::Greater;
+        Range::between(
+            root,
+            |key| match range.start_bound() {
+                Bound::Included(start) => less(key, start),
+                Bound::Excluded(start) => less_or_equal(key, start),
+                Bound::Unbounded => false,
+            },
+            |key| match range.end_bound() {
+                Bound::Included(end) => less_or_equal(key, end),
+                Bound::Excluded(end) => less(key, end),
+                Bound::Unbounded => true,
+            },
+        )
     }
 
-    fn get_mut(&mut self, key: &K) -> Option<&mut V> {
-        match self {
-            BNode::Branch {
-                intervals,
-                children,
-            } => children[find_idx_from_interval(intervals, key)].get_mut(key),
-            BNode::Leaf(children) => {
-                let idx = children
-                    .binary_search_by(|(child_key, _)| child_key.cmp(key))
-                    .ok()?;
-                Some(&mut children[idx].1)
+    /// The entries from the first key that isn't `before_start` up to the last that's `before_end`
+    ///
+    /// Both have to hold for every key up to some point in the tree's order and none after it.
+    fn between(
+        root: &'a BNode<K, V>,
+        before_start: impl Fn(&K) -> bool,
+        before_end: impl Fn(&K) -> bool,
+    ) -> Self {
+        // Keys equal to an interval belong to the child after it, and a cursor that ends up past the
+        // end of a leaf is moved on to the next one
+        let descend = |before: &dyn Fn(&K) -> bool, back: bool| {
+            let mut path = Vec::new();
+            let mut node = root;
+            loop {
+                match node {
+                    BNode::Branch {
+                        intervals,
+                        children,
+                    } => {
+                        let idx = intervals.partition_point(before);
+                        let Some(child) = children.get(idx) else {
+                            break;
+                        };
+                        path.push((node, if back { idx } else { idx + 1 }));
+                        node = child;
+                    }
+                    BNode::Leaf(children) => {
+                        path.push((node, children.partition_point(|(key, _)| before(key))));
+                        break;
+                    }
+                }
+            }
+            path
+        };
+
+        let mut range = Range {
+            front: descend(&before_start, false),
+            back: descend(&before_end, true),
+        };
+        match (seek_front(&mut range.front), seek_back(&mut range.back)) {
+            // An empty range leaves the front cursor past the back cursor
+            (Some((first, _)), Some(_)) if before_end(first) => {}
+            _ => {
+                range.front.clear();
+                range.back.clear();
             }
         }
+        range
     }
+}
 
-    fn first(&self) -> Option<&(K, V)> {
-        match self {
-            BNode::Branch {
-                intervals: _,
-                children,
-            } => children.first().and_then(|child| child.first()),
-            BNode::Leaf(children) => children.first(),
+impl<K, V> Range<'_, K, V> {
+    fn is_exhausted(&mut self) -> bool {
+        // The cursors can meet at the end of one leaf and the start of the next, so they're
+        // compared again after each is settled, before either can step past the other
+        if self.cursors_met() || seek_back(&mut self.back).is_none() || self.cursors_met() {
+            return true;
         }
+        seek_front(&mut self.front).is_none() || self.cursors_met()
     }
 
-    fn split(&mut self) -> Self {
-        match self {
-            BNode::Branch {
-                intervals,
-                children,
-            } => {
-                let children_halfway = children.len() / 2;
-                let split_children = children.drain(children_halfway..).collect();
+    fn cursors_met(&self) -> bool {
+        match (self.front.last(), self.back.last()) {
+            (Some(&(front_node, front_idx)), Some(&(back_node, back_idx))) => {
+                core::ptr::eq(front_node, back_node) && front_idx >= back_idx
+            }
+            _ => true,
+        }
+    }
+}
 
-                let interval_halfway = children_halfway - 1;
-                let split_interval = intervals.drain((interval_halfway + 1)..).collect();
-                intervals.remove(interval_halfway);
+impl<'a, K, V> Iterator for Range<'a, K, V> {
+    type Item = (&'a K, &'a V);
 
-                self.debug_validate_intervals();
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.is_exhausted() {
+            return None;
+        }
+        let (key, val) = seek_front(&mut self.front)?;
+        self.front.last_mut().unwrap().1 += 1;
+        Some((key, val))
+    }
+}
 
-                BNode::Branch {
-                    intervals: split_interval,
-                    children: split_children,
-                }
-            }
-            BNode::Leaf(children) => {
-                let halfway = children.len() / 2;
-                let split_children = children.drain(halfway..).collect();
-                BNode::Leaf(split_children)
-            }
+impl<K, V> DoubleEndedIterator for Range<'_, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.is_exhausted() {
+            return None;
         }
+        let (key, val) = seek_back(&mut self.back)?;
+        self.back.last_mut().unwrap().1 -= 1;
+        Some((key, val))
     }
+}
 
-    fn debug_validate_intervals(&self) {
-        #[cfg(debug_assertions)]
-        match self {
+/// Advance a front cursor until it points at a leaf entry, returning that entry
+fn seek_front<'a, K, V>(stack: &mut Vec<(&'a BNode<K, V>, usize)>) -> Option<&'a (K, V)> {
+    loop {
+        let (node, idx) = stack.last_mut()?;
+        let node: &'a BNode<K, V> = node;
+        match node {
             BNode::Branch {
-                intervals,
+                intervals: _,
                 children,
-            } => {
-                debug_assert_eq!(intervals.len() + 1, children.len());
-                for i in 0..intervals.len() {
-                    debug_assert!(intervals[i] == children[i + 1].first().unwrap().0);
+            } => match children.get(*idx) {
+                Some(child) => {
+                    *idx += 1;
+                    stack.push((child, 0));
                 }
-            }
-            BNode::Leaf(_) => {}
+                None => {
+                    stack.pop();
+                }
+            },
+            BNode::Leaf(children) => match children.get(*idx) {
+                Some(entry) => return Some(entry),
+                None => {
+                    stack.pop();
+                }
+            },
         }
     }
+}
 
-    fn len(&self) -> usize {
-        match self {
+/// Retreat a back cursor until the entry before it is a leaf entry, returning that entry
+fn seek_back<'a, K, V>(stack: &mut Vec<(&'a BNode<K, V>, usize)>) -> Option<&'a (K, V)> {
+    loop {
+        let (node, idx) = stack.last_mut()?;
+        let node: &'a BNode<K, V> = node;
+        if *idx == 0 {
+            stack.pop();
+            continue;
+        }
+        match node {
             BNode::Branch {
                 intervals: _,
                 children,
-            } => children.len(),
-            BNode::Leaf(children) => children.len(),
+            } => {
+                *idx -= 1;
+                let child = &children[*idx];
+                stack.push((child, child.len()));
+            }
+            BNode::Leaf(children) => return Some(&children[*idx - 1]),
         }
     }
 }
 
-impl<K: Ord + Clone, V: Clone> BNode<K, V> {
-    fn insert(&mut self, key: K, mut val: V) -> Option<V> {
-        match self {
-            BNode::Branch {
-                intervals,
-                children,
-            } => {
-                if children.is_empty() {
-                    children.push(BNode::Leaf(vec![(key, val)]));
-                    return None;
-                }
-
-                let idx = find_idx_from_interval(intervals, &key);
-                let previous_val = children[idx].insert(key, val);
-                if children[idx].len() > MAX_ITEMS_IN_NODE {
-                    let new_node = children[idx].split();
-                    new_node.debug_validate_intervals();
-                    let (new_first_key, _) = new_node.first().unwrap();
-                    // TODO: can we avoid cloning here by storing references?
-                    intervals.insert(idx, new_first_key.clone());
-                    children.insert(idx + 1, new_node);
-                }
-                debug_assert!(children[idx].len() <= MAX_ITEMS_IN_NODE);
-
-                if children.len() > MAX_ITEMS_IN_NODE {
-                    let new_node = self.split();
-                    new_node.debug_validate_intervals();
-                    let old_node = std::mem::take(self);
-                    let (new_first_key, _) = new_node.first().unwrap();
-                    *self = BNode::Branch {
-                        // TODO: can we avoid cloning here by storing references?
-                        intervals: vec![new_first_key.clone()],
-                        children: vec![old_node, new_node],
-                    };
-                }
+pub struct BTreeIterMut<'a, K, V> {
+    stack: Vec<core::slice::IterMut<'a, Arc<BNode<K, V>>>>,
+    leaf: core::slice::IterMut<'a, (K, V)>,
+    remaining: usize,
+}
+
+impl<'a, K: Clone, V: Clone> Iterator for BTreeIterMut<'a, K, V> {
+    type Item = (&'a K, &'a mut V);
 
-                previous_val
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((key, val)) = self.leaf.next() {
+                self.remaining -= 1;
+                return Some((key, val));
             }
-            BNode::Leaf(children) => {
-                match children.binary_search_by(|child_key| child_key.0.cmp(&key)) {
-                    Ok(idx) => {
-                        let (_, child_value) = &mut children[idx];
-                        std::mem::swap(&mut val, child_value);
-                        Some(val)
-                    }
-                    Err(idx) => {
-                        children.insert(idx, (key, val));
-                        None
-                    }
+            match self.stack.last_mut()?.next().map(Arc::make_mut) {
+                Some(BNode::Branch {
+                    intervals: _,
+                    children,
+                }) => self.stack.push(children.iter_mut()),
+                Some(BNode::Leaf(children)) => self.leaf = children.iter_mut(),
+                None => {
+                    self.stack.pop();
                 }
             }
         }
     }
 
-    fn remove(&mut self, key: &K) -> Option<V> {
-        match self {
-            BNode::Branch {
-                intervals,
-                children,
-            } => {
-                if children.is_empty() {
-                    return None;
-                }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
 
-                let idx = find_idx_from_interval(intervals, key);
-                let previous = children[idx].remove(key);
+impl<K: Clone, V: Clone> ExactSizeIterator for BTreeIterMut<'_, K, V> {}
 
-                if children[idx].len() < MIN_ITEMS_IN_NODE {
-                    if idx > 0 {
-                        // TODO: This could be an expensive clone
-                        children[idx] = children[idx - 1].merged(&children[idx]);
-                        children.remove(idx - 1);
-                        intervals.remove(idx - 1);
-                    } else if idx + 1 < children.len() {
-                        // TODO: This could be an expensive clone
-                        children[idx] = children[idx].merged(&children[idx + 1]);
-                        children.remove(idx + 1);
-                        intervals.remove(idx);
-                    }
-                }
-                if children.len() > 1 {
-                    debug_assert!(children[idx].len() >= MIN_ITEMS_IN_NODE);
-                }
+impl<K: Clone, V: Clone> FusedIterator for BTreeIterMut<'_, K, V> {}
 
-                previous
-            }
-            BNode::Leaf(children) => {
-                match children.binary_search_by(|child_key| child_key.0.cmp(key)) {
-                    Ok(idx) => Some(children.remove(idx).1),
-                    Err(_) => None,
-                }
-            }
+impl<K: Clone, V: Clone, const MAX: usize, C> IntoIterator for BTree<K, V, MAX, C> {
+    type Item = (K, V);
+    type IntoIter = IntoIter<K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        IntoIter {
+            stack: vec![vec![self.root].into_iter()],
+            leaf: Vec::new().into_iter(),
+            remaining: self.len,
         }
     }
+}
 
-    fn merged(&self, other: &Self) -> Self {
-        let Some(other_first) = other.first() else {
-            return self.clone();
-        };
-        match (self, other) {
-            (
-                BNode::Branch {
-                    children: a_children,
-                    intervals: a_intervals,
-                },
-                BNode::Branch {
-                    children: b_children,
-                    intervals: b_intervals,
-                },
-            ) => {
-                let mut children = Vec::new();
-                children.extend(a_children.iter().cloned());
-                children.extend(b_children.iter().cloned());
-                let mut intervals = Vec::new();
-                intervals.extend(a_intervals.iter().cloned());
-                intervals.push(other_first.0.clone());
-                intervals.extend(b_intervals.iter().cloned());
-                BNode::Branch {
-                    intervals,
-                    children,
-                }
+/// Nodes shared with a snapshot are copied as the iterator reaches them, rather than moved out of
+pub struct IntoIter<K, V> {
+    stack: Vec<alloc::vec::IntoIter<Arc<BNode<K, V>>>>,
+    leaf: alloc::vec::IntoIter<(K, V)>,
+    remaining: usize,
+}
+
+impl<K: Clone, V: Clone> Iterator for IntoIter<K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.leaf.next() {
+                self.remaining -= 1;
+                return Some(entry);
             }
-            (
-                BNode::Branch {
-                    intervals,
-                    children,
-                },
-                BNode::Leaf(_),
-            ) => {
-                let mut intervals = intervals.clone();
-                let mut children = children.clone();
-                intervals.push(other_first.0.clone());
-                children.push(other.clone());
-                BNode::Branch {
-                    intervals,
+            match self.stack.last_mut()?.next().map(Arc::unwrap_or_clone) {
+                Some(BNode::Branch {
+                    intervals: _,
                     children,
+                }) => self.stack.push(children.into_iter()),
+                Some(BNode::Leaf(children)) => self.leaf = children.into_iter(),
+                None => {
+                    self.stack.pop();
                 }
             }
-            (BNode::Leaf(_), BNode::Branch { .. }) => todo!(),
-            (BNode::Leaf(_), BNode::Leaf(_)) => BNode::Branch {
-                intervals: vec![other_first.0.clone()],
-                children: vec![self.clone(), other.clone()],
-            },
         }
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
 }
 
-fn find_idx_from_interval<K: Ord>(intervals: &[K], key: &K) -> usize {
-    if intervals.is_empty() {
-        0
-    } else {
-        let halfway = intervals.len() / 2;
-        match key.cmp(&intervals[halfway]) {
-            std::cmp::Ordering::Less => find_idx_from_interval(&intervals[0..halfway], key),
-            std::cmp::Ordering::Equal => halfway + 1,
-            std::cmp::Ordering::Greater => {
-                halfway + 1 + find_idx_from_interval(&intervals[(halfway + 1)..], key)
-            }
-        }
+impl<K: Clone, V: Clone> ExactSizeIterator for IntoIter<K, V> {}
+
+impl<K: Clone, V: Clone> FusedIterator for IntoIter<K, V> {}
+
+pub struct Drain<'a, K, V> {
+    entries: IntoIter<K, V>,
+    _tree: PhantomData<&'a mut (K, V)>,
+}
+
+impl<K: Clone, V: Clone> Iterator for Drain<'_, K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.entries.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
     }
 }
 
-pub struct BTreeIter<'a, K, V> {
-    stack: Vec<(&'a BNode<K, V>, usize)>,
+impl<K: Clone, V: Clone> ExactSizeIterator for Drain<'_, K, V> {}
+
+impl<K: Clone, V: Clone> FusedIterator for Drain<'_, K, V> {}
+
+pub struct ExtractIf<'a, K, V, const MAX: usize, C, F> {
+    tree: &'a mut BTree<K, V, MAX, C>,
+    pred: F,
+    /// The last key passed to the predicate, which the search for the next entry starts after
+    last: Option<K>,
 }
 
-impl<'a, K, V> Iterator for BTreeIter<'a, K, V> {
-    type Item = (&'a K, &'a V);
+impl<K: Clone, V: Clone, const MAX: usize, C: Comparator<K>, F: FnMut(&K, &V) -> bool> Iterator
+    for ExtractIf<'_, K, V, MAX, C, F>
+{
+    type Item = (K, V);
 
     fn next(&mut self) -> Option<Self::Item> {
-        match self.stack.last_mut() {
-            Some((node, idx)) => match node {
+        let start = match &self.last {
+            Some(last) => Bound::Excluded(last),
+            None => Bound::Unbounded,
+        };
+        // Entries that are kept are only ever looked at, so nodes shared with a snapshot stay shared
+        let key = self
+            .tree
+            .range((start, Bound::Unbounded))
+            .find(|(key, val)| (self.pred)(key, val))?
+            .0
+            .clone();
+        let val = self.tree.remove(&key).unwrap();
+        self.last = Some(key.clone());
+        Some((key, val))
+    }
+}
+
+/// A position in a tree, either on one of its entries or on a "ghost" position that sits past the
+/// last entry and before the first
+pub struct Cursor<'a, K, V, C = NaturalOrder> {
+    root: &'a BNode<K, V>,
+    /// The node at each level down to the current entry, and the index taken out of it; empty on
+    /// the ghost position
+    path: Vec<(&'a BNode<K, V>, usize)>,
+    cmp: &'a C,
+}
+
+impl<'a, K, V, C> Cursor<'a, K, V, C> {
+    pub fn key(&self) -> Option<&'a K> {
+        self.entry().map(|(key, _)| key)
+    }
+
+    pub fn value(&self) -> Option<&'a V> {
+        self.entry().map(|(_, val)| val)
+    }
+
+    fn entry(&self) -> Option<&'a (K, V)> {
+        match self.path.last()? {
+            (BNode::Leaf(children), idx) => children.get(*idx),
+            _ => None,
+        }
+    }
+
+    /// Moves to the next entry, onto the ghost position from the last entry, or onto the first
+    /// entry from the ghost position
+    pub fn move_next(&mut self) {
+        match self.path.last_mut() {
+            Some((_, idx)) => *idx += 1,
+            None => self.path.push((self.root, 0)),
+        }
+        self.settle(true);
+    }
+
+    /// Moves to the previous entry, onto the ghost position from the first entry, or onto the last
+    /// entry from the ghost position
+    pub fn move_prev(&mut self) {
+        match self.path.last_mut() {
+            Some((_, idx)) => *idx = idx.wrapping_sub(1),
+            None => self.path.push((self.root, self.root.len().wrapping_sub(1))),
+        }
+        self.settle(false);
+    }
+
+    /// Moves to the first entry with a key greater than or equal to `key`, or onto the ghost
+    /// position if there isn't one
+    pub fn seek<Q: ?Sized>(&mut self, key: &Q)
+    where
+        K: Borrow<Q>,
+        C: Comparator<Q>,
+    {
+        self.path.clear();
+        let mut node = self.root;
+        loop {
+            match node {
                 BNode::Branch {
-                    intervals: _,
+                    intervals,
                     children,
                 } => {
-                    let child_idx = *idx;
-                    if child_idx < children.len() {
-                        *idx += 1;
-                        self.stack.push((&children[child_idx], 0));
-                        self.next()
-                    } else {
-                        self.stack.pop();
-                        self.next()
+                    let idx = find_idx_from_interval(intervals, key, self.cmp);
+                    self.path.push((node, idx));
+                    match children.get(idx) {
+                        Some(child) => node = child,
+                        None => break,
                     }
                 }
                 BNode::Leaf(children) => {
-                    let child_idx = *idx;
-                    if child_idx < children.len() {
-                        *idx += 1;
-                        let (key, val) = &children[child_idx];
-                        Some((key, val))
+                    let idx = children.partition_point(|(child_key, _)| {
+                        self.cmp.compare(child_key.borrow(), key) == Ordering::Less
+                    });
+                    self.path.push((node, idx));
+                    break;
+                }
+            }
+        }
+        self.settle(true);
+    }
+
+    /// Walks the path in one direction until it ends on an entry, or falls off the end of the tree
+    /// onto the ghost position
+    ///
+    /// Indices past either end of a node, including one wrapped below zero, mean that node is used
+    /// up and the walk carries on from its sibling.
+    fn settle(&mut self, forward: bool) {
+        while let Some(&(node, idx)) = self.path.last() {
+            let child = match node {
+                BNode::Branch {
+                    intervals: _,
+                    children,
+                } => children.get(idx),
+                BNode::Leaf(children) if idx < children.len() => return,
+                BNode::Leaf(_) => None,
+            };
+            match child {
+                Some(child) => {
+                    let start = if forward {
+                        0
                     } else {
-                        self.stack.pop();
-                        self.next()
+                        child.len().wrapping_sub(1)
+                    };
+                    self.path.push((child, start));
+                }
+                None => {
+                    self.path.pop();
+                    if let Some((_, parent_idx)) = self.path.last_mut() {
+                        *parent_idx = if forward {
+                            *parent_idx + 1
+                        } else {
+                            parent_idx.wrapping_sub(1)
+                        };
                     }
                 }
-            },
-            None => None,
+            }
         }
     }
 }
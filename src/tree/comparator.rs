@@ -0,0 +1,105 @@
+use core::{cmp::Ordering, convert::Infallible, fmt};
+
+/// Decides the order that keys are kept in
+///
+/// Implemented by [`NaturalOrder`] for anything [`Ord`], and by any closure comparing two keys.
+pub trait Comparator<K: ?Sized> {
+    fn compare(&self, a: &K, b: &K) -> Ordering;
+}
+
+/// Orders keys by their [`Ord`] implementation, which is what trees use unless told otherwise
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct NaturalOrder;
+
+impl<K: Ord + ?Sized> Comparator<K> for NaturalOrder {
+    fn compare(&self, a: &K, b: &K) -> Ordering {
+        a.cmp(b)
+    }
+}
+
+impl<K: ?Sized, F: Fn(&K, &K) -> Ordering> Comparator<K> for F {
+    fn compare(&self, a: &K, b: &K) -> Ordering {
+        self(a, b)
+    }
+}
+
+/// Decides the order that keys are kept in, where comparing two keys can fail
+///
+/// Implemented by [`PartialOrder`] for anything [`PartialOrd`], and by any closure comparing two
+/// keys that returns a `Result`. A tree orders its keys with one through [`Fallible`].
+pub trait TryComparator<K: ?Sized> {
+    type Error;
+
+    fn try_compare(&self, a: &K, b: &K) -> Result<Ordering, Self::Error>;
+}
+
+/// Orders keys by their [`PartialOrd`] implementation, failing on keys that have no order between
+/// them, like a NaN float
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct PartialOrder;
+
+/// The error [`PartialOrder`] fails with
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Unordered;
+
+impl fmt::Display for Unordered {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "keys have no order between them")
+    }
+}
+
+impl core::error::Error for Unordered {}
+
+impl<K: PartialOrd + ?Sized> TryComparator<K> for PartialOrder {
+    type Error = Unordered;
+
+    fn try_compare(&self, a: &K, b: &K) -> Result<Ordering, Unordered> {
+        a.partial_cmp(b).ok_or(Unordered)
+    }
+}
+
+impl<K: ?Sized, E, F: Fn(&K, &K) -> Result<Ordering, E>> TryComparator<K> for F {
+    type Error = E;
+
+    fn try_compare(&self, a: &K, b: &K) -> Result<Ordering, E> {
+        self(a, b)
+    }
+}
+
+/// Lets a tree order its keys with a [`TryComparator`], which its `try_` methods report the
+/// failures of
+///
+/// Every other method that compares keys panics if a comparison fails.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Fallible<T>(pub T);
+
+impl<K: ?Sized, T: TryComparator<K>> Comparator<K> for Fallible<T> {
+    fn compare(&self, a: &K, b: &K) -> Ordering {
+        Unwrapping(&self.0).compare(a, b)
+    }
+}
+
+/// Treats a [`Comparator`] as a [`TryComparator`] that never fails, so that one implementation of
+/// an operation can serve both
+pub(crate) struct Infallibly<'c, C>(pub &'c C);
+
+impl<K: ?Sized, C: Comparator<K>> TryComparator<K> for Infallibly<'_, C> {
+    type Error = Infallible;
+
+    fn try_compare(&self, a: &K, b: &K) -> Result<Ordering, Infallible> {
+        Ok(self.0.compare(a, b))
+    }
+}
+
+/// Treats a borrowed [`TryComparator`] as a [`Comparator`], panicking the same way [`Fallible`]
+/// does
+pub(crate) struct Unwrapping<'c, T>(pub &'c T);
+
+impl<K: ?Sized, T: TryComparator<K>> Comparator<K> for Unwrapping<'_, T> {
+    fn compare(&self, a: &K, b: &K) -> Ordering {
+        match self.0.try_compare(a, b) {
+            Ok(order) => order,
+            Err(_) => panic!("keys couldn't be compared"),
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use alloc::sync::Arc;
+
+use super::{BTree, Comparator, End, NaturalOrder};
+
+// Entries hold on to the key and descend again when they're used, because inserting can split
+// nodes and move the leaf that the key would have lived in.
+pub enum Entry<'a, K, V, const MAX: usize = 4, C = NaturalOrder> {
+    Occupied(OccupiedEntry<'a, K, V, MAX, C>),
+    Vacant(VacantEntry<'a, K, V, MAX, C>),
+}
+
+pub struct OccupiedEntry<'a, K, V, const MAX: usize = 4, C = NaturalOrder> {
+    tree: &'a mut BTree<K, V, MAX, C>,
+    position: Position<K>,
+}
+
+/// How an occupied entry gets back to its entry in the tree
+enum Position<K> {
+    /// Searches for the key
+    Key(K),
+    /// Follows the first or last child of every node, which doesn't need a key
+    End(End),
+}
+
+pub struct VacantEntry<'a, K, V, const MAX: usize = 4, C = NaturalOrder> {
+    tree: &'a mut BTree<K, V, MAX, C>,
+    key: K,
+}
+
+impl<'a, K, V, const MAX: usize, C: Comparator<K>> Entry<'a, K, V, MAX, C> {
+    pub(super) fn new(tree: &'a mut BTree<K, V, MAX, C>, key: K) -> Self {
+        if tree.contains_key(&key) {
+            Entry::Occupied(OccupiedEntry {
+                tree,
+                position: Position::Key(key),
+            })
+        } else {
+            Entry::Vacant(VacantEntry { tree, key })
+        }
+    }
+
+    pub fn key(&self) -> &K {
+        match self {
+            Entry::Occupied(entry) => entry.key(),
+            Entry::Vacant(entry) => entry.key(),
+        }
+    }
+
+    pub fn and_modify<F: FnOnce(&mut V)>(self, f: F) -> Self
+    where
+        K: Clone,
+        V: Clone,
+    {
+        match self {
+            Entry::Occupied(mut entry) => {
+                f(entry.get_mut());
+                Entry::Occupied(entry)
+            }
+            Entry::Vacant(entry) => Entry::Vacant(entry),
+        }
+    }
+}
+
+impl<'a, K: Clone, V: Clone, const MAX: usize, C: Comparator<K>> Entry<'a, K, V, MAX, C> {
+    pub fn or_insert(self, default: V) -> &'a mut V {
+        self.or_insert_with(|| default)
+    }
+
+    pub fn or_insert_with<F: FnOnce() -> V>(self, default: F) -> &'a mut V {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(default()),
+        }
+    }
+
+    pub fn or_default(self) -> &'a mut V
+    where
+        V: Default,
+    {
+        self.or_insert_with(V::default)
+    }
+}
+
+impl<'a, K, V, const MAX: usize, C: Comparator<K>> OccupiedEntry<'a, K, V, MAX, C> {
+    /// The tree must not be empty
+    pub(super) fn at_end(tree: &'a mut BTree<K, V, MAX, C>, end: End) -> Self {
+        OccupiedEntry {
+            tree,
+            position: Position::End(end),
+        }
+    }
+
+    pub fn key(&self) -> &K {
+        match &self.position {
+            Position::Key(key) => key,
+            Position::End(end) => &self.tree.root.end(*end).unwrap().0,
+        }
+    }
+
+    pub fn get(&self) -> &V {
+        match &self.position {
+            Position::Key(key) => self.tree.get(key).unwrap(),
+            Position::End(end) => &self.tree.root.end(*end).unwrap().1,
+        }
+    }
+
+    /// Copies the nodes on the way to the value first if they're shared, like [`BTree::get_mut`]
+    pub fn get_mut(&mut self) -> &mut V
+    where
+        K: Clone,
+        V: Clone,
+    {
+        match &self.position {
+            Position::Key(key) => self.tree.get_mut(key).unwrap(),
+            Position::End(end) => &mut Arc::make_mut(&mut self.tree.root).end_mut(*end).unwrap().1,
+        }
+    }
+
+    /// Like [`OccupiedEntry::get_mut`], but borrowing from the tree rather than the entry
+    pub fn into_mut(self) -> &'a mut V
+    where
+        K: Clone,
+        V: Clone,
+    {
+        match self.position {
+            Position::Key(key) => self.tree.get_mut(&key).unwrap(),
+            Position::End(end) => &mut Arc::make_mut(&mut self.tree.root).end_mut(end).unwrap().1,
+        }
+    }
+
+    pub fn insert(&mut self, val: V) -> V
+    where
+        K: Clone,
+        V: Clone,
+    {
+        core::mem::replace(self.get_mut(), val)
+    }
+}
+
+impl<K: Clone, V: Clone, const MAX: usize, C: Comparator<K>> OccupiedEntry<'_, K, V, MAX, C> {
+    pub fn remove(self) -> V {
+        match self.position {
+            Position::Key(key) => self.tree.remove(&key).unwrap(),
+            Position::End(end) => {
+                let (_, val) = self
+                    .tree
+                    .remove_by::<K>(|node, _| end.locate(node))
+                    .unwrap();
+                val
+            }
+        }
+    }
+}
+
+impl<K, V, const MAX: usize, C> VacantEntry<'_, K, V, MAX, C> {
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn into_key(self) -> K {
+        self.key
+    }
+}
+
+impl<'a, K: Clone, V: Clone, const MAX: usize, C: Comparator<K>> VacantEntry<'a, K, V, MAX, C> {
+    pub fn insert(self, val: V) -> &'a mut V {
+        self.tree.insert(self.key.clone(), val);
+        self.tree.get_mut(&self.key).unwrap()
+    }
+}
@@ -0,0 +1,50 @@
+use core::cmp::Ordering;
+
+use super::BTree;
+
+/// Which of the two trees passed to [`merge_join`] hold a key, along with their values for it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MergeSide<'a, V1, V2> {
+    /// Only the first tree holds the key
+    Left(&'a V1),
+    /// Only the second tree holds the key
+    Right(&'a V2),
+    /// Both trees hold the key
+    Both(&'a V1, &'a V2),
+}
+
+/// Walks two trees side by side, yielding every key in either of them once, in ascending order
+///
+/// Both trees are read through their ordered iterators, so this takes a single pass over each
+/// without collecting anything. Unions, intersections and differences come out of filtering on
+/// the [`MergeSide`].
+pub fn merge_join<'a, K: Ord, V1, V2, const MAX1: usize, const MAX2: usize>(
+    a: &'a BTree<K, V1, MAX1>,
+    b: &'a BTree<K, V2, MAX2>,
+) -> impl Iterator<Item = (&'a K, MergeSide<'a, V1, V2>)> {
+    let mut left = a.iter().peekable();
+    let mut right = b.iter().peekable();
+    core::iter::from_fn(move || {
+        let order = match (left.peek(), right.peek()) {
+            (Some((l, _)), Some((r, _))) => l.cmp(r),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => return None,
+        };
+        Some(match order {
+            Ordering::Less => {
+                let (key, val) = left.next()?;
+                (key, MergeSide::Left(val))
+            }
+            Ordering::Greater => {
+                let (key, val) = right.next()?;
+                (key, MergeSide::Right(val))
+            }
+            Ordering::Equal => {
+                let (key, l) = left.next()?;
+                let (_, r) = right.next()?;
+                (key, MergeSide::Both(l, r))
+            }
+        })
+    })
+}
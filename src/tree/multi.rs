@@ -0,0 +1,88 @@
+use alloc::vec::Vec;
+use core::borrow::Borrow;
+
+use super::{BTree, Comparator, NaturalOrder};
+
+/// A [`BTree`] that can hold more than one value under each key
+///
+/// Values under the same key are kept in the order they were inserted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MultiBTree<K, V, const MAX: usize = 4, C = NaturalOrder> {
+    tree: BTree<K, Vec<V>, MAX, C>,
+    /// The number of values, across every key
+    len: usize,
+}
+
+impl<K, V> MultiBTree<K, V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<K, V, const MAX: usize, C: Default> Default for MultiBTree<K, V, MAX, C> {
+    fn default() -> Self {
+        MultiBTree {
+            tree: BTree::default(),
+            len: 0,
+        }
+    }
+}
+
+impl<K, V, const MAX: usize, C> MultiBTree<K, V, MAX, C> {
+    /// The number of values in the tree, counting each one under a shared key separately
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The number of distinct keys in the tree
+    pub fn key_count(&self) -> usize {
+        self.tree.len()
+    }
+
+    /// Every value under a key, in the order they were inserted
+    pub fn get_all<Q: ?Sized>(&self, key: &Q) -> &[V]
+    where
+        K: Borrow<Q>,
+        C: Comparator<Q>,
+    {
+        self.tree.get(key).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn contains_key<Q: ?Sized>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        C: Comparator<Q>,
+    {
+        self.tree.contains_key(key)
+    }
+
+    /// Iterates over every value in ascending key order, alongside the key it's under
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.tree
+            .iter()
+            .flat_map(|(key, values)| values.iter().map(move |val| (key, val)))
+    }
+}
+
+impl<K: Clone, V: Clone, const MAX: usize, C: Comparator<K>> MultiBTree<K, V, MAX, C> {
+    /// Adds a value under a key, after any values already there
+    pub fn insert(&mut self, key: K, val: V) {
+        self.tree.entry(key).or_default().push(val);
+        self.len += 1;
+    }
+
+    /// Removes every value under a key, returning them in the order they were inserted
+    pub fn remove_all<Q: ?Sized>(&mut self, key: &Q) -> Vec<V>
+    where
+        K: Borrow<Q>,
+        C: Comparator<Q>,
+    {
+        let values = self.tree.remove(key).unwrap_or_default();
+        self.len -= values.len();
+        values
+    }
+}
@@ -0,0 +1,66 @@
+use alloc::{sync::Arc, vec::Vec};
+use core::cmp::Ordering;
+
+use rayon::prelude::*;
+
+use super::{BNode, BTree, Comparator};
+
+impl<K, V, const MAX: usize, C> BTree<K, V, MAX, C>
+where
+    K: Clone + Send + Sync,
+    V: Clone + Send + Sync,
+    C: Comparator<K> + Default,
+{
+    /// Like [`BTree::from_sorted`], but splitting the entries into nodes across threads
+    ///
+    /// Each level of the tree is chunked exactly the way `from_sorted` chunks it, so the two build
+    /// the same tree. The entries must be strictly ascending; this is only checked in debug
+    /// builds.
+    pub fn par_from_sorted(entries: Vec<(K, V)>) -> Self {
+        let mut tree = Self::default();
+        debug_assert!(
+            entries
+                .windows(2)
+                .all(|pair| tree.cmp.compare(&pair[0].0, &pair[1].0) == Ordering::Less),
+            "par_from_sorted needs strictly ascending keys"
+        );
+        tree.len = entries.len();
+
+        let mut level: Vec<_> = par_chunk_evenly(entries, MAX)
+            .into_par_iter()
+            .map(BNode::Leaf)
+            .collect();
+        while level.len() > MAX {
+            level = par_chunk_evenly(level, MAX)
+                .into_par_iter()
+                .map(BNode::branch_of)
+                .collect();
+        }
+        tree.root = Arc::new(BNode::branch_of(level));
+        tree
+    }
+}
+
+/// Splits items into the same chunks as [`super::chunk_evenly`], across threads
+///
+/// Those chunks are the longer ones first, then the rest one item shorter, so each run of
+/// same-length chunks can be cut up independently.
+fn par_chunk_evenly<T: Send>(mut items: Vec<T>, max: usize) -> Vec<Vec<T>> {
+    let chunk_count = items.len().div_ceil(max);
+    if chunk_count == 0 {
+        return Vec::new();
+    }
+    let len = items.len() / chunk_count;
+    let longer = items.len() % chunk_count;
+    let shorter = items.split_off(longer * (len + 1));
+    items
+        .into_par_iter()
+        .chunks(len + 1)
+        .chain(shorter.into_par_iter().chunks(len))
+        // Chunks can come out with room to spare, which `chunk_evenly`'s don't have
+        .map(|mut chunk| {
+            chunk.shrink_to_fit();
+            chunk
+        })
+        .collect()
+}
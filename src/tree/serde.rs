@@ -0,0 +1,49 @@
+use core::{fmt, marker::PhantomData};
+
+use serde::{
+    de::{MapAccess, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+use super::{BTree, Comparator};
+
+impl<K: Serialize, V: Serialize, const MAX: usize, C> Serialize for BTree<K, V, MAX, C> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.iter())
+    }
+}
+
+impl<'de, K, V, const MAX: usize, C> Deserialize<'de> for BTree<K, V, MAX, C>
+where
+    K: Deserialize<'de> + Clone,
+    V: Deserialize<'de> + Clone,
+    C: Comparator<K> + Default,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_map(BTreeVisitor(PhantomData))
+    }
+}
+
+struct BTreeVisitor<K, V, const MAX: usize, C>(PhantomData<(K, V, C)>);
+
+impl<'de, K, V, const MAX: usize, C> Visitor<'de> for BTreeVisitor<K, V, MAX, C>
+where
+    K: Deserialize<'de> + Clone,
+    V: Deserialize<'de> + Clone,
+    C: Comparator<K> + Default,
+{
+    type Value = BTree<K, V, MAX, C>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a map")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        // Entries can arrive in any order, insert sorts them out
+        let mut tree = BTree::default();
+        while let Some((key, val)) = map.next_entry()? {
+            tree.insert(key, val);
+        }
+        Ok(tree)
+    }
+}
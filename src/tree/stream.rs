@@ -0,0 +1,95 @@
+use std::io::{self, Read, Write};
+
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+
+use super::{BTree, Comparator};
+
+impl<K, V, const MAX: usize, C> BTree<K, V, MAX, C> {
+    /// Streams every entry to a writer in ascending key order, to be read back with
+    /// [`BTree::read_ordered`]
+    ///
+    /// The closures turn keys and values into bytes. The stream starts with the number of entries,
+    /// and each key and value follows behind its length, all as little-endian `u64`s. Entries are
+    /// encoded one at a time, so the tree is never copied as a whole.
+    pub fn write_ordered<W: Write>(
+        &self,
+        writer: &mut W,
+        mut encode_key: impl FnMut(&K, &mut Vec<u8>),
+        mut encode_value: impl FnMut(&V, &mut Vec<u8>),
+    ) -> io::Result<()> {
+        writer.write_all(&(self.len() as u64).to_le_bytes())?;
+        let mut bytes = Vec::new();
+        for (key, val) in self.iter() {
+            bytes.clear();
+            encode_key(key, &mut bytes);
+            write_chunk(writer, &bytes)?;
+            bytes.clear();
+            encode_value(val, &mut bytes);
+            write_chunk(writer, &bytes)?;
+        }
+        Ok(())
+    }
+
+    /// Builds a tree out of a stream written by [`BTree::write_ordered`], the same way
+    /// [`BTree::from_sorted`] does
+    ///
+    /// Fails with [`io::ErrorKind::InvalidData`] if the keys aren't strictly ascending, or
+    /// [`io::ErrorKind::UnexpectedEof`] if the stream ends early. Errors from the closures are
+    /// passed along as they are.
+    pub fn read_ordered<R: Read>(
+        reader: &mut R,
+        mut decode_key: impl FnMut(&[u8]) -> io::Result<K>,
+        mut decode_value: impl FnMut(&[u8]) -> io::Result<V>,
+    ) -> io::Result<Self>
+    where
+        K: Clone,
+        V: Clone,
+        C: Comparator<K> + Default,
+    {
+        let cmp = C::default();
+        let len = read_u64(reader)?;
+        let mut entries: Vec<(K, V)> = Vec::new();
+        let mut bytes = Vec::new();
+        for _ in 0..len {
+            read_chunk(reader, &mut bytes)?;
+            let key = decode_key(&bytes)?;
+            if let Some((last, _)) = entries.last() {
+                if cmp.compare(last, &key) != Ordering::Less {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "keys aren't in ascending order",
+                    ));
+                }
+            }
+            read_chunk(reader, &mut bytes)?;
+            entries.push((key, decode_value(&bytes)?));
+        }
+        Ok(Self::from_sorted(entries))
+    }
+}
+
+fn write_chunk(writer: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    writer.write_all(&(bytes.len() as u64).to_le_bytes())?;
+    writer.write_all(bytes)
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut len = [0; 8];
+    reader.read_exact(&mut len)?;
+    Ok(u64::from_le_bytes(len))
+}
+
+/// Reads a length-prefixed chunk into `bytes`, replacing what was there
+///
+/// The bytes are read as they arrive rather than allocated up front, so a damaged length can't
+/// ask for more memory than the stream actually holds.
+fn read_chunk(reader: &mut impl Read, bytes: &mut Vec<u8>) -> io::Result<()> {
+    let len = read_u64(reader)?;
+    bytes.clear();
+    reader.take(len).read_to_end(bytes)?;
+    if (bytes.len() as u64) < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}